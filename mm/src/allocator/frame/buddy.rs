@@ -1,7 +1,8 @@
 use core::{marker::PhantomData, mem};
 
 use crate::{
-    Arch, BumpAllocator, FrameAllocator, FrameCount, FrameUsage, PhysicalAddress, VirtualAddress,
+    Arch, BumpAllocator, FrameAllocator, FrameCount, FrameUsage, MemoryArea, PhysicalAddress,
+    VirtualAddress, BUMP_RESERVED_MAX,
 };
 
 #[repr(transparent)]
@@ -63,80 +64,440 @@ impl<A: Arch> BuddyEntry<A> {
         let addr = self.usage_addr(page)?;
         Some(A::write(addr, usage))
     }
+    /// 初始化使用表，使用表本身占用的页标记为已使用
+    unsafe fn init_usage(&mut self) -> Option<()> {
+        let usage_pages = self.usage_pages();
+        if self.pages() > usage_pages {
+            let usage_start = self.usage_addr(0)?;
+            for page in 0..usage_pages {
+                A::write_bytes(usage_start.add(page << A::PAGE_SHIFT), 0, A::PAGE_SIZE);
+            }
+            for page in 0..usage_pages {
+                self.set_usage(page, BuddyUsage(1))?;
+            }
+            #[cfg(feature = "poison")]
+            crate::poison::<A>(
+                self.base.add(usage_pages << A::PAGE_SHIFT),
+                FrameCount::new(self.pages() - usage_pages),
+            );
+        }
+        self.skip = usage_pages;
+        self.used = usage_pages;
+        Some(())
+    }
+    /// 使用的页数、空闲块和共享的页
+    unsafe fn frame_usage(&self) -> Option<FrameUsage> {
+        let mut usage = FrameUsage::new(FrameCount::new(self.used), FrameCount::new(self.pages()));
+        let mut free_run = 0;
+        // `skip`之前的页都已经使用，但其中可能有共享的页
+        for page in self.usage_pages()..self.pages() {
+            let usage_count = self.usage(page)?.0;
+            if usage_count == 0 {
+                free_run += 1;
+            } else {
+                usage.add_free_run(FrameCount::new(free_run));
+                free_run = 0;
+                if usage_count > 1 {
+                    usage.add_shared(FrameCount::new(1));
+                }
+            }
+        }
+        usage.add_free_run(FrameCount::new(free_run));
+        Some(usage)
+    }
+    /// `area`覆盖的页的序号范围，没有重叠时返回`None`
+    fn removed_pages(&self, area: &MemoryArea) -> Option<(usize, usize)> {
+        let base = self.base;
+        let start = area.base.max(base);
+        let end = area.end().min(base.add(self.size));
+        if start >= end {
+            return None;
+        }
+        let first = (start.data() - base.data()) >> A::PAGE_SHIFT;
+        let last = (end.data() - base.data()) >> A::PAGE_SHIFT;
+        Some((first, last))
+    }
+    /// 从第`page`页开始的后半部分，使用表还没有初始化
+    fn split_off(&self, page: usize) -> Self {
+        let mut tail = Self::empty();
+        tail.base = self.base.add(page << A::PAGE_SHIFT);
+        tail.size = self.size - (page << A::PAGE_SHIFT);
+        tail
+    }
+    /// 在开头建立使用表，从`entry`的第`page`页开始复制引用计数，
+    /// 使用表占用的页必须空闲
+    unsafe fn init_split(&mut self, entry: &Self, page: usize) -> Option<()> {
+        let usage_pages = self.usage_pages();
+        let usage_start = self.usage_addr(0)?;
+        for i in 0..usage_pages {
+            A::write_bytes(usage_start.add(i << A::PAGE_SHIFT), 0, A::PAGE_SIZE);
+            self.set_usage(i, BuddyUsage(1))?;
+        }
+        self.skip = usage_pages;
+        self.used = usage_pages;
+        for i in usage_pages..self.pages() {
+            let usage = entry.usage(page + i)?;
+            if usage.0 != 0 {
+                self.set_usage(i, usage)?;
+                self.used += 1;
+            }
+        }
+        Some(())
+    }
+    /// 在末尾加入`pages`个空闲页，使用表变大时新占用的页必须空闲
+    unsafe fn grow(&mut self, pages: usize) -> Option<()> {
+        let old_pages = self.pages();
+        let old_usage_pages = self.usage_pages();
+        let mut grown = *self;
+        grown.size += pages << A::PAGE_SHIFT;
+        let usage_pages = grown.usage_pages();
+        for page in old_usage_pages..usage_pages.min(old_pages) {
+            if self.usage(page)?.0 != 0 {
+                return None;
+            }
+        }
+        // 使用表新占用的页中只保存加入的页的引用计数，清零即可
+        for page in old_usage_pages..usage_pages {
+            let virt = A::phys_to_virt(grown.base.add(page << A::PAGE_SHIFT));
+            A::write_bytes(virt, 0, A::PAGE_SIZE);
+            grown.set_usage(page, BuddyUsage(1))?;
+        }
+        for page in old_pages.max(usage_pages)..grown.pages() {
+            grown.set_usage(page, BuddyUsage(0))?;
+            #[cfg(feature = "poison")]
+            crate::poison::<A>(grown.base.add(page << A::PAGE_SHIFT), FrameCount::new(1));
+        }
+        grown.used += usage_pages - old_usage_pages;
+        *self = grown;
+        Some(())
+    }
+    /// 在开头加入`pages`个空闲页，合并后的使用表必须放得进这些页，
+    /// 原来的使用表占用的页变为空闲
+    unsafe fn prepend(&self, pages: usize) -> Option<Self> {
+        let base = self.base;
+        let mut merged = Self::empty();
+        merged.base = PhysicalAddress::new(base.data().checked_sub(pages << A::PAGE_SHIFT)?);
+        merged.size = self.size + (pages << A::PAGE_SHIFT);
+        let usage_pages = merged.usage_pages();
+        if usage_pages > pages {
+            return None;
+        }
+        let usage_start = merged.usage_addr(0)?;
+        for page in 0..usage_pages {
+            A::write_bytes(usage_start.add(page << A::PAGE_SHIFT), 0, A::PAGE_SIZE);
+            merged.set_usage(page, BuddyUsage(1))?;
+        }
+        #[cfg(feature = "poison")]
+        crate::poison::<A>(
+            merged.base.add(usage_pages << A::PAGE_SHIFT),
+            FrameCount::new(pages - usage_pages),
+        );
+        let old_usage_pages = self.usage_pages();
+        for page in old_usage_pages..self.pages() {
+            merged.set_usage(pages + page, self.usage(page)?)?;
+        }
+        #[cfg(feature = "poison")]
+        crate::poison::<A>(base, FrameCount::new(old_usage_pages));
+        merged.skip = usage_pages;
+        merged.used = usage_pages;
+        for page in pages..merged.pages() {
+            if merged.usage(page)?.0 != 0 {
+                merged.used += 1;
+            }
+        }
+        Some(merged)
+    }
+    /// 只保留前`pages`页，使用表变小时多出来的页变为空闲
+    unsafe fn shrink(&mut self, pages: usize) -> Option<()> {
+        let old_usage_pages = self.usage_pages();
+        self.size = pages << A::PAGE_SHIFT;
+        let usage_pages = self.usage_pages();
+        for page in usage_pages..old_usage_pages {
+            self.set_usage(page, BuddyUsage(0))?;
+            #[cfg(feature = "poison")]
+            crate::poison::<A>(self.base.add(page << A::PAGE_SHIFT), FrameCount::new(1));
+        }
+        self.used = 0;
+        for page in 0..self.pages() {
+            if self.usage(page)?.0 != 0 {
+                self.used += 1;
+            }
+        }
+        self.skip = self.skip.min(usage_pages);
+        Some(())
+    }
 }
 
 pub struct BuddyAllocator<A> {
     table_virt: VirtualAddress,
+    // 从bump分配器继承的预留内存块，只有这些内存块可以`release`
+    reserved: [MemoryArea; BUMP_RESERVED_MAX],
+    reserved_count: usize,
     phantom: PhantomData<A>,
 }
 
 impl<A: Arch> BuddyAllocator<A> {
     const BUDDY_ENTRIES: usize = A::PAGE_SIZE / mem::size_of::<BuddyEntry<A>>();
     pub unsafe fn new(mut bump_allocator: BumpAllocator<A>) -> Option<Self> {
-        
         let table_phys = bump_allocator.allocate_one()?;
         let table_virt = A::phys_to_virt(table_phys);
         for i in 0..(A::PAGE_SIZE / mem::size_of::<BuddyEntry<A>>()) {
             let virt = table_virt.add(i * mem::size_of::<BuddyEntry<A>>());
             A::write(virt, BuddyEntry::<A>::empty());
         }
+        let mut reserved = [MemoryArea {
+            base: PhysicalAddress::new(0),
+            size: 0,
+        }; BUMP_RESERVED_MAX];
+        let reserved_count = bump_allocator.reserved().len();
+        reserved[..reserved_count].copy_from_slice(bump_allocator.reserved());
         let allocator = Self {
             table_virt,
+            reserved,
+            reserved_count,
             phantom: PhantomData,
         };
-        let mut offset = bump_allocator.offset();
-        for old_area in bump_allocator.areas().iter() {
-            let mut area = old_area.clone();
-            if offset >= area.size {
-                offset -= area.size;
+        bump_allocator.free_areas(|area| allocator.insert_free(area));
+
+        for i in 0..Self::BUDDY_ENTRIES {
+            let virt = table_virt.add(i * mem::size_of::<BuddyEntry<A>>());
+            let mut entry = A::read::<BuddyEntry<A>>(virt);
+            entry.init_usage()?;
+            A::write(virt, entry)
+        }
+        Some(allocator)
+    }
+
+    /// 把空闲内存块加入表中，和相邻的内存块合并
+    unsafe fn insert_free(&self, area: MemoryArea) {
+        for i in 0..Self::BUDDY_ENTRIES {
+            let virt = self.table_virt.add(i * mem::size_of::<BuddyEntry<A>>());
+            let mut entry = A::read::<BuddyEntry<A>>(virt);
+            // 不能引用packed结构体的字段，先复制出来
+            let (base, size) = (entry.base, entry.size);
+            let inserted = if area.base.add(area.size) == base {
+                entry.base = area.base;
+                entry.size += area.size;
+                true
+            } else if area.base == base.add(size) {
+                entry.size += area.size;
+                true
+            } else if entry.size == 0 {
+                entry.base = area.base;
+                entry.size = area.size;
+                true
+            } else {
+                false
+            };
+            if inserted {
+                A::write(virt, entry);
+                break;
+            }
+        }
+    }
+
+    /// 仍然预留的内存块
+    pub fn reserved(&self) -> &[MemoryArea] {
+        &self.reserved[..self.reserved_count]
+    }
+
+    /// 释放不再需要的预留内存块（例如已经解压完的initfs），交给伙伴分配器管理。
+    /// 范围必须在同一个预留的内存块之内，可以只释放其中一部分
+    pub unsafe fn release(&mut self, area: MemoryArea) -> Option<()> {
+        let area = area.page_aligned::<A>()?;
+        let i = self.reserved().iter().position(|r| r.contains(&area))?;
+        let reserved = self.reserved[i];
+        let before = MemoryArea {
+            base: reserved.base,
+            size: area.base.data() - reserved.base.data(),
+        };
+        let after = MemoryArea {
+            base: area.end(),
+            size: reserved.end().data() - area.end().data(),
+        };
+        // 从中间释放时预留块一分为二，需要一个空位
+        if before.size > 0 && after.size > 0 && self.reserved_count >= BUMP_RESERVED_MAX {
+            return None;
+        }
+        self.merge_area(area)?;
+        self.reserved_count -= 1;
+        self.reserved[i] = self.reserved[self.reserved_count];
+        for rest in [before, after].iter() {
+            if rest.size > 0 {
+                self.reserved[self.reserved_count] = *rest;
+                self.reserved_count += 1;
+            }
+        }
+        Some(())
+    }
+
+    /// 把释放的内存块并入相邻的内存块，不占用新的表项和使用表。
+    /// 没有相邻的内存块或者放不下合并后的使用表时单独加入
+    unsafe fn merge_area(&mut self, area: MemoryArea) -> Option<()> {
+        let pages = area.size >> A::PAGE_SHIFT;
+        for i in 0..Self::BUDDY_ENTRIES {
+            let virt = self.table_virt.add(i * mem::size_of::<BuddyEntry<A>>());
+            let entry = A::read::<BuddyEntry<A>>(virt);
+            let (base, size) = (entry.base, entry.size);
+            if size == 0 {
                 continue;
-            } else if offset > 0 {
-                area.base = area.base.add(offset);
-                area.size -= offset;
-                offset = 0;
-            }
-            for i in 0..(A::PAGE_SIZE / mem::size_of::<BuddyEntry<A>>()) {
-                let virt = table_virt.add(i * mem::size_of::<BuddyEntry<A>>());
-                let mut entry = A::read::<BuddyEntry<A>>(virt);
-                let inserted = if area.base.add(area.size) == entry.base {
-                    entry.base = area.base;
-                    entry.size = area.size;
-                    true
-                } else if area.base == entry.base.add(entry.size) {
-                    entry.size += area.size;
-                    true
-                } else if entry.size == 0 {
-                    entry.base = area.base;
-                    entry.size = area.size;
-                    true
-                } else {
-                    false
-                };
-                if inserted {
-                    A::write(virt, entry);
-                    break;
+            }
+            let merged = if base.add(size) == area.base {
+                let mut grown = entry;
+                grown.grow(pages).map(|_| grown)
+            } else if area.end() == base {
+                entry.prepend(pages)
+            } else {
+                None
+            };
+            if let Some(merged) = merged {
+                A::write(virt, merged);
+                return Some(());
+            }
+        }
+        self.add_area(area)
+    }
+
+    /// 运行时加入新的内存块（内存热插拔、可回收的ACPI内存等），
+    /// 该内存块必须已经映射到`phys_to_virt`
+    pub unsafe fn add_area(&mut self, area: MemoryArea) -> Option<()> {
+        let start = area.base.align_down(A::PAGE_SIZE).data();
+        let end = area.base.add(area.size).align_up(A::PAGE_SIZE).data();
+        if start >= end {
+            return None;
+        }
+        let mut free_i = None;
+        for i in 0..Self::BUDDY_ENTRIES {
+            let virt = self.table_virt.add(i * mem::size_of::<BuddyEntry<A>>());
+            let entry = A::read::<BuddyEntry<A>>(virt);
+            let base = entry.base;
+            if entry.size == 0 {
+                if free_i.is_none() {
+                    free_i = Some(i);
                 }
+            } else if base.data() < end && start < base.data() + entry.size {
+                return None;
             }
         }
+        let virt = self
+            .table_virt
+            .add(free_i? * mem::size_of::<BuddyEntry<A>>());
+        let mut entry = BuddyEntry::<A>::empty();
+        entry.base = PhysicalAddress::new(start);
+        entry.size = end - start;
+        if entry.pages() <= entry.usage_pages() {
+            return None;
+        }
+        entry.init_usage()?;
+        A::write(virt, entry);
+        Some(())
+    }
 
+    /// 移除内存块（内存气球、热拔出），只有当其中的页全部空闲时才会成功。
+    /// 范围可以只覆盖表中内存块的一部分，剩下的部分拆开保留，
+    /// 后半部分在开头建立自己的使用表，这些页也必须空闲
+    pub unsafe fn remove_area(&mut self, area: MemoryArea) -> Option<()> {
+        let area = area.page_aligned::<A>()?;
+        let mut found = false;
+        let mut free_slots = 0;
+        let mut new_slots = 0;
         for i in 0..Self::BUDDY_ENTRIES {
-            let virt = table_virt.add(i * mem::size_of::<BuddyEntry<A>>());
-            let mut entry = A::read::<BuddyEntry<A>>(virt);
+            let virt = self.table_virt.add(i * mem::size_of::<BuddyEntry<A>>());
+            let entry = A::read::<BuddyEntry<A>>(virt);
+            if entry.size == 0 {
+                free_slots += 1;
+                continue;
+            }
+            let (first, last) = match entry.removed_pages(&area) {
+                Some(pages) => pages,
+                None => continue,
+            };
+            // 不能只移除使用表的一部分
             let usage_pages = entry.usage_pages();
-            if entry.pages() > usage_pages {
-                let usage_start = entry.usage_addr(0)?;
-                for page in 0..usage_pages {
-                    A::write_bytes(usage_start.add(page << A::PAGE_SHIFT), 0, A::PAGE_SIZE);
+            if first > 0 && first < usage_pages {
+                return None;
+            }
+            let mut check = first.max(usage_pages)..last;
+            if last < entry.pages() {
+                let tail = entry.split_off(last);
+                if tail.pages() <= tail.usage_pages() {
+                    return None;
                 }
-                for page in 0..usage_pages {
-                    entry.set_usage(page, BuddyUsage(1))?;
+                check.end = last + tail.usage_pages();
+                if first > 0 {
+                    new_slots += 1;
                 }
             }
-            entry.skip = usage_pages;
-            entry.used = usage_pages;
-            A::write(virt, entry)
+            for page in check {
+                if entry.usage(page)?.0 != 0 {
+                    return None;
+                }
+            }
+            found = true;
         }
-        Some(allocator)
+        if !found || new_slots > free_slots {
+            return None;
+        }
+        for i in 0..Self::BUDDY_ENTRIES {
+            let virt = self.table_virt.add(i * mem::size_of::<BuddyEntry<A>>());
+            let mut entry = A::read::<BuddyEntry<A>>(virt);
+            let (first, last) = match entry.removed_pages(&area) {
+                Some(pages) => pages,
+                None => continue,
+            };
+            let tail = if last < entry.pages() {
+                let mut tail = entry.split_off(last);
+                tail.init_split(&entry, last)?;
+                Some(tail)
+            } else {
+                None
+            };
+            if first > 0 {
+                entry.shrink(first)?;
+                A::write(virt, entry);
+                if let Some(tail) = tail {
+                    self.insert_entry(tail)?;
+                }
+            } else {
+                A::write(virt, tail.unwrap_or_else(BuddyEntry::<A>::empty));
+            }
+        }
+        Some(())
+    }
+
+    /// 把内存块放到表中的空位
+    unsafe fn insert_entry(&mut self, entry: BuddyEntry<A>) -> Option<()> {
+        for i in 0..Self::BUDDY_ENTRIES {
+            let virt = self.table_virt.add(i * mem::size_of::<BuddyEntry<A>>());
+            if A::read::<BuddyEntry<A>>(virt).size == 0 {
+                A::write(virt, entry);
+                return Some(());
+            }
+        }
+        None
+    }
+
+    /// 包含`count`个页框的内存块，和第一个页框在块中的序号
+    unsafe fn entry_of(
+        &self,
+        base: PhysicalAddress,
+        count: FrameCount,
+    ) -> Option<(BuddyEntry<A>, usize)> {
+        if self.table_virt.data() == 0 {
+            return None;
+        }
+        let size = count.data() * A::PAGE_SIZE;
+        for i in 0..Self::BUDDY_ENTRIES {
+            let virt = self.table_virt.add(i * mem::size_of::<BuddyEntry<A>>());
+            let entry = A::read::<BuddyEntry<A>>(virt);
+            // 不能引用packed结构体的字段，先复制出来
+            let (entry_base, entry_size) = (entry.base, entry.size);
+            if entry_size > 0 && base >= entry_base && base.add(size) <= entry_base.add(entry_size)
+            {
+                return Some((entry, (base.data() - entry_base.data()) >> A::PAGE_SHIFT));
+            }
+        }
+        None
     }
 }
 
@@ -170,6 +531,13 @@ impl<A: Arch> FrameAllocator for BuddyAllocator<A> {
                     usage.0 += 1;
                     entry.set_usage(page, usage);
                     let page_phys = entry.base.add(page << A::PAGE_SHIFT);
+                    #[cfg(feature = "poison")]
+                    {
+                        if let Err(report) = crate::check_poison::<A>(page_phys, FrameCount::new(1))
+                        {
+                            panic!("{}", report);
+                        }
+                    }
                     let page_virt = A::phys_to_virt(page_phys);
                     A::write_bytes(page_virt, 0, A::PAGE_SIZE);
                 }
@@ -206,6 +574,11 @@ impl<A: Arch> FrameAllocator for BuddyAllocator<A> {
                             entry.skip = page;
                         }
                         entry.used -= 1;
+                        #[cfg(feature = "poison")]
+                        crate::poison::<A>(
+                            entry.base.add(page << A::PAGE_SHIFT),
+                            FrameCount::new(1),
+                        );
                     }
                     entry
                         .set_usage(page, usage)
@@ -217,15 +590,260 @@ impl<A: Arch> FrameAllocator for BuddyAllocator<A> {
         }
     }
 
+    unsafe fn share(&mut self, base: PhysicalAddress, count: FrameCount) -> Option<()> {
+        let (entry, start_page) = self.entry_of(base, count)?;
+        let pages = start_page..start_page + count.data();
+        // 先检查所有页，失败时不修改任何引用计数
+        for page in pages.clone() {
+            let usage = entry.usage(page)?;
+            if usage.0 == 0 || usage.0 == u8::MAX {
+                return None;
+            }
+        }
+        for page in pages {
+            let mut usage = entry.usage(page)?;
+            usage.0 += 1;
+            entry.set_usage(page, usage)?;
+        }
+        Some(())
+    }
+
+    unsafe fn ref_count(&self, address: PhysicalAddress) -> Option<usize> {
+        let (entry, page) = self.entry_of(address, FrameCount::new(1))?;
+        Some(entry.usage(page)?.0 as usize)
+    }
+
     unsafe fn usage(&self) -> FrameUsage {
-        let mut total = 0;
-        let mut used = 0;
+        let mut usage = FrameUsage::new(FrameCount::new(0), FrameCount::new(0));
+        if self.table_virt.data() == 0 {
+            return usage;
+        }
         for i in 0..Self::BUDDY_ENTRIES {
             let virt = self.table_virt.add(i * mem::size_of::<BuddyEntry<A>>());
             let entry = A::read::<BuddyEntry<A>>(virt);
-            total += entry.size >> A::PAGE_SHIFT;
-            used += entry.used;
+            if entry.size != 0 {
+                if let Some(entry_usage) = entry.frame_usage() {
+                    usage.merge(&entry_usage);
+                }
+            }
+        }
+        usage
+    }
+
+    unsafe fn area_usage(&self, i: usize) -> Option<(MemoryArea, FrameUsage)> {
+        if self.table_virt.data() == 0 {
+            return None;
+        }
+        let mut area_i = 0;
+        for entry_i in 0..Self::BUDDY_ENTRIES {
+            let virt = self
+                .table_virt
+                .add(entry_i * mem::size_of::<BuddyEntry<A>>());
+            let entry = A::read::<BuddyEntry<A>>(virt);
+            if entry.size == 0 {
+                continue;
+            }
+            if area_i < i {
+                area_i += 1;
+                continue;
+            }
+            let area = MemoryArea {
+                base: entry.base,
+                size: entry.size,
+            };
+            return Some((area, entry.frame_usage()?));
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        emulate_test, emulate_test_with, Arch, EmulateArch, EmulateConfig, FrameAllocator,
+        FrameCount, MemoryArea, PhysicalAddress, MEGA_BYTE,
+    };
+
+    #[test]
+    fn release() {
+        unsafe {
+            let page = EmulateArch::PAGE_SIZE;
+            let mut config = EmulateConfig::default();
+            let initfs = MemoryArea {
+                base: config.areas[1].base.add(MEGA_BYTE),
+                size: 64 * page,
+            };
+            config.reserved.push(initfs);
+            let mut allocator = emulate_test_with(&config);
+            assert_eq!(allocator.reserved().len(), 1);
+            assert_eq!(allocator.ref_count(initfs.base), None);
+            let usage = allocator.usage();
+
+            // 没有预留的、部分重叠的范围不能释放
+            let area = |base, pages| MemoryArea {
+                base,
+                size: pages * page,
+            };
+            let free = initfs.base.add(2 * MEGA_BYTE);
+            assert!(allocator.release(area(free, 16)).is_none());
+            let partial = initfs.base.add(48 * page);
+            assert!(allocator.release(area(partial, 32)).is_none());
+
+            // 从中间释放，预留块一分为二
+            let middle = initfs.base.add(16 * page);
+            allocator.release(area(middle, 16)).unwrap();
+            assert!(allocator.release(area(middle, 16)).is_none());
+            assert_eq!(allocator.reserved().len(), 2);
+            assert_eq!(allocator.ref_count(middle.add(8 * page)), Some(0));
+            assert_eq!(allocator.ref_count(initfs.base), None);
+            let released = allocator.usage();
+            assert_eq!(released.tatal().data(), usage.tatal().data() + 16);
+            assert_eq!(released.used().data(), usage.used().data() + 1);
+
+            allocator.release(area(initfs.base, 16)).unwrap();
+            allocator.release(area(middle.add(16 * page), 32)).unwrap();
+            assert!(allocator.reserved().is_empty());
+            assert_eq!(allocator.usage().tatal().data(), usage.tatal().data() + 64);
+        }
+    }
+
+    #[test]
+    fn release_merges() {
+        unsafe {
+            let page = EmulateArch::PAGE_SIZE;
+            let mut config = EmulateConfig::default();
+            // 两段相邻的预留内存把内存块分成前后两个
+            let initfs = MemoryArea {
+                base: config.areas[1].base.add(MEGA_BYTE),
+                size: 8 * page,
+            };
+            let single = MemoryArea {
+                base: PhysicalAddress::new(initfs.base.data() - page),
+                size: page,
+            };
+            config.reserved = vec![single, initfs];
+            let mut allocator = emulate_test_with(&config);
+            let usage = allocator.usage();
+            assert!(allocator.area_usage(1).is_some());
+            assert!(allocator.area_usage(2).is_none());
+
+            // 并入后面的内存块，使用表移到新的开头，引用计数和内容不变
+            let frame = allocator.allocate_one().unwrap();
+            EmulateArch::write::<usize>(EmulateArch::phys_to_virt(frame), 0x5A);
+            allocator.release(initfs).unwrap();
+            assert!(allocator.area_usage(2).is_none());
+            assert_eq!(allocator.ref_count(initfs.base.add(4 * page)), Some(0));
+            assert_eq!(allocator.ref_count(frame), Some(1));
+            assert_eq!(
+                EmulateArch::read::<usize>(EmulateArch::phys_to_virt(frame)),
+                0x5A
+            );
+            let released = allocator.usage();
+            assert_eq!(released.tatal().data(), usage.tatal().data() + 8);
+            assert_eq!(released.used().data(), usage.used().data() + 1);
+            allocator.free_one(frame);
+
+            // 只有一页也可以释放，并入前面的内存块，不需要新的表项和使用表
+            allocator.release(single).unwrap();
+            assert!(allocator.reserved().is_empty());
+            assert!(allocator.area_usage(2).is_none());
+            assert_eq!(allocator.ref_count(single.base), Some(0));
+            let released = allocator.usage();
+            assert_eq!(released.tatal().data(), usage.tatal().data() + 9);
+            assert_eq!(released.used().data(), usage.used().data());
+        }
+    }
+
+    #[test]
+    fn add_remove_area() {
+        unsafe {
+            let page = EmulateArch::PAGE_SIZE;
+            // 默认配置的两块内存相邻，在表中合并成一个内存块
+            let mut allocator = emulate_test();
+            let total = allocator.usage().tatal().data();
+            let pages = MEGA_BYTE / page;
+            let a = allocator.allocate(FrameCount::new(4 * pages)).unwrap();
+            let b = allocator.allocate(FrameCount::new(12 * pages)).unwrap();
+            assert_eq!(b, a.add(4 * MEGA_BYTE));
+            EmulateArch::write::<usize>(EmulateArch::phys_to_virt(b), 0x5A);
+            allocator.free(a, FrameCount::new(4 * pages));
+
+            // 有使用中的页、只覆盖使用表的一部分时不能移除
+            let used = MemoryArea {
+                base: b.add(MEGA_BYTE),
+                size: MEGA_BYTE,
+            };
+            assert!(allocator.remove_area(used).is_none());
+            let table = MemoryArea {
+                base: EmulateConfig::default().areas[0].base,
+                size: page,
+            };
+            assert!(allocator.remove_area(table).is_none());
+
+            // 从合并后的内存块中间移除，后半部分保留原来的引用计数
+            let removed = MemoryArea {
+                base: a.add(MEGA_BYTE),
+                size: MEGA_BYTE,
+            };
+            allocator.remove_area(removed).unwrap();
+            assert_eq!(allocator.usage().tatal().data(), total - pages);
+            assert_eq!(allocator.ref_count(removed.base), None);
+            assert_eq!(allocator.ref_count(b), Some(1));
+            assert_eq!(
+                EmulateArch::read::<usize>(EmulateArch::phys_to_virt(b)),
+                0x5A
+            );
+            let c = allocator.allocate(FrameCount::new(2 * pages)).unwrap();
+            assert!(!removed.overlaps(&MemoryArea {
+                base: c,
+                size: 2 * MEGA_BYTE,
+            }));
+            allocator.free(c, FrameCount::new(2 * pages));
+            allocator.free(b, FrameCount::new(12 * pages));
+            assert!(allocator.remove_area(removed).is_none());
+
+            // 加回来之后重新可以分配，不能重复加入
+            allocator.add_area(removed).unwrap();
+            assert!(allocator.add_area(removed).is_none());
+            assert_eq!(allocator.ref_count(removed.base.add(8 * page)), Some(0));
+            assert_eq!(allocator.usage().tatal().data(), total);
+        }
+    }
+
+    #[test]
+    fn usage() {
+        unsafe {
+            let page = EmulateArch::PAGE_SIZE;
+            let mut allocator = emulate_test();
+            let before = allocator.usage();
+            assert_eq!(before.shared().data(), 0);
+
+            // 中间释放4页留下一个空闲块，前两页共享
+            let frames = allocator.allocate(FrameCount::new(16)).unwrap();
+            allocator.free(frames.add(4 * page), FrameCount::new(4));
+            allocator.share(frames, FrameCount::new(2)).unwrap();
+            let usage = allocator.usage();
+            assert_eq!(usage.used().data(), before.used().data() + 12);
+            assert_eq!(usage.shared().data(), 2);
+            assert_eq!(usage.free_runs()[2], before.free_runs()[2] + 1);
+            assert_eq!(
+                usage.largest_free().data(),
+                before.largest_free().data() - 16
+            );
+
+            // 默认配置只有一个内存块，统计和总的相同
+            let (area, area_usage) = allocator.area_usage(0).unwrap();
+            assert!(area.base <= frames && frames < area.end());
+            assert!(allocator.area_usage(1).is_none());
+            assert_eq!(area_usage.shared().data(), 2);
+            assert_eq!(area_usage.free_runs(), usage.free_runs());
+
+            allocator.free(frames, FrameCount::new(4));
+            allocator.free(frames, FrameCount::new(2));
+            allocator.free(frames.add(8 * page), FrameCount::new(8));
+            let after = allocator.usage();
+            assert_eq!(after.used().data(), before.used().data());
+            assert_eq!(after.free_runs(), before.free_runs());
         }
-        FrameUsage::new(FrameCount::new(used), FrameCount::new(total))
     }
 }
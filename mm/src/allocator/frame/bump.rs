@@ -2,10 +2,16 @@ use core::marker::PhantomData;
 
 use crate::{Arch, FrameAllocator, FrameCount, FrameUsage, MemoryArea, PhysicalAddress};
 
+/// 预留内存块的最大数量
+pub const BUMP_RESERVED_MAX: usize = 16;
+
 pub struct BumpAllocator<A> {
     areas: &'static [MemoryArea],
     // 偏移量
     offset: usize,
+    // 预留内存块（内核镜像、initfs、引导数据），永远不会被分配出去
+    reserved: [MemoryArea; BUMP_RESERVED_MAX],
+    reserved_count: usize,
     phantom: PhantomData<A>,
 }
 
@@ -14,6 +20,11 @@ impl<A: Arch> BumpAllocator<A> {
         Self {
             areas,
             offset,
+            reserved: [MemoryArea {
+                base: PhysicalAddress::new(0),
+                size: 0,
+            }; BUMP_RESERVED_MAX],
+            reserved_count: 0,
             phantom: PhantomData,
         }
     }
@@ -23,24 +34,97 @@ impl<A: Arch> BumpAllocator<A> {
     pub fn offset(&self) -> usize {
         self.offset
     }
+    /// 预留一块物理内存，范围会向外对齐到页边界。
+    /// 和已经预留的内存块重叠或者地址溢出时返回`None`
+    pub fn reserve(&mut self, area: MemoryArea) -> Option<()> {
+        if self.reserved_count >= BUMP_RESERVED_MAX {
+            return None;
+        }
+        let area = area.page_aligned::<A>()?;
+        if self.reserved().iter().any(|r| r.overlaps(&area)) {
+            return None;
+        }
+        self.reserved[self.reserved_count] = area;
+        self.reserved_count += 1;
+        Some(())
+    }
+    /// 已预留的内存块
+    pub fn reserved(&self) -> &[MemoryArea] {
+        &self.reserved[..self.reserved_count]
+    }
+    pub fn is_reserved(&self, phys: PhysicalAddress) -> bool {
+        self.reserved()
+            .iter()
+            .any(|area| phys >= area.base && phys < area.end())
+    }
+    /// 依次返回尚未分配的内存块，跳过预留的部分
+    pub fn free_areas<F: FnMut(MemoryArea)>(&self, mut f: F) {
+        let mut offset = self.offset;
+        for old_area in self.areas.iter() {
+            let mut area = *old_area;
+            if offset >= area.size {
+                offset -= area.size;
+                continue;
+            } else if offset > 0 {
+                area.base = area.base.add(offset);
+                area.size -= offset;
+                offset = 0;
+            }
+            Self::split_reserved(area, self.reserved(), &mut f);
+        }
+    }
+    fn split_reserved<F: FnMut(MemoryArea)>(area: MemoryArea, reserved: &[MemoryArea], f: &mut F) {
+        if area.size == 0 {
+            return;
+        }
+        let end = area.base.add(area.size);
+        for (i, r) in reserved.iter().enumerate() {
+            let r_end = r.base.add(r.size);
+            if r.base < end && area.base < r_end {
+                if area.base < r.base {
+                    let before = MemoryArea {
+                        base: area.base,
+                        size: r.base.data() - area.base.data(),
+                    };
+                    Self::split_reserved(before, &reserved[i + 1..], f);
+                }
+                if r_end < end {
+                    let after = MemoryArea {
+                        base: r_end,
+                        size: end.data() - r_end.data(),
+                    };
+                    Self::split_reserved(after, &reserved[i + 1..], f);
+                }
+                return;
+            }
+        }
+        f(area);
+    }
+    fn phys(&self, mut offset: usize) -> Option<PhysicalAddress> {
+        for area in self.areas.iter() {
+            if offset < area.size {
+                return Some(area.base.add(offset));
+            }
+            offset -= area.size;
+        }
+        None
+    }
 }
 /// BumpAllocator  bump 内存分配器
 impl<A: Arch> FrameAllocator for BumpAllocator<A> {
-    /// 内存分配
+    /// 内存分配，跳过预留的内存块
     unsafe fn allocate(&mut self, count: FrameCount) -> Option<PhysicalAddress> {
         if count.data() != 1 {
             return None;
         }
-        let mut offset = self.offset;
-        for area in self.areas.iter() {
-            if offset < area.size {
-                let page_phys = area.base.add(offset);
-                let page_virt = A::phys_to_virt(page_phys);
-                A::write_bytes(page_virt, 0, A::PAGE_SIZE);
-                self.offset += A::PAGE_SIZE;
-                return Some(page_phys);
+        while let Some(page_phys) = self.phys(self.offset) {
+            self.offset += A::PAGE_SIZE;
+            if self.is_reserved(page_phys) {
+                continue;
             }
-            offset -= area.size;
+            let page_virt = A::phys_to_virt(page_phys);
+            A::write_bytes(page_virt, 0, A::PAGE_SIZE);
+            return Some(page_phys);
         }
         None
     }
@@ -58,3 +142,62 @@ impl<A: Arch> FrameAllocator for BumpAllocator<A> {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::{BumpAllocator, BUMP_RESERVED_MAX};
+    use crate::{
+        emulate_test_bump, Arch, EmulateArch, FrameAllocator, MemoryArea, PhysicalAddress,
+    };
+
+    fn area(base: usize, size: usize) -> MemoryArea {
+        MemoryArea {
+            base: PhysicalAddress::new(base),
+            size,
+        }
+    }
+
+    #[test]
+    fn reserve() {
+        let page = EmulateArch::PAGE_SIZE;
+        let mut bump_allocator = BumpAllocator::<EmulateArch>::new(&[], 0);
+        // 向外对齐到页边界
+        bump_allocator.reserve(area(page + 8, page)).unwrap();
+        let reserved = bump_allocator.reserved()[0];
+        assert_eq!((reserved.base.data(), reserved.size), (page, 2 * page));
+        assert!(bump_allocator.is_reserved(PhysicalAddress::new(2 * page + 8)));
+        assert!(!bump_allocator.is_reserved(PhysicalAddress::new(3 * page)));
+
+        // 重叠、为空和溢出的范围都被拒绝
+        assert!(bump_allocator.reserve(area(2 * page, page)).is_none());
+        assert!(bump_allocator.reserve(area(0, page + 1)).is_none());
+        assert!(bump_allocator.reserve(area(4 * page, 0)).is_none());
+        assert!(bump_allocator
+            .reserve(area(usize::MAX - page, 2 * page))
+            .is_none());
+        assert!(bump_allocator.reserve(area(usize::MAX - 8, 4)).is_none());
+        assert_eq!(bump_allocator.reserved().len(), 1);
+
+        bump_allocator.reserve(area(3 * page, page)).unwrap();
+        for i in 2..BUMP_RESERVED_MAX {
+            bump_allocator.reserve(area((i + 2) * page, page)).unwrap();
+        }
+        let next = area((BUMP_RESERVED_MAX + 2) * page, page);
+        assert!(bump_allocator.reserve(next).is_none());
+    }
+
+    #[test]
+    fn allocate_skips_reserved() {
+        unsafe {
+            let page = EmulateArch::PAGE_SIZE;
+            let mut bump_allocator = emulate_test_bump();
+            let next = bump_allocator.phys(bump_allocator.offset()).unwrap();
+            bump_allocator
+                .reserve(MemoryArea {
+                    base: next,
+                    size: 2 * page,
+                })
+                .unwrap();
+            assert_eq!(bump_allocator.allocate_one(), Some(next.add(2 * page)));
+        }
+    }
+}
@@ -1,9 +1,21 @@
-use crate::PhysicalAddress;
+use core::mem;
+
+use crate::{Arch, Frame, FrameRange, MemoryArea, PhysicalAddress};
 
 pub use self::buddy::*;
 pub use self::bump::*;
+#[cfg(feature = "poison")]
+pub use self::poison::*;
+pub use self::slab::*;
+#[cfg(feature = "track")]
+pub use self::track::*;
 mod buddy;
 mod bump;
+#[cfg(feature = "poison")]
+mod poison;
+mod slab;
+#[cfg(feature = "track")]
+mod track;
 
 /// 页框大小
 #[derive(Clone, Copy, Debug)]
@@ -19,15 +31,29 @@ impl FrameCount {
     }
 }
 
+/// 空闲块直方图的阶数，第i阶统计长度在[2^i, 2^(i+1))页之间的连续空闲块
+pub const FRAME_RUN_ORDERS: usize = 20;
+
 /// 页框分配情况
+#[derive(Clone, Copy, Debug)]
 pub struct FrameUsage {
     used: FrameCount,
     total: FrameCount,
+    // 引用计数大于1的页框
+    shared: FrameCount,
+    largest_free: FrameCount,
+    free_runs: [usize; FRAME_RUN_ORDERS],
 }
 
 impl FrameUsage {
     pub fn new(used: FrameCount, total: FrameCount) -> Self {
-        Self { used, total }
+        Self {
+            used,
+            total,
+            shared: FrameCount(0),
+            largest_free: FrameCount(0),
+            free_runs: [0; FRAME_RUN_ORDERS],
+        }
     }
     pub fn used(&self) -> FrameCount {
         self.used
@@ -39,15 +65,53 @@ impl FrameUsage {
     pub fn free(&self) -> FrameCount {
         FrameCount(self.total.0 - self.used.0)
     }
+    pub fn shared(&self) -> FrameCount {
+        self.shared
+    }
+    /// 最大的连续空闲块
+    pub fn largest_free(&self) -> FrameCount {
+        self.largest_free
+    }
+    /// 连续空闲块长度的直方图
+    pub fn free_runs(&self) -> &[usize; FRAME_RUN_ORDERS] {
+        &self.free_runs
+    }
+    pub fn add_shared(&mut self, count: FrameCount) {
+        self.shared.0 += count.0;
+    }
+    /// 记录一个连续空闲块
+    pub fn add_free_run(&mut self, run: FrameCount) {
+        if run.0 == 0 {
+            return;
+        }
+        let order = mem::size_of::<usize>() * 8 - 1 - run.0.leading_zeros() as usize;
+        self.free_runs[order.min(FRAME_RUN_ORDERS - 1)] += 1;
+        if run.0 > self.largest_free.0 {
+            self.largest_free = run;
+        }
+    }
+    /// 合并另一个内存块的分配情况
+    pub fn merge(&mut self, other: &FrameUsage) {
+        self.used.0 += other.used.0;
+        self.total.0 += other.total.0;
+        self.shared.0 += other.shared.0;
+        if other.largest_free.0 > self.largest_free.0 {
+            self.largest_free = other.largest_free;
+        }
+        for (runs, other_runs) in self.free_runs.iter_mut().zip(other.free_runs.iter()) {
+            *runs += *other_runs;
+        }
+    }
 }
 
 /// 页帧分配器 trait
 pub trait FrameAllocator {
     /// 分配物理内存
     unsafe fn allocate(&mut self, count: FrameCount) -> Option<PhysicalAddress>;
-    /// 释放已经分配的物理内存
+    /// 释放已经分配的物理内存，开启`poison`时会用`POISON_BYTE`填充
     unsafe fn free(&mut self, address: PhysicalAddress, count: FrameCount);
     /// 分配一块物理内存地址
+    #[track_caller]
     unsafe fn allocate_one(&mut self) -> Option<PhysicalAddress> {
         self.allocate(FrameCount::new(1))
     }
@@ -55,7 +119,62 @@ pub trait FrameAllocator {
     unsafe fn free_one(&mut self, address: PhysicalAddress) {
         self.free(address, FrameCount::new(1));
     }
+    /// 分配连续的页框
+    #[track_caller]
+    unsafe fn allocate_range<A: Arch>(&mut self, count: FrameCount) -> Option<FrameRange<A>>
+    where
+        Self: Sized,
+    {
+        let base = self.allocate(count)?;
+        Some(FrameRange::new(Frame::containing(base), count))
+    }
+    /// 释放`allocate_range`分配的页框
+    unsafe fn free_range<A: Arch>(&mut self, range: FrameRange<A>)
+    where
+        Self: Sized,
+    {
+        self.free(range.start().start(), FrameCount::new(range.len()));
+    }
+    /// 增加已经分配的页框的引用计数，用于多个页表共享页框（例如写时复制），
+    /// 之后每个引用都要`free`一次。不支持共享的分配器返回`None`
+    unsafe fn share(&mut self, _address: PhysicalAddress, _count: FrameCount) -> Option<()> {
+        None
+    }
+    /// 页框的引用计数，0表示空闲。不支持共享的分配器返回`None`
+    unsafe fn ref_count(&self, _address: PhysicalAddress) -> Option<usize> {
+        None
+    }
     /// 内存分配情况
     unsafe fn usage(&self) -> FrameUsage;
+    /// 第i个内存块的分配情况
+    unsafe fn area_usage(&self, _i: usize) -> Option<(MemoryArea, FrameUsage)> {
+        None
+    }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::{FrameCount, FrameUsage, FRAME_RUN_ORDERS};
+
+    #[test]
+    fn free_runs() {
+        let mut usage = FrameUsage::new(FrameCount::new(10), FrameCount::new(100));
+        for &run in [0, 1, 3, 4, 7, 8].iter() {
+            usage.add_free_run(FrameCount::new(run));
+        }
+        // 长度为0的块不统计，4和7都在第2阶
+        assert_eq!(usage.free_runs()[..4], [1, 1, 2, 1]);
+        assert_eq!(usage.largest_free().data(), 8);
+
+        // 超过最高阶的块统计在最高阶
+        let mut other = FrameUsage::new(FrameCount::new(1), FrameCount::new(2));
+        other.add_free_run(FrameCount::new(1 << 30));
+        other.add_shared(FrameCount::new(3));
+        usage.merge(&other);
+        assert_eq!(usage.free_runs()[FRAME_RUN_ORDERS - 1], 1);
+        assert_eq!(usage.largest_free().data(), 1 << 30);
+        assert_eq!(usage.used().data(), 11);
+        assert_eq!(usage.tatal().data(), 102);
+        assert_eq!(usage.shared().data(), 3);
+    }
+}
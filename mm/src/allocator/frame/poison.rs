@@ -0,0 +1,110 @@
+use core::{fmt, mem};
+
+use crate::{Arch, FrameCount, PhysicalAddress};
+
+/// 释放后填充页框的字节
+pub const POISON_BYTE: u8 = 0x6B;
+const POISON_WORD: usize = usize::MAX / 0xFF * POISON_BYTE as usize;
+
+/// 被破坏的页框
+#[derive(Clone, Copy, Debug)]
+pub struct PoisonReport {
+    pub frame: PhysicalAddress,
+    /// 第一个被修改的字节在页框中的偏移
+    pub offset: usize,
+    pub value: u8,
+}
+
+impl fmt::Display for PoisonReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "poisoned frame 0x{:X} modified at offset 0x{:X}: 0x{:02X} instead of 0x{:02X}",
+            self.frame.data(),
+            self.offset,
+            self.value,
+            POISON_BYTE
+        )
+    }
+}
+
+/// 用`POISON_BYTE`填充页框
+pub unsafe fn poison<A: Arch>(base: PhysicalAddress, count: FrameCount) {
+    for page in 0..count.data() {
+        let page_virt = A::phys_to_virt(base.add(page << A::PAGE_SHIFT));
+        A::write_bytes(page_virt, POISON_BYTE, A::PAGE_SIZE);
+    }
+}
+
+/// 检查页框是否仍然是`POISON_BYTE`，用于发现释放后被写入的页框
+pub unsafe fn check_poison<A: Arch>(
+    base: PhysicalAddress,
+    count: FrameCount,
+) -> Result<(), PoisonReport> {
+    for page in 0..count.data() {
+        let frame = base.add(page << A::PAGE_SHIFT);
+        let page_virt = A::phys_to_virt(frame);
+        for word in 0..A::PAGE_SIZE / mem::size_of::<usize>() {
+            let word_offset = word * mem::size_of::<usize>();
+            if A::read::<usize>(page_virt.add(word_offset)) == POISON_WORD {
+                continue;
+            }
+            for offset in word_offset..word_offset + mem::size_of::<usize>() {
+                let value = A::read::<u8>(page_virt.add(offset));
+                if value != POISON_BYTE {
+                    return Err(PoisonReport {
+                        frame,
+                        offset,
+                        value,
+                    });
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_poison, POISON_BYTE};
+    use crate::{emulate_test, Arch, EmulateArch, FrameAllocator, FrameCount};
+
+    #[test]
+    fn write_after_free() {
+        unsafe {
+            let page = EmulateArch::PAGE_SIZE;
+            let mut allocator = emulate_test();
+            let frames = allocator.allocate(FrameCount::new(2)).unwrap();
+            allocator.free(frames, FrameCount::new(2));
+            assert!(check_poison::<EmulateArch>(frames, FrameCount::new(2)).is_ok());
+            let virt = EmulateArch::phys_to_virt(frames.add(page));
+            assert_eq!(EmulateArch::read::<u8>(virt.add(0x123)), POISON_BYTE);
+
+            // 报告第一个被修改的字节
+            EmulateArch::write::<u8>(virt.add(0x123), 0x42);
+            EmulateArch::write::<u8>(virt.add(0x456), 0x43);
+            let report = check_poison::<EmulateArch>(frames, FrameCount::new(2)).unwrap_err();
+            assert_eq!(report.frame, frames.add(page));
+            assert_eq!((report.offset, report.value), (0x123, 0x42));
+            assert_eq!(
+                report.to_string(),
+                format!(
+                    "poisoned frame 0x{:X} modified at offset 0x123: 0x42 instead of 0x6B",
+                    frames.add(page).data()
+                )
+            );
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "modified at offset 0x8")]
+    fn allocate_poisoned() {
+        unsafe {
+            let mut allocator = emulate_test();
+            let frame = allocator.allocate_one().unwrap();
+            allocator.free_one(frame);
+            EmulateArch::write::<usize>(EmulateArch::phys_to_virt(frame).add(8), 0);
+            allocator.allocate_one();
+        }
+    }
+}
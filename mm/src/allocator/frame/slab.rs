@@ -0,0 +1,372 @@
+use core::{marker::PhantomData, mem};
+
+use crate::{
+    Arch, BumpAllocator, FrameAllocator, FrameCount, FrameUsage, MemoryArea, PhysicalAddress,
+};
+
+/// 空闲块开头在链表指针之后写入的标记，和块的地址、级别一起用于发现重复释放
+const SLAB_FREE_MAGIC: usize = 0x51AB_F4EE;
+/// 空闲块开头的头部：下一个块、上一个块和标记
+const SLAB_HEADER_SIZE: usize = 3 * mem::size_of::<usize>();
+/// 链表的空指针，不是页对齐的地址，不会是空闲块
+const SLAB_NULL: PhysicalAddress = PhysicalAddress::new(usize::MAX);
+
+/// 空闲块双向链表，链表指针和标记保存在空闲块的开头，可以直接摘下任意一个块
+struct SlabNode<A> {
+    head: PhysicalAddress,
+    count: usize,
+    phantom: PhantomData<A>,
+}
+
+impl<A: Arch> SlabNode<A> {
+    fn empty() -> Self {
+        Self {
+            head: SLAB_NULL,
+            count: 0,
+            phantom: PhantomData,
+        }
+    }
+
+    fn magic(phys: PhysicalAddress, level: usize) -> usize {
+        SLAB_FREE_MAGIC ^ phys.data() ^ level
+    }
+
+    unsafe fn set_next(phys: PhysicalAddress, next: PhysicalAddress) {
+        A::write(A::phys_to_virt(phys), next);
+    }
+
+    unsafe fn set_prev(phys: PhysicalAddress, prev: PhysicalAddress) {
+        A::write(A::phys_to_virt(phys).add(mem::size_of::<usize>()), prev);
+    }
+
+    /// `phys`开头是否是`level`级空闲块的头部
+    unsafe fn is_head(phys: PhysicalAddress, level: usize) -> bool {
+        let magic = A::phys_to_virt(phys).add(2 * mem::size_of::<usize>());
+        A::read::<usize>(magic) == Self::magic(phys, level)
+    }
+
+    unsafe fn insert(&mut self, phys: PhysicalAddress, level: usize) {
+        let virt = A::phys_to_virt(phys);
+        Self::set_next(phys, self.head);
+        Self::set_prev(phys, SLAB_NULL);
+        A::write(
+            virt.add(2 * mem::size_of::<usize>()),
+            Self::magic(phys, level),
+        );
+        if self.head != SLAB_NULL {
+            Self::set_prev(self.head, phys);
+        }
+        self.head = phys;
+        self.count += 1;
+    }
+
+    /// 从链表中摘下`phys`，清除头部
+    unsafe fn remove(&mut self, phys: PhysicalAddress) {
+        let virt = A::phys_to_virt(phys);
+        let next = A::read::<PhysicalAddress>(virt);
+        let prev = A::read::<PhysicalAddress>(virt.add(mem::size_of::<usize>()));
+        if prev == SLAB_NULL {
+            self.head = next;
+        } else {
+            Self::set_next(prev, next);
+        }
+        if next != SLAB_NULL {
+            Self::set_prev(next, prev);
+        }
+        self.count -= 1;
+        #[cfg(feature = "poison")]
+        A::write_bytes(virt, crate::POISON_BYTE, SLAB_HEADER_SIZE);
+        #[cfg(not(feature = "poison"))]
+        A::write_bytes(virt, 0, SLAB_HEADER_SIZE);
+    }
+
+    unsafe fn pop(&mut self) -> Option<PhysicalAddress> {
+        if self.count > 0 {
+            let phys = self.head;
+            self.remove(phys);
+            Some(phys)
+        } else {
+            None
+        }
+    }
+}
+
+/// 每一级页表大小（4K、2M、1G）一个空闲链表，可以很快地分配对齐的大页。
+/// 释放时同一个上一级块中的块全部空闲就合并成上一级的块
+pub struct SlabAllocator<A> {
+    nodes: [SlabNode<A>; 3],
+    total: usize,
+    // 只读取这些内存块中的块头部
+    areas: &'static [MemoryArea],
+}
+
+impl<A: Arch> SlabAllocator<A> {
+    pub unsafe fn new(bump_allocator: BumpAllocator<A>) -> Self {
+        let mut allocator = Self {
+            nodes: [SlabNode::empty(), SlabNode::empty(), SlabNode::empty()],
+            total: 0,
+            areas: bump_allocator.areas(),
+        };
+        bump_allocator.free_areas(|area: MemoryArea| {
+            #[cfg(feature = "poison")]
+            crate::poison::<A>(area.base, FrameCount::new(area.size >> A::PAGE_SHIFT));
+            allocator.insert_range(area.base, area.size);
+            allocator.total += area.size >> A::PAGE_SHIFT;
+        });
+        allocator
+    }
+
+    fn levels() -> usize {
+        (A::PAGE_LEVELS - 1).min(3)
+    }
+
+    fn level_size(level: usize) -> usize {
+        1 << (level * A::PAGE_ENTRY_SHIFT + A::PAGE_SHIFT)
+    }
+
+    /// 从`base`开始的`level`级块是否整个在可用内存中
+    fn in_areas(&self, base: PhysicalAddress, level: usize) -> bool {
+        let block = MemoryArea {
+            base,
+            size: Self::level_size(level),
+        };
+        self.areas.iter().any(|area| area.contains(&block))
+    }
+
+    /// 把一段内存拆成尽量大的对齐块放入空闲链表，不完整的页被忽略
+    unsafe fn insert_range(&mut self, base: PhysicalAddress, size: usize) {
+        let end = base.add(size).align_down(A::PAGE_SIZE);
+        let mut base = base.align_up(A::PAGE_SIZE);
+        if base >= end {
+            return;
+        }
+        let mut size = end.data() - base.data();
+        while size >= A::PAGE_SIZE {
+            for level in (0..Self::levels()).rev() {
+                let level_size = Self::level_size(level);
+                if size >= level_size && base.data() & (level_size - 1) == 0 {
+                    self.insert_block(base, level);
+                    base = base.add(level_size);
+                    size -= level_size;
+                    break;
+                }
+            }
+        }
+    }
+
+    /// 放入一个空闲块，同一个上一级块中的其他块都空闲时一起合并成上一级的块
+    unsafe fn insert_block(&mut self, mut base: PhysicalAddress, mut level: usize) {
+        while level + 1 < Self::levels() {
+            let parent = base.align_down(Self::level_size(level + 1));
+            if !self.in_areas(parent, level + 1) {
+                break;
+            }
+            let level_size = Self::level_size(level);
+            let siblings = (0..A::PAGE_ENTRIES)
+                .map(|i| parent.add(i * level_size))
+                .filter(|&sibling| sibling != base);
+            if !siblings
+                .clone()
+                .all(|sibling| SlabNode::<A>::is_head(sibling, level))
+            {
+                break;
+            }
+            for sibling in siblings {
+                self.nodes[level].remove(sibling);
+            }
+            base = parent;
+            level += 1;
+        }
+        self.nodes[level].insert(base, level);
+    }
+
+    /// 页框是否在某个空闲块中，只检查包含它的每一级对齐块开头的头部
+    unsafe fn is_free(&self, phys: PhysicalAddress) -> bool {
+        (0..Self::levels()).any(|level| {
+            let base = phys.align_down(Self::level_size(level));
+            self.in_areas(base, level) && SlabNode::<A>::is_head(base, level)
+        })
+    }
+
+    /// 空闲内存的字节数
+    pub fn remaining(&self) -> usize {
+        let mut remaining = 0;
+        for level in 0..Self::levels() {
+            remaining += self.nodes[level].count * Self::level_size(level);
+        }
+        remaining
+    }
+}
+
+impl<A: Arch> FrameAllocator for SlabAllocator<A> {
+    unsafe fn allocate(&mut self, count: FrameCount) -> Option<PhysicalAddress> {
+        let size = count.data() << A::PAGE_SHIFT;
+        if size == 0 {
+            return None;
+        }
+        for level in 0..Self::levels() {
+            let level_size = Self::level_size(level);
+            if size > level_size {
+                continue;
+            }
+            // 摘下时头部已经恢复成毒化的内容
+            if let Some(base) = self.nodes[level].pop() {
+                #[cfg(feature = "poison")]
+                {
+                    if let Err(report) = crate::check_poison::<A>(base, count) {
+                        panic!("{}", report);
+                    }
+                }
+                self.insert_range(base.add(size), level_size - size);
+                for page in 0..count.data() {
+                    let page_virt = A::phys_to_virt(base.add(page << A::PAGE_SHIFT));
+                    A::write_bytes(page_virt, 0, A::PAGE_SIZE);
+                }
+                return Some(base);
+            }
+        }
+        None
+    }
+
+    unsafe fn free(&mut self, address: PhysicalAddress, count: FrameCount) {
+        if !address.is_aligned(A::PAGE_SIZE) {
+            panic!("tried to free unaligned frame 0x{:X}", address.data());
+        }
+        for page in 0..count.data() {
+            if self.is_free(address.add(page << A::PAGE_SHIFT)) {
+                panic!("tried to free already free frame");
+            }
+        }
+        #[cfg(feature = "poison")]
+        crate::poison::<A>(address, count);
+        self.insert_range(address, count.data() << A::PAGE_SHIFT);
+    }
+
+    unsafe fn usage(&self) -> FrameUsage {
+        let free = self.remaining() >> A::PAGE_SHIFT;
+        let mut usage = FrameUsage::new(
+            FrameCount::new(self.total - free),
+            FrameCount::new(self.total),
+        );
+        for level in 0..Self::levels() {
+            for _ in 0..self.nodes[level].count {
+                usage.add_free_run(FrameCount::new(Self::level_size(level) >> A::PAGE_SHIFT));
+            }
+        }
+        usage
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SlabAllocator;
+    use crate::{
+        emulate_test_bump, Arch, EmulateArch, FrameAllocator, FrameCount, PhysicalAddress,
+    };
+
+    #[test]
+    fn huge_blocks() {
+        unsafe {
+            let bump_allocator = emulate_test_bump();
+            let mut allocator = SlabAllocator::<EmulateArch>::new(bump_allocator);
+            let total = allocator.usage().tatal().data();
+            assert_eq!(allocator.usage().used().data(), 0);
+
+            let huge_pages = EmulateArch::PAGE_ENTRIES;
+            let huge = allocator.allocate(FrameCount::new(huge_pages)).unwrap();
+            assert_eq!(huge.data() & (huge_pages * EmulateArch::PAGE_SIZE - 1), 0);
+            let page = allocator.allocate_one().unwrap();
+            assert_eq!(allocator.usage().used().data(), huge_pages + 1);
+
+            let virt = EmulateArch::phys_to_virt(page);
+            EmulateArch::write::<usize>(virt, 0x5A);
+            allocator.free_one(page);
+            assert_eq!(allocator.allocate_one(), Some(page));
+            assert_eq!(EmulateArch::read::<usize>(virt), 0);
+            allocator.free_one(page);
+
+            // 释放的大页重新作为一个对齐的块放回链表
+            allocator.free(huge, FrameCount::new(huge_pages));
+            assert_eq!(allocator.allocate(FrameCount::new(huge_pages)), Some(huge));
+            allocator.free(huge, FrameCount::new(huge_pages));
+            assert_eq!(allocator.usage().used().data(), 0);
+
+            let mut allocated = 0;
+            while allocator.allocate_one().is_some() {
+                allocated += 1;
+            }
+            assert_eq!(allocated, total);
+            assert!(allocator
+                .allocate(FrameCount::new(huge_pages + 1))
+                .is_none());
+        }
+    }
+
+    #[test]
+    fn coalesce() {
+        unsafe {
+            let mut allocator = SlabAllocator::<EmulateArch>::new(emulate_test_bump());
+            let huge_pages = EmulateArch::PAGE_ENTRIES;
+            let free_runs = allocator.usage().free_runs().len();
+
+            // 大页拆成4K页分配出去，全部释放之后重新合并
+            let huge = allocator.allocate(FrameCount::new(huge_pages)).unwrap();
+            allocator.free(huge, FrameCount::new(huge_pages));
+            let pages: Vec<_> = (0..huge_pages)
+                .map(|_| allocator.allocate_one().unwrap())
+                .collect();
+            assert!(pages.contains(&huge));
+            for &page in pages.iter().rev() {
+                allocator.free_one(page);
+            }
+            assert_eq!(allocator.usage().free_runs().len(), free_runs);
+            assert_eq!(allocator.allocate(FrameCount::new(huge_pages)), Some(huge));
+            allocator.free(huge, FrameCount::new(huge_pages));
+
+            // 所有内存都按4K页分配之后释放，仍然可以分配大页
+            let mut pages = Vec::new();
+            while let Some(page) = allocator.allocate_one() {
+                pages.push(page);
+            }
+            for page in pages {
+                allocator.free_one(page);
+            }
+            assert_eq!(allocator.usage().used().data(), 0);
+            assert_eq!(allocator.usage().free_runs().len(), free_runs);
+            assert!(allocator.allocate(FrameCount::new(huge_pages)).is_some());
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "tried to free unaligned frame")]
+    fn free_unaligned() {
+        unsafe {
+            let mut allocator = SlabAllocator::<EmulateArch>::new(emulate_test_bump());
+            let page = allocator.allocate_one().unwrap();
+            allocator.free_one(PhysicalAddress::new(page.data() + 8));
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "tried to free already free frame")]
+    fn double_free() {
+        unsafe {
+            let mut allocator = SlabAllocator::<EmulateArch>::new(emulate_test_bump());
+            let page = allocator.allocate_one().unwrap();
+            allocator.free_one(page);
+            allocator.free_one(page);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "tried to free already free frame")]
+    fn free_inside_free_block() {
+        unsafe {
+            let mut allocator = SlabAllocator::<EmulateArch>::new(emulate_test_bump());
+            let huge_pages = EmulateArch::PAGE_ENTRIES;
+            let huge = allocator.allocate(FrameCount::new(huge_pages)).unwrap();
+            allocator.free(huge, FrameCount::new(huge_pages));
+            allocator.free_one(huge.add(3 * EmulateArch::PAGE_SIZE));
+        }
+    }
+}
@@ -0,0 +1,374 @@
+use core::{fmt, marker::PhantomData, mem, panic::Location};
+
+use crate::{
+    Arch, FrameAllocator, FrameCount, FrameUsage, MemoryArea, PhysicalAddress, VirtualAddress,
+};
+
+/// 页框的分配者
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameOwner {
+    Unknown,
+    /// 调用者提供的子系统编号
+    Subsystem(usize),
+    /// 调用者的代码位置
+    Caller(&'static Location<'static>),
+}
+
+impl fmt::Display for FrameOwner {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FrameOwner::Unknown => write!(f, "unknown"),
+            FrameOwner::Subsystem(id) => write!(f, "subsystem {}", id),
+            FrameOwner::Caller(location) => write!(f, "{}", location),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct FrameRecord {
+    base: PhysicalAddress,
+    count: usize,
+    owner: FrameOwner,
+}
+
+impl FrameRecord {
+    fn empty() -> Self {
+        Self {
+            base: PhysicalAddress::new(0),
+            count: 0,
+            owner: FrameOwner::Unknown,
+        }
+    }
+}
+
+/// 记录每次分配的分配者，用于查找页框泄漏
+pub struct TrackAllocator<A, F> {
+    inner: F,
+    table_virt: VirtualAddress,
+    capacity: usize,
+    owner: FrameOwner,
+    // 记录表满了以后没有记录下来的分配次数
+    untracked: usize,
+    // 从中间释放时记录表已满，丢掉的后半部分记录的页框数
+    dropped: usize,
+    phantom: PhantomData<A>,
+}
+
+impl<A: Arch, F: FrameAllocator> TrackAllocator<A, F> {
+    /// 从`inner`中分配`table_count`个连续页框存放记录表
+    pub unsafe fn new(mut inner: F, table_count: FrameCount) -> Option<Self> {
+        let table_phys = inner.allocate(table_count)?;
+        let table_virt = A::phys_to_virt(table_phys);
+        let capacity = (table_count.data() << A::PAGE_SHIFT) / mem::size_of::<FrameRecord>();
+        let allocator = Self {
+            inner,
+            table_virt,
+            capacity,
+            owner: FrameOwner::Unknown,
+            untracked: 0,
+            dropped: 0,
+            phantom: PhantomData,
+        };
+        for i in 0..capacity {
+            allocator.set_record(i, FrameRecord::empty());
+        }
+        Some(allocator)
+    }
+    pub fn inner(&self) -> &F {
+        &self.inner
+    }
+    pub fn inner_mut(&mut self) -> &mut F {
+        &mut self.inner
+    }
+    /// 设置之后分配的分配者，`FrameOwner::Unknown`表示使用调用位置，返回之前的分配者
+    pub fn set_owner(&mut self, owner: FrameOwner) -> FrameOwner {
+        mem::replace(&mut self.owner, owner)
+    }
+    pub fn untracked(&self) -> usize {
+        self.untracked
+    }
+    /// 因为记录表已满，不再出现在`outstanding`中的页框数
+    pub fn dropped(&self) -> FrameCount {
+        FrameCount::new(self.dropped)
+    }
+
+    unsafe fn record(&self, i: usize) -> FrameRecord {
+        A::read(self.table_virt.add(i * mem::size_of::<FrameRecord>()))
+    }
+    unsafe fn set_record(&self, i: usize, record: FrameRecord) {
+        A::write(
+            self.table_virt.add(i * mem::size_of::<FrameRecord>()),
+            record,
+        )
+    }
+    unsafe fn insert(&self, record: FrameRecord) -> bool {
+        for i in 0..self.capacity {
+            if self.record(i).count == 0 {
+                self.set_record(i, record);
+                return true;
+            }
+        }
+        false
+    }
+
+    /// 以指定的分配者分配物理内存
+    pub unsafe fn allocate_owned(
+        &mut self,
+        count: FrameCount,
+        owner: FrameOwner,
+    ) -> Option<PhysicalAddress> {
+        let base = self.inner.allocate(count)?;
+        let record = FrameRecord {
+            base,
+            count: count.data(),
+            owner,
+        };
+        if !self.insert(record) {
+            self.untracked += 1;
+        }
+        Some(base)
+    }
+
+    /// 按分配者汇总尚未释放的分配：分配者，分配次数，页框数量
+    pub unsafe fn outstanding<C: FnMut(FrameOwner, usize, FrameCount)>(&self, mut callback: C) {
+        for i in 0..self.capacity {
+            let record = self.record(i);
+            if record.count == 0 {
+                continue;
+            }
+            if (0..i).any(|j| {
+                let other = self.record(j);
+                other.count > 0 && other.owner == record.owner
+            }) {
+                continue;
+            }
+            let mut allocations = 0;
+            let mut frames = 0;
+            for j in i..self.capacity {
+                let other = self.record(j);
+                if other.count > 0 && other.owner == record.owner {
+                    allocations += 1;
+                    frames += other.count;
+                }
+            }
+            callback(record.owner, allocations, FrameCount::new(frames));
+        }
+    }
+
+    /// 输出尚未释放的分配
+    pub unsafe fn dump<W: fmt::Write>(&self, w: &mut W) -> fmt::Result {
+        let mut result = Ok(());
+        self.outstanding(|owner, allocations, frames| {
+            if result.is_ok() {
+                result = writeln!(
+                    w,
+                    "{}: {} allocations, {} frames",
+                    owner,
+                    allocations,
+                    frames.data()
+                );
+            }
+        });
+        result?;
+        if self.untracked > 0 {
+            writeln!(w, "untracked: {} allocations", self.untracked)?;
+        }
+        if self.dropped > 0 {
+            writeln!(w, "dropped: {} frames", self.dropped)?;
+        }
+        Ok(())
+    }
+}
+
+impl<A: Arch, F: FrameAllocator> FrameAllocator for TrackAllocator<A, F> {
+    #[track_caller]
+    unsafe fn allocate(&mut self, count: FrameCount) -> Option<PhysicalAddress> {
+        let owner = match self.owner {
+            FrameOwner::Unknown => FrameOwner::Caller(Location::caller()),
+            owner => owner,
+        };
+        self.allocate_owned(count, owner)
+    }
+
+    unsafe fn free(&mut self, address: PhysicalAddress, count: FrameCount) {
+        // 共享的页框还有其他引用，记录保留到最后一次释放
+        if self.inner.ref_count(address).unwrap_or(0) > 1 {
+            self.inner.free(address, count);
+            return;
+        }
+        // 释放的范围可能跨过多条记录（例如逐页分配、一次释放），每一条都要裁掉重叠的部分
+        let end = address.add(count.data() << A::PAGE_SHIFT);
+        for i in 0..self.capacity {
+            let mut record = self.record(i);
+            let record_end = record.base.add(record.count << A::PAGE_SHIFT);
+            if record.count == 0 || end <= record.base || address >= record_end {
+                continue;
+            }
+            if address > record.base && end < record_end {
+                // 从中间释放，拆成两条记录
+                let tail = FrameRecord {
+                    base: end,
+                    count: (record_end.data() - end.data()) >> A::PAGE_SHIFT,
+                    owner: record.owner,
+                };
+                record.count = (address.data() - record.base.data()) >> A::PAGE_SHIFT;
+                self.set_record(i, record);
+                if !self.insert(tail) {
+                    self.dropped += tail.count;
+                }
+                continue;
+            }
+            if address > record.base {
+                record.count = (address.data() - record.base.data()) >> A::PAGE_SHIFT;
+            } else if end < record_end {
+                record.count = (record_end.data() - end.data()) >> A::PAGE_SHIFT;
+                record.base = end;
+            } else {
+                record = FrameRecord::empty();
+            }
+            self.set_record(i, record);
+        }
+        self.inner.free(address, count);
+    }
+
+    unsafe fn share(&mut self, address: PhysicalAddress, count: FrameCount) -> Option<()> {
+        self.inner.share(address, count)
+    }
+
+    unsafe fn ref_count(&self, address: PhysicalAddress) -> Option<usize> {
+        self.inner.ref_count(address)
+    }
+
+    unsafe fn usage(&self) -> FrameUsage {
+        self.inner.usage()
+    }
+
+    unsafe fn area_usage(&self, i: usize) -> Option<(MemoryArea, FrameUsage)> {
+        self.inner.area_usage(i)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FrameOwner, TrackAllocator};
+    use crate::{
+        emulate_test, Arch, BuddyAllocator, EmulateArch, FrameAllocator, FrameCount, PageMapper,
+        VirtualAddress,
+    };
+
+    type Track = TrackAllocator<EmulateArch, BuddyAllocator<EmulateArch>>;
+
+    unsafe fn outstanding(allocator: &Track) -> Vec<(FrameOwner, usize, usize)> {
+        let mut owners = Vec::new();
+        allocator.outstanding(|owner, allocations, frames| {
+            owners.push((owner, allocations, frames.data()))
+        });
+        owners
+    }
+
+    #[test]
+    fn owners() {
+        unsafe {
+            let page = EmulateArch::PAGE_SIZE;
+            let mut allocator = Track::new(emulate_test(), FrameCount::new(1)).unwrap();
+            let frame = allocator.allocate_one().unwrap();
+            let owners = outstanding(&allocator);
+            assert_eq!(owners.len(), 1);
+            match owners[0].0 {
+                FrameOwner::Caller(location) => assert_eq!(location.file(), file!()),
+                owner => panic!("unexpected owner {}", owner),
+            }
+            allocator.free_one(frame);
+            assert!(outstanding(&allocator).is_empty());
+
+            // 页表和页框都记到调用映射的位置，而不是映射器内部
+            {
+                let mut mapper = PageMapper::<EmulateArch, _>::current(&mut allocator);
+                mapper
+                    .map(VirtualAddress::new(0x7000_0000_0000), 0)
+                    .unwrap()
+                    .flush();
+            }
+            let owners = outstanding(&allocator);
+            assert_eq!(owners.len(), 1);
+            match owners[0].0 {
+                FrameOwner::Caller(location) => assert_eq!(location.file(), file!()),
+                owner => panic!("unexpected owner {}", owner),
+            }
+            let mapped = owners[0].1;
+            assert!(mapped > 1);
+
+            // 指定子系统，从中间释放时拆成两条记录
+            allocator.set_owner(FrameOwner::Subsystem(7));
+            let frames = allocator.allocate(FrameCount::new(8)).unwrap();
+            allocator.set_owner(FrameOwner::Unknown);
+            allocator.free(frames.add(2 * page), FrameCount::new(3));
+            let owners = outstanding(&allocator);
+            assert_eq!(owners[1], (FrameOwner::Subsystem(7), 2, 5));
+
+            // 共享的页框释放一次之后记录还在
+            allocator.share(frames, FrameCount::new(2)).unwrap();
+            allocator.free(frames, FrameCount::new(2));
+            assert_eq!(outstanding(&allocator)[1], (FrameOwner::Subsystem(7), 2, 5));
+            allocator.free(frames, FrameCount::new(2));
+            assert_eq!(outstanding(&allocator)[1], (FrameOwner::Subsystem(7), 1, 3));
+            assert_eq!(allocator.dropped().data(), 0);
+        }
+    }
+
+    #[test]
+    fn free_across_records() {
+        unsafe {
+            let page = EmulateArch::PAGE_SIZE;
+            let mut allocator = Track::new(emulate_test(), FrameCount::new(1)).unwrap();
+            allocator.set_owner(FrameOwner::Subsystem(1));
+            let frames: Vec<_> = (0..6).map(|_| allocator.allocate_one().unwrap()).collect();
+            let base = frames[0];
+            for (i, &frame) in frames.iter().enumerate() {
+                assert_eq!(frame, base.add(i * page));
+            }
+
+            // 一次释放跨过的记录全部裁掉，两边各剩一页
+            allocator.free(base.add(page), FrameCount::new(4));
+            assert_eq!(
+                outstanding(&allocator),
+                vec![(FrameOwner::Subsystem(1), 2, 2)]
+            );
+            let other = allocator.allocate(FrameCount::new(4)).unwrap();
+            allocator.free(base, FrameCount::new(1));
+            allocator.free(base.add(5 * page), FrameCount::new(1));
+            allocator.free(other, FrameCount::new(4));
+            assert!(outstanding(&allocator).is_empty());
+        }
+    }
+
+    #[test]
+    fn table_full() {
+        unsafe {
+            let page = EmulateArch::PAGE_SIZE;
+            let mut allocator = Track::new(emulate_test(), FrameCount::new(1)).unwrap();
+            let mut frames = Vec::new();
+            for _ in 0..allocator.capacity {
+                frames.push(allocator.allocate(FrameCount::new(4)).unwrap());
+            }
+            let extra = allocator.allocate_one().unwrap();
+            assert_eq!(allocator.untracked(), 1);
+
+            // 拆开的后半部分放不下，计入丢掉的页框
+            allocator.free(frames[0].add(page), FrameCount::new(1));
+            assert_eq!(allocator.dropped().data(), 2);
+            let mut dump = String::new();
+            allocator.dump(&mut dump).unwrap();
+            assert!(dump.contains("untracked: 1 allocations"));
+            assert!(dump.contains("dropped: 2 frames"));
+
+            allocator.free_one(extra);
+            allocator.free_one(frames[0]);
+            allocator.free(frames[0].add(2 * page), FrameCount::new(2));
+            for frame in frames.iter().skip(1) {
+                allocator.free(*frame, FrameCount::new(4));
+            }
+            assert!(outstanding(&allocator).is_empty());
+        }
+    }
+}
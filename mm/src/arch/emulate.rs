@@ -1,11 +1,77 @@
-use crate::{Arch, MemoryArea, PageEntry, PhysicalAddress, VirtualAddress, X8664Arch, MEGA_BYTE};
-use core::{marker::PhantomData, mem, ptr};
+use crate::{
+    Arch, FaultInfo, MemoryArea, MemoryType, PageEntry, PhysicalAddress, VirtualAddress, X8664Arch,
+    X8664La57Arch, MEGA_BYTE,
+};
+use core::{
+    any::{Any, TypeId},
+    cell::RefCell,
+    fmt,
+    marker::PhantomData,
+    mem, ptr,
+};
 use std::collections::BTreeMap;
 
+pub use self::snapshot::*;
+mod snapshot;
+
+/// 模拟CPU的特权级
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Privilege {
+    Supervisor,
+    User,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Access {
+    Read,
+    Write,
+    Fetch,
+}
+
+/// 模拟的缺页异常，`error_code`的格式和x86相同
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EmulateFault {
+    pub address: VirtualAddress,
+    pub error_code: usize,
+}
+
+impl EmulateFault {
+    pub fn info(&self) -> FaultInfo {
+        X8664Arch::decode_fault(self.error_code, self.address)
+    }
+}
+
+impl fmt::Display for EmulateFault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "page fault at 0x{:X} error code 0x{:X}",
+            self.address.data(),
+            self.error_code
+        )
+    }
+}
+
+/// 模拟TLB中的一项，大页只占一项
+struct TlbEntry<A> {
+    // 结合了所有层级权限的叶子表项，地址按页的大小对齐
+    entry: PageEntry<A>,
+    // 叶子表项所在的物理地址，用于设置访问位和脏位
+    entry_phys: PhysicalAddress,
+    // 叶子表项所在的层级，0是普通页
+    level: usize,
+}
+
+/// 全局页的TLB项不属于任何ASID
+const GLOBAL_TAG: usize = usize::MAX;
+
 struct Machine<A> {
     memory: Box<[u8]>,
-    map: BTreeMap<VirtualAddress, PageEntry<A>>,
+    // 带标记的TLB，键是(ASID或`GLOBAL_TAG`, 虚拟地址)，没有命中时才查页表
+    map: BTreeMap<(usize, VirtualAddress), TlbEntry<A>>,
     table_addr: PhysicalAddress,
+    asid: usize,
+    privilege: Privilege,
     phantom: PhantomData<A>,
 }
 
@@ -15,6 +81,8 @@ impl<A: Arch> Machine<A> {
             memory: vec![0; memory_size].into_boxed_slice(),
             map: BTreeMap::new(),
             table_addr: PhysicalAddress::new(0),
+            asid: 0,
+            privilege: Privilege::Supervisor,
             phantom: PhantomData,
         }
     }
@@ -60,210 +128,824 @@ impl<A: Arch> Machine<A> {
         }
     }
 
-    fn translate(&self, virt: VirtualAddress) -> Option<(PhysicalAddress, usize)> {
-        let virt_data = virt.data();
-        let page = virt_data & A::PAGE_ADDRESS_MASK;
-        let offset = virt_data & A::PAGE_OFFSET_MASK;
-        let entry = self.map.get(&VirtualAddress::new(page))?;
-        Some((entry.address().add(offset), entry.flags()))
+    /// 第`level`级表项覆盖的大小
+    fn level_size(level: usize) -> usize {
+        1 << (level * A::PAGE_ENTRY_SHIFT + A::PAGE_SHIFT)
     }
 
-    fn read<T>(&self, virt: VirtualAddress) -> T {
-        let virt_data = virt.data();
-        let size = mem::size_of::<T>();
-        if (virt_data & A::PAGE_ADDRESS_MASK) != ((virt_data + (size - 1)) & A::PAGE_ADDRESS_MASK) {
-            panic!(
-                "read: 0x{:X} size 0x {:X} passes page boundary",
-                virt_data, size,
-            );
-        }
-        if let Some((phys, _flags)) = self.translate(virt) {
-            self.read_phys(phys)
-        } else {
-            panic!("read: 0x{:X} size 0x{:X} not present", virt_data, size);
+    /// TLB的键，去掉了符号扩展的高位
+    fn tlb_key(virt: VirtualAddress, level: usize) -> VirtualAddress {
+        VirtualAddress::new(virt.data() & (A::PAGE_ADDRESS_SIZE - Self::level_size(level)))
+    }
+
+    /// 查找覆盖这个地址的TLB项，从普通页到最大的大页依次查找，
+    /// 只匹配当前ASID和全局页
+    fn lookup(&self, virt: VirtualAddress) -> Option<(usize, VirtualAddress)> {
+        for level in 0..A::PAGE_LEVELS {
+            let key = Self::tlb_key(virt, level);
+            for &tag in [self.asid, GLOBAL_TAG].iter() {
+                match self.map.get(&(tag, key)) {
+                    Some(tlb) if tlb.level == level => return Some((tag, key)),
+                    _ => (),
+                }
+            }
         }
+        None
     }
 
-    fn write<T>(&mut self, virt: VirtualAddress, value: T) {
+    /// TLB没有命中时查页表并填入TLB
+    fn translate(&mut self, virt: VirtualAddress) -> Option<(PhysicalAddress, usize)> {
+        let key = match self.lookup(virt) {
+            Some(key) => key,
+            None => self.walk(virt)?,
+        };
+        let tlb = &self.map[&key];
+        let offset = virt.data() & (Self::level_size(tlb.level) - 1);
+        Some((tlb.entry.address().add(offset), tlb.entry.flags()))
+    }
+
+    /// 和硬件一样，TLB中的表项没有这些位时才写回页表
+    fn mark(&mut self, virt: VirtualAddress, flags: usize) {
+        let key = match self.lookup(virt) {
+            Some(key) => key,
+            None => return,
+        };
+        let (entry_phys, data) = match self.map.get_mut(&key) {
+            Some(tlb) if tlb.entry.flags() & flags != flags => {
+                tlb.entry = PageEntry::new(tlb.entry.data() | flags);
+                (tlb.entry_phys, flags)
+            }
+            _ => return,
+        };
+        let e = self.read_phys::<usize>(entry_phys);
+        self.write_phys::<usize>(entry_phys, e | data);
+    }
+
+    /// 按当前特权级检查访问权限，失败时返回x86格式的错误码，成功时设置访问位和脏位
+    fn access(
+        &mut self,
+        virt: VirtualAddress,
+        size: usize,
+        access: Access,
+    ) -> Result<PhysicalAddress, EmulateFault> {
         let virt_data = virt.data();
-        let size = mem::size_of::<T>();
         if (virt_data & A::PAGE_ADDRESS_MASK) != ((virt_data + (size - 1)) & A::PAGE_ADDRESS_MASK) {
             panic!(
-                "read: 0x{:X} size 0x {:X} passes page boundary",
-                virt_data, size,
+                "{:?}: 0x{:X} size 0x{:X} passes page boundary",
+                access, virt_data, size,
             );
         }
-        if let Some((phys, flags)) = self.translate(virt) {
-            if flags & A::ENTRY_FLAG_WRITABLE != 0 {
-                self.write_phys(phys, value);
-            } else {
-                panic!("write: 0x{:X} size 0x{:X} not writable", virt_data, size);
+        let mut error_code = 0;
+        if self.privilege == Privilege::User {
+            error_code |= X8664Arch::FAULT_USER;
+        }
+        match access {
+            Access::Read => (),
+            Access::Write => error_code |= X8664Arch::FAULT_WRITE,
+            Access::Fetch => error_code |= X8664Arch::FAULT_INSTRUCTION,
+        }
+        let fault = |error_code| EmulateFault {
+            address: virt,
+            error_code,
+        };
+        let (phys, flags) = self.translate(virt).ok_or_else(|| fault(error_code))?;
+        let error_code = error_code | X8664Arch::FAULT_PRESENT;
+        if self.privilege == Privilege::User && flags & A::ENTRY_FLAG_USER == 0 {
+            return Err(fault(error_code));
+        }
+        match access {
+            Access::Write if flags & A::ENTRY_FLAG_WRITABLE == 0 => Err(fault(error_code)),
+            Access::Fetch if flags & A::ENTRY_FLAG_NO_EXEC != 0 => Err(fault(error_code)),
+            Access::Write => {
+                self.mark(virt, A::ENTRY_FLAG_ACCESSED | A::ENTRY_FLAG_DIRTY);
+                Ok(phys)
+            }
+            _ => {
+                self.mark(virt, A::ENTRY_FLAG_ACCESSED);
+                Ok(phys)
             }
-        } else {
-            panic!("write: 0x{:X} size 0x{:X} not present", virt_data, size);
+        }
+    }
+
+    fn try_read<T>(&mut self, virt: VirtualAddress) -> Result<T, EmulateFault> {
+        let phys = self.access(virt, mem::size_of::<T>(), Access::Read)?;
+        Ok(self.read_phys(phys))
+    }
+
+    fn try_write<T>(&mut self, virt: VirtualAddress, value: T) -> Result<(), EmulateFault> {
+        let phys = self.access(virt, mem::size_of::<T>(), Access::Write)?;
+        self.write_phys(phys, value);
+        Ok(())
+    }
+
+    fn try_write_bytes(
+        &mut self,
+        virt: VirtualAddress,
+        value: u8,
+        count: usize,
+    ) -> Result<(), EmulateFault> {
+        let phys = self.access(virt, count, Access::Write)?;
+        self.write_phys_bytes(phys, value, count);
+        Ok(())
+    }
+
+    fn try_fetch(&mut self, virt: VirtualAddress) -> Result<u8, EmulateFault> {
+        let phys = self.access(virt, 1, Access::Fetch)?;
+        Ok(self.read_phys(phys))
+    }
+
+    fn read<T>(&mut self, virt: VirtualAddress) -> T {
+        match self.try_read(virt) {
+            Ok(value) => value,
+            Err(fault) => panic!("read: size 0x{:X} {}", mem::size_of::<T>(), fault),
+        }
+    }
+
+    fn write<T>(&mut self, virt: VirtualAddress, value: T) {
+        if let Err(fault) = self.try_write(virt, value) {
+            panic!("write: size 0x{:X} {}", mem::size_of::<T>(), fault);
         }
     }
 
     fn write_bytes(&mut self, virt: VirtualAddress, value: u8, count: usize) {
-        let virt_data = virt.data();
-        if (virt_data & A::PAGE_ADDRESS_MASK) != ((virt_data + (count - 1)) & A::PAGE_ADDRESS_MASK)
-        {
-            panic!(
-                "write_bytes: 0x{:X} size 0x {:X} passes page boundary",
-                virt_data, count,
-            )
+        if let Err(fault) = self.try_write_bytes(virt, value, count) {
+            panic!("write_bytes: count 0x{:X} {}", count, fault);
         }
-        if let Some((phys, flags)) = self.translate(virt) {
-            if flags & A::ENTRY_FLAG_WRITABLE != 0 {
-                self.write_phys_bytes(phys, value, count);
-            } else {
-                panic!(
-                    "write_bytes: 0x{:X} count 0x{:X} not writable",
-                    virt_data, count
-                );
+    }
+
+    /// 查页表，把叶子表项放入TLB，返回TLB的键
+    fn walk(&mut self, address: VirtualAddress) -> Option<(usize, VirtualAddress)> {
+        let mut table = self.table_addr;
+        let mut and_flags = A::ENTRY_FLAG_WRITABLE | A::ENTRY_FLAG_USER;
+        let mut no_exec = 0;
+        for level in (0..A::PAGE_LEVELS).rev() {
+            let shift = level * A::PAGE_ENTRY_SHIFT + A::PAGE_SHIFT;
+            let i = (address.data() >> shift) & A::PAGE_ENTRY_MASK;
+            let entry_phys = table.add(i * A::PAGE_ENTRY_SIZE);
+            let e = self.read_phys::<usize>(entry_phys);
+            if e & A::ENTRY_FLAG_PRESENT == 0 {
+                return None;
             }
-        } else {
-            panic!(
-                "write_bytes: 0x{:X} count 0x{:X} not present",
-                virt_data, count
-            );
+            if level == 0 || e & A::ENTRY_FLAG_HUGE != 0 {
+                let tag = if e & A::ENTRY_FLAG_GLOBAL != 0 {
+                    GLOBAL_TAG
+                } else {
+                    self.asid
+                };
+                let key = (tag, Self::tlb_key(address, level));
+                let tlb = Self::leaf_entry(e, entry_phys, level, and_flags, no_exec);
+                self.map.insert(key, tlb);
+                return Some(key);
+            }
+            and_flags &= e;
+            no_exec |= e & A::ENTRY_FLAG_NO_EXEC;
+            table = PhysicalAddress::new(e & A::ENTRY_ADDRESS_MASK);
         }
+        None
     }
-    fn invalid_data(&mut self, _address: VirtualAddress) {
-        unimplemented!("EmulateArch::invalid_data not implemented");
+
+    /// 刷新当前ASID和全局页中覆盖这个地址的TLB项
+    fn invalid_data(&mut self, address: VirtualAddress) {
+        while let Some(key) = self.lookup(address) {
+            self.map.remove(&key);
+        }
+    }
+
+    /// 结合中间表项的权限：可写和用户位需要每一级都有，任何一级不可执行即不可执行
+    fn leaf_entry(
+        e: usize,
+        entry_phys: PhysicalAddress,
+        level: usize,
+        and_flags: usize,
+        no_exec: usize,
+    ) -> TlbEntry<A> {
+        let inherited = A::ENTRY_FLAG_WRITABLE | A::ENTRY_FLAG_USER;
+        // 大页地址的低位可能是PAT位
+        let address = e & A::ENTRY_ADDRESS_MASK & !(Self::level_size(level) - 1);
+        let flags = e & A::ENTRY_FLAGS_MASK;
+        TlbEntry {
+            entry: PageEntry::new(address | (flags & !inherited) | (flags & and_flags) | no_exec),
+            entry_phys,
+            level,
+        }
     }
 
     fn get_table(&self) -> PhysicalAddress {
         self.table_addr
     }
+
+    /// 不使用ASID切换页表，和x86一样使用ASID 0并刷新
     fn set_table(&mut self, address: PhysicalAddress) {
         self.table_addr = address;
+        self.asid = 0;
         self.invalid_data_all();
     }
 
+    fn set_table_asid(&mut self, address: PhysicalAddress, asid: usize) {
+        self.table_addr = address;
+        self.asid = asid;
+    }
+
+    /// 和x86重新加载CR3一样只刷新当前ASID，全局页保留在TLB中
     fn invalid_data_all(&mut self) {
+        self.invalid_asid(self.asid);
+    }
+
+    fn invalid_asid(&mut self, asid: usize) {
+        let keys: Vec<_> = self
+            .map
+            .range((asid, VirtualAddress::new(0))..=(asid, VirtualAddress::new(usize::MAX)))
+            .map(|(key, _)| *key)
+            .collect();
+        for key in keys {
+            self.map.remove(&key);
+        }
+    }
+
+    fn invalid_all_asids(&mut self) {
         self.map.clear();
-        let a4 = self.table_addr.data();
-        for i4 in 0..A::PAGE_ENTRIES {
-            let e3 = self.read_phys::<usize>(PhysicalAddress::new(a4 + i4 * A::PAGE_ENTRY_SIZE));
-            let f3 = e3 & A::ENTRY_FLAGS_MASK;
-            if f3 & A::ENTRY_FLAG_PRESENT == 0 {
-                continue;
-            }
-            let a3 = e3 & A::ENTRY_ADDRESS_MASK;
-            for i3 in 0..A::PAGE_ENTRIES {
-                let e2 =
-                    self.read_phys::<usize>(PhysicalAddress::new(a3 + i3 * A::PAGE_ENTRY_SIZE));
-                let f2 = e2 & A::ENTRY_FLAGS_MASK;
-                if f2 & A::ENTRY_FLAG_PRESENT == 0 {
-                    continue;
-                }
-                let a2 = e2 & A::ENTRY_ADDRESS_MASK;
-                for i2 in 0..A::PAGE_ENTRIES {
-                    let e1 =
-                        self.read_phys::<usize>(PhysicalAddress::new(a2 + i2 * A::PAGE_ENTRY_SIZE));
-                    let f1 = e1 & A::ENTRY_FLAGS_MASK;
-                    if f1 & A::ENTRY_FLAG_PRESENT == 0 {
-                        continue;
-                    }
-                    let a1 = e1 & A::ENTRY_ADDRESS_MASK;
-                    for i1 in 0..A::PAGE_ENTRIES {
-                        let e = self
-                            .read_phys::<usize>(PhysicalAddress::new(a1 + i1 * A::PAGE_ENTRY_SIZE));
-                        let f = e & A::ENTRY_FLAGS_MASK;
-                        if f & A::ENTRY_FLAG_PRESENT == 0 {
-                            continue;
-                        }
-                        let page = (i4 << 39) | (i3 << 30) | (i2 << 21) | (i1 << 12);
-                        self.map
-                            .insert(VirtualAddress::new(page), PageEntry::new(e));
-                    }
-                }
-            }
+    }
+}
+
+/// 为引导页表保留的页数，放在物理内存开头，足够5级分页使用
+const BOOT_TABLE_PAGES: usize = 8;
+
+/// 模拟机器的内存布局
+#[derive(Clone, Debug)]
+pub struct EmulateConfig {
+    /// 物理内存大小
+    pub memory_size: usize,
+    /// 可用的内存块，之间可以有空洞，不能和引导页表重叠
+    pub areas: Vec<MemoryArea>,
+    /// 预留的内存块，`emulate_test_bump`会交给bump分配器预留
+    pub reserved: Vec<MemoryArea>,
+}
+
+impl EmulateConfig {
+    /// 除引导页表以外都可用的内存
+    pub fn new(memory_size: usize) -> Self {
+        let boot_size = BOOT_TABLE_PAGES * EmulateArch::PAGE_SIZE;
+        Self {
+            memory_size,
+            areas: vec![MemoryArea {
+                base: PhysicalAddress::new(boot_size),
+                size: memory_size - boot_size,
+            }],
+            reserved: Vec::new(),
         }
     }
 }
 
-const MEMORY_SIZE: usize = 64 * MEGA_BYTE;
-static MEMORY_AREAS: [MemoryArea; 2] = [
-    MemoryArea {
-        base: PhysicalAddress::new(EmulateArch::PAGE_SIZE * 4),
-        size: MEMORY_SIZE / 2 - EmulateArch::PAGE_SIZE * 4,
-    },
-    MemoryArea {
-        base: PhysicalAddress::new(MEMORY_SIZE / 2),
-        size: MEMORY_SIZE / 2,
-    },
-];
-static mut MACHINE: Option<Machine<EmulateArch>> = None;
-
-#[derive(Clone, Copy)]
-pub struct EmulateArch;
-
-impl Arch for EmulateArch {
-    const PAGE_SHIFT: usize = X8664Arch::PAGE_SHIFT;
-    const PAGE_ENTRY_SHIFT: usize = X8664Arch::PAGE_ENTRY_SHIFT;
-    const PAGE_LEVELS: usize = X8664Arch::PAGE_LEVELS;
-    const ENTRY_ADDRESS_SHIFT: usize = X8664Arch::PAGE_ADDRESS_SHIFT;
-    const ENTRY_FLAG_PRESENT: usize = X8664Arch::ENTRY_FLAG_PRESENT;
-    const ENTRY_FLAG_WRITABLE: usize = X8664Arch::ENTRY_FLAG_WRITABLE;
-    const ENTRY_FLAG_USER: usize = X8664Arch::ENTRY_FLAG_USER;
-    const ENTRY_FLAG_HUGE: usize = X8664Arch::ENTRY_FLAG_HUGE;
-    const ENTRY_FLAG_GLOBAL: usize = X8664Arch::ENTRY_FLAG_GLOBAL;
-    const ENTRY_FLAG_NO_EXEC: usize = X8664Arch::ENTRY_FLAG_NO_EXEC;
-    const PHYS_OFFSET: usize = X8664Arch::PHYS_OFFSET;
+impl Default for EmulateConfig {
+    /// 64MiB内存，分成两块
+    fn default() -> Self {
+        let memory_size = 64 * MEGA_BYTE;
+        let boot_size = BOOT_TABLE_PAGES * EmulateArch::PAGE_SIZE;
+        Self {
+            memory_size,
+            areas: vec![
+                MemoryArea {
+                    base: PhysicalAddress::new(boot_size),
+                    size: memory_size / 2 - boot_size,
+                },
+                MemoryArea {
+                    base: PhysicalAddress::new(memory_size / 2),
+                    size: memory_size / 2,
+                },
+            ],
+            reserved: Vec::new(),
+        }
+    }
+}
 
-    unsafe fn init() -> &'static [MemoryArea] {
-        let mut machine = Machine::new(MEMORY_SIZE);
-        let pm14 = 0;
-        let pdp = pm14 + Self::PAGE_SIZE;
-        let flags = Self::ENTRY_FLAG_WRITABLE | Self::ENTRY_FLAG_PRESENT;
-        machine.write_phys::<usize>(
-            PhysicalAddress::new(pm14 + 256 * Self::PAGE_ENTRY_SIZE),
-            pdp | flags,
-        );
-        let pd = pdp + Self::PAGE_SIZE;
-        machine.write_phys::<usize>(PhysicalAddress::new(pdp), pd | flags);
-        let pt = pd + Self::PAGE_SIZE;
-        machine.write_phys::<usize>(PhysicalAddress::new(pd), pt | flags);
+thread_local! {
+    // 每个线程每种架构有自己的模拟机器，测试可以并行执行
+    static MACHINES: RefCell<BTreeMap<TypeId, Box<dyn Any>>> = RefCell::new(BTreeMap::new());
+    // `init_with`泄漏的内存块，相同的内存块只泄漏一次
+    static LEAKED_AREAS: RefCell<Vec<&'static [MemoryArea]>> = RefCell::new(Vec::new());
+}
 
-        for i in 0..Self::PAGE_ENTRIES {
-            let page = i * Self::PAGE_SIZE;
-            machine.write_phys::<usize>(
-                PhysicalAddress::new(pt + i * Self::PAGE_ENTRY_MASK),
-                page | flags,
-            )
+fn with_machine<X: Arch + 'static, T, F: FnOnce(&mut Machine<Emulate<X>>) -> T>(f: F) -> T {
+    MACHINES.with(|machines| {
+        let mut machines = machines.borrow_mut();
+        let machine = machines
+            .get_mut(&TypeId::of::<X>())
+            .and_then(|machine| machine.downcast_mut())
+            .expect("EmulateArch: machine is not initialized on this thread");
+        f(machine)
+    })
+}
+
+/// 分配器会一直持有内存块，替换机器之后也不能失效，所以故意泄漏。
+/// 模拟机器只用于测试，不同的内存块配置很少
+fn leak_areas(areas: &[MemoryArea]) -> &'static [MemoryArea] {
+    LEAKED_AREAS.with(|leaked| {
+        let mut leaked = leaked.borrow_mut();
+        let same = |other: &&&'static [MemoryArea]| {
+            other.len() == areas.len()
+                && other
+                    .iter()
+                    .zip(areas)
+                    .all(|(a, b)| a.base == b.base && a.size == b.size)
+        };
+        if let Some(&found) = leaked.iter().find(same) {
+            return found;
         }
-        MACHINE = Some(machine);
-        EmulateArch::set_table(PhysicalAddress::new(pm14));
-        &MEMORY_AREAS
+        let new: &'static [MemoryArea] = Box::leak(areas.to_vec().into_boxed_slice());
+        leaked.push(new);
+        new
+    })
+}
+
+fn set_machine<X: Arch + 'static>(machine: Machine<Emulate<X>>) {
+    MACHINES.with(|machines| {
+        machines
+            .borrow_mut()
+            .insert(TypeId::of::<X>(), Box::new(machine))
+    });
+}
+
+/// 在普通内存中模拟架构`X`的分页
+#[derive(Clone, Copy, Debug)]
+pub struct Emulate<X>(PhantomData<X>);
+
+/// 模拟4级分页的x86_64
+pub type EmulateArch = Emulate<X8664Arch>;
+/// 模拟5级分页的x86_64
+pub type EmulateLa57Arch = Emulate<X8664La57Arch>;
+
+impl<X: Arch + 'static> Arch for Emulate<X> {
+    const PAGE_SHIFT: usize = X::PAGE_SHIFT;
+    const PAGE_ENTRY_SHIFT: usize = X::PAGE_ENTRY_SHIFT;
+    const PAGE_LEVELS: usize = X::PAGE_LEVELS;
+    const ENTRY_ADDRESS_SHIFT: usize = X::ENTRY_ADDRESS_SHIFT;
+    const ENTRY_FLAG_PRESENT: usize = X::ENTRY_FLAG_PRESENT;
+    const ENTRY_FLAG_WRITABLE: usize = X::ENTRY_FLAG_WRITABLE;
+    const ENTRY_FLAG_USER: usize = X::ENTRY_FLAG_USER;
+    const ENTRY_FLAG_HUGE: usize = X::ENTRY_FLAG_HUGE;
+    const ENTRY_FLAG_GLOBAL: usize = X::ENTRY_FLAG_GLOBAL;
+    const ENTRY_FLAG_NO_EXEC: usize = X::ENTRY_FLAG_NO_EXEC;
+    const ENTRY_FLAG_ACCESSED: usize = X::ENTRY_FLAG_ACCESSED;
+    const ENTRY_FLAG_DIRTY: usize = X::ENTRY_FLAG_DIRTY;
+    const ENTRY_FLAG_SWAP: usize = X::ENTRY_FLAG_SWAP;
+    const ENTRY_FLAG_COW: usize = X::ENTRY_FLAG_COW;
+    const PHYS_OFFSET: usize = X::PHYS_OFFSET;
+    const ASID_COUNT: usize = X::ASID_COUNT;
+
+    unsafe fn init() -> &'static [MemoryArea] {
+        Self::init_with(&EmulateConfig::default())
+    }
+
+    fn memory_type_flags(memory_type: MemoryType, huge: bool) -> usize {
+        X::memory_type_flags(memory_type, huge)
+    }
+
+    fn memory_type(flags: usize, huge: bool) -> MemoryType {
+        X::memory_type(flags, huge)
+    }
+
+    fn decode_fault(error_code: usize, address: VirtualAddress) -> FaultInfo {
+        X::decode_fault(error_code, address)
     }
 
     unsafe fn read<T>(address: VirtualAddress) -> T {
-        MACHINE.as_ref().unwrap().read(address)
+        with_machine::<X, _, _>(|machine| machine.read(address))
     }
 
     unsafe fn write<T>(address: VirtualAddress, value: T) {
-        MACHINE.as_mut().unwrap().write(address, value);
+        with_machine::<X, _, _>(|machine| machine.write(address, value));
     }
 
     unsafe fn write_bytes(address: VirtualAddress, value: u8, count: usize) {
-        MACHINE.as_mut().unwrap().write_bytes(address, value, count);
+        with_machine::<X, _, _>(|machine| machine.write_bytes(address, value, count));
     }
 
     unsafe fn invalid_data_all() {
-        MACHINE.as_mut().unwrap().invalid_data_all();
+        with_machine::<X, _, _>(|machine| machine.invalid_data_all());
     }
 
     unsafe fn invalid_data(address: VirtualAddress) {
-        MACHINE.as_mut().unwrap().invalid_data(address);
+        with_machine::<X, _, _>(|machine| machine.invalid_data(address));
     }
 
     unsafe fn table() -> PhysicalAddress {
-        MACHINE.as_mut().unwrap().get_table()
+        with_machine::<X, _, _>(|machine| machine.get_table())
     }
 
     unsafe fn set_table(address: PhysicalAddress) {
-        MACHINE.as_mut().unwrap().set_table(address);
+        with_machine::<X, _, _>(|machine| machine.set_table(address));
+    }
+
+    unsafe fn set_table_asid(address: PhysicalAddress, asid: usize) {
+        with_machine::<X, _, _>(|machine| machine.set_table_asid(address, asid));
+    }
+
+    unsafe fn invalid_asid(asid: usize) {
+        with_machine::<X, _, _>(|machine| machine.invalid_asid(asid));
+    }
+
+    unsafe fn invalid_all_asids() {
+        with_machine::<X, _, _>(|machine| machine.invalid_all_asids());
+    }
+}
+
+impl<X: Arch + 'static> Emulate<X> {
+    /// 按配置在当前线程创建模拟机器，替换之前的机器。
+    /// 引导页表只直接映射了前2MiB，之后建立页表用的页框需要在这个范围内。
+    /// 返回的内存块是泄漏的，替换机器之后仍然有效
+    pub unsafe fn init_with(config: &EmulateConfig) -> &'static [MemoryArea] {
+        let boot_size = BOOT_TABLE_PAGES * Self::PAGE_SIZE;
+        for area in config.areas.iter().chain(config.reserved.iter()) {
+            let end = area.base.checked_add(area.size);
+            if area.base.data() < boot_size
+                || end.map_or(true, |end| end.data() > config.memory_size)
+            {
+                panic!(
+                    "EmulateArch: area 0x{:X} size 0x{:X} outside of usable memory",
+                    area.base.data(),
+                    area.size
+                );
+            }
+        }
+        let mut machine = Machine::<Self>::new(config.memory_size);
+        let areas = leak_areas(&config.areas);
+        // 每一级一个页表，从物理地址0开始依次存放，最后一级映射前2MiB
+        let flags = Self::ENTRY_FLAG_WRITABLE | Self::ENTRY_FLAG_PRESENT;
+        let mut table = 0;
+        for level in (1..Self::PAGE_LEVELS).rev() {
+            let shift = level * Self::PAGE_ENTRY_SHIFT + Self::PAGE_SHIFT;
+            let i = (Self::PHYS_OFFSET >> shift) & Self::PAGE_ENTRY_MASK;
+            let next = table + Self::PAGE_SIZE;
+            machine.write_phys::<usize>(
+                PhysicalAddress::new(table + i * Self::PAGE_ENTRY_SIZE),
+                next | flags,
+            );
+            table = next;
+        }
+        for i in 0..Self::PAGE_ENTRIES {
+            let page = i * Self::PAGE_SIZE;
+            machine.write_phys::<usize>(
+                PhysicalAddress::new(table + i * Self::PAGE_ENTRY_SIZE),
+                page | flags,
+            )
+        }
+        set_machine(machine);
+        Self::set_table(PhysicalAddress::new(0));
+        areas
+    }
+
+    /// 之后的访问按这个特权级检查权限
+    pub unsafe fn set_privilege(privilege: Privilege) {
+        with_machine::<X, _, _>(|machine| machine.privilege = privilege);
+    }
+
+    pub unsafe fn privilege() -> Privilege {
+        with_machine::<X, _, _>(|machine| machine.privilege)
+    }
+
+    /// 当前的ASID
+    pub unsafe fn asid() -> usize {
+        with_machine::<X, _, _>(|machine| machine.asid)
+    }
+
+    /// 和`read`相同，但是缺页时返回错误而不是panic
+    pub unsafe fn try_read<T>(address: VirtualAddress) -> Result<T, EmulateFault> {
+        with_machine::<X, _, _>(|machine| machine.try_read(address))
+    }
+
+    pub unsafe fn try_write<T>(address: VirtualAddress, value: T) -> Result<(), EmulateFault> {
+        with_machine::<X, _, _>(|machine| machine.try_write(address, value))
+    }
+
+    /// 模拟取指令，检查`ENTRY_FLAG_NO_EXEC`
+    pub unsafe fn try_fetch(address: VirtualAddress) -> Result<u8, EmulateFault> {
+        with_machine::<X, _, _>(|machine| machine.try_fetch(address))
+    }
+}
+
+/// 初始化默认配置的模拟机器，建立不可执行的直接映射，返回之后的bump分配器
+#[cfg(test)]
+pub(crate) unsafe fn emulate_test_bump() -> crate::BumpAllocator<EmulateArch> {
+    emulate_test_bump_with(&EmulateConfig::default())
+}
+
+/// 按配置初始化当前线程的模拟机器，预留`config.reserved`
+#[cfg(test)]
+pub(crate) unsafe fn emulate_test_bump_with(
+    config: &EmulateConfig,
+) -> crate::BumpAllocator<EmulateArch> {
+    emulate_test_bump_arch::<X8664Arch>(config)
+}
+
+#[cfg(test)]
+unsafe fn emulate_test_bump_arch<X: Arch + 'static>(
+    config: &EmulateConfig,
+) -> crate::BumpAllocator<Emulate<X>> {
+    let areas = Emulate::<X>::init_with(config);
+    let mut bump_allocator = crate::BumpAllocator::<Emulate<X>>::new(areas, 0);
+    for area in config.reserved.iter() {
+        bump_allocator
+            .reserve(*area)
+            .expect("failed to reserve memory area");
+    }
+    {
+        let mut mapper = crate::PageMapper::<Emulate<X>, _>::create(&mut bump_allocator)
+            .expect("failed to create mapper");
+        for area in areas.iter() {
+            for i in 0..area.size / X::PAGE_SIZE {
+                let phys = area.base.add(i * X::PAGE_SIZE);
+                let virt = Emulate::<X>::phys_to_virt(phys);
+                mapper
+                    .map_phys(virt, phys, X::ENTRY_FLAG_WRITABLE | X::ENTRY_FLAG_NO_EXEC)
+                    .expect("failed to map page to frame")
+                    .ignore();
+            }
+        }
+        mapper.make_current();
+    }
+    bump_allocator
+}
+
+/// 初始化默认配置的模拟机器，返回伙伴分配器
+#[cfg(test)]
+pub(crate) unsafe fn emulate_test() -> crate::BuddyAllocator<EmulateArch> {
+    emulate_test_with(&EmulateConfig::default())
+}
+
+#[cfg(test)]
+pub(crate) unsafe fn emulate_test_with(
+    config: &EmulateConfig,
+) -> crate::BuddyAllocator<EmulateArch> {
+    crate::BuddyAllocator::new(emulate_test_bump_with(config))
+        .expect("failed to create buddy allocator")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        emulate_test_bump_arch, EmulateArch, EmulateConfig, EmulateFault, EmulateLa57Arch,
+        Privilege,
+    };
+    use crate::{
+        emulate_test, emulate_test_with, Arch, BuddyAllocator, FrameAllocator, MemoryArea,
+        PageEntry, PageFlushAll, PageMapper, PhysicalAddress, VirtualAddress, X8664Arch,
+        X8664La57Arch, MEGA_BYTE,
+    };
+
+    fn fault<T>(address: VirtualAddress, error_code: usize) -> Result<T, EmulateFault> {
+        Err(EmulateFault {
+            address,
+            error_code,
+        })
+    }
+
+    #[test]
+    fn privilege() {
+        unsafe {
+            let mut allocator = emulate_test();
+            let mut mapper = PageMapper::<EmulateArch, _>::current(&mut allocator);
+            let user = VirtualAddress::new(0x1000);
+            let code = VirtualAddress::new(0x2000);
+            let kernel = VirtualAddress::new(0xFFFF_C000_0000_0000);
+            let flush_all = PageFlushAll::<EmulateArch>::new();
+            flush_all.consume(
+                mapper
+                    .map(
+                        user,
+                        EmulateArch::ENTRY_FLAG_USER
+                            | EmulateArch::ENTRY_FLAG_WRITABLE
+                            | EmulateArch::ENTRY_FLAG_NO_EXEC,
+                    )
+                    .unwrap(),
+            );
+            flush_all.consume(mapper.map(code, EmulateArch::ENTRY_FLAG_USER).unwrap());
+            flush_all.consume(
+                mapper
+                    .map(kernel, EmulateArch::ENTRY_FLAG_WRITABLE)
+                    .unwrap(),
+            );
+            flush_all.flush();
+            let present = X8664Arch::FAULT_PRESENT;
+            let write = X8664Arch::FAULT_WRITE;
+            let fetch = X8664Arch::FAULT_INSTRUCTION;
+            let user_mode = X8664Arch::FAULT_USER;
+
+            // 内核可以访问用户页，但不能执行不可执行的页
+            assert_eq!(EmulateArch::try_write(user, 1usize), Ok(()));
+            assert_eq!(EmulateArch::try_fetch(user), fault(user, present | fetch));
+            assert_eq!(EmulateArch::try_fetch(kernel), Ok(0));
+
+            EmulateArch::set_privilege(Privilege::User);
+            assert_eq!(EmulateArch::try_read::<usize>(user), Ok(1));
+            assert_eq!(EmulateArch::try_fetch(code), Ok(0));
+            assert_eq!(
+                EmulateArch::try_write(code, 1usize),
+                fault(code, present | write | user_mode)
+            );
+            assert_eq!(
+                EmulateArch::try_read::<usize>(kernel),
+                fault(kernel, present | user_mode)
+            );
+            let unmapped = VirtualAddress::new(0x3000_0000);
+            assert_eq!(
+                EmulateArch::try_write(unmapped, 1usize),
+                fault(unmapped, write | user_mode)
+            );
+
+            // 中间表项不可写时，叶子可写也不能写
+            EmulateArch::set_privilege(Privilege::Supervisor);
+            let mut table = mapper.table();
+            let entry = table.entry(0).unwrap();
+            table.set_entry(
+                0,
+                crate::PageEntry::new(entry.data() & !EmulateArch::ENTRY_FLAG_WRITABLE),
+            );
+            EmulateArch::invalid_data(user);
+            assert_eq!(
+                EmulateArch::try_write(user, 1usize),
+                fault(user, present | write)
+            );
+            table.set_entry(0, entry);
+            EmulateArch::invalid_data_all();
+        }
+    }
+
+    #[test]
+    fn layouts() {
+        let page = EmulateArch::PAGE_SIZE;
+        let area = |base: usize, size: usize| MemoryArea {
+            base: PhysicalAddress::new(base),
+            size,
+        };
+        // 中间有空洞，第一块开头预留4页
+        let holey = EmulateConfig {
+            memory_size: 16 * MEGA_BYTE,
+            areas: vec![
+                area(8 * page, MEGA_BYTE),
+                area(8 * MEGA_BYTE, 4 * MEGA_BYTE),
+            ],
+            reserved: vec![area(8 * page, 4 * page)],
+        };
+        let configs = vec![
+            holey,
+            EmulateConfig::new(4 * MEGA_BYTE),
+            EmulateConfig::default(),
+        ];
+        let threads: Vec<_> = configs
+            .into_iter()
+            .map(|config| {
+                std::thread::spawn(move || unsafe {
+                    let mut allocator = emulate_test_with(&config);
+                    let usage = allocator.usage();
+                    let pages: usize = config.areas.iter().map(|area| area.size / page).sum();
+                    let reserved: usize = config.reserved.iter().map(|area| area.size / page).sum();
+                    // 除去预留的页和直接映射用的页表
+                    let total = usage.tatal().data();
+                    assert!(total < pages - reserved && total + 64 > pages - reserved);
+                    for _ in 0..64 {
+                        let frame = allocator.allocate_one().unwrap();
+                        assert!(config.areas.iter().any(|area| {
+                            frame >= area.base && frame < area.base.add(area.size)
+                        }));
+                        assert!(!config.reserved.iter().any(|area| {
+                            frame >= area.base && frame < area.base.add(area.size)
+                        }));
+                        let virt = EmulateArch::phys_to_virt(frame);
+                        EmulateArch::write::<usize>(virt, frame.data());
+                        assert_eq!(EmulateArch::read::<usize>(virt), frame.data());
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn huge_pages() {
+        unsafe {
+            let mut allocator = emulate_test_with(&EmulateConfig::new(4 * MEGA_BYTE));
+            let frame = allocator.allocate_one().unwrap();
+            assert!(frame.data() < 2 * MEGA_BYTE);
+            let mut mapper = PageMapper::<EmulateArch, _>::current(&mut allocator);
+            // 先建立0x4000_0000所在的页目录，再把下一个2MiB设置成映射物理地址0的大页
+            let flags = EmulateArch::ENTRY_FLAG_WRITABLE | EmulateArch::ENTRY_FLAG_NO_EXEC;
+            let small = VirtualAddress::new(0x4000_0000);
+            mapper.map(small, flags).unwrap().flush();
+            let mut pd = mapper.table().next(0).unwrap().next(1).unwrap();
+            let huge_flags = flags | EmulateArch::ENTRY_FLAG_PRESENT | EmulateArch::ENTRY_FLAG_HUGE;
+            pd.set_entry(1, PageEntry::new(huge_flags));
+            EmulateArch::invalid_data_all();
+
+            let huge = VirtualAddress::new(0x4020_0000);
+            EmulateArch::write::<usize>(huge.add(frame.data()), 0x1234);
+            let frame_virt = EmulateArch::phys_to_virt(frame);
+            assert_eq!(EmulateArch::read::<usize>(frame_virt), 0x1234);
+            assert_eq!(
+                mapper.translate(huge.add(frame.data())),
+                Some((
+                    frame,
+                    huge_flags | EmulateArch::ENTRY_FLAG_ACCESSED | EmulateArch::ENTRY_FLAG_DIRTY
+                ))
+            );
+            assert!(pd.entry(1).unwrap().dirty());
+            assert_eq!(EmulateArch::read::<usize>(small), 0);
+
+            // 刷新大页中的任意一页都会刷新整个大页
+            pd.set_entry(
+                1,
+                PageEntry::new(huge_flags & !EmulateArch::ENTRY_FLAG_WRITABLE),
+            );
+            EmulateArch::invalid_data(huge.add(0x5000));
+            assert_eq!(
+                EmulateArch::try_write(huge.add(frame.data()), 1usize),
+                Err(EmulateFault {
+                    address: huge.add(frame.data()),
+                    error_code: X8664Arch::FAULT_PRESENT | X8664Arch::FAULT_WRITE,
+                })
+            );
+            pd.set_entry(1, PageEntry::new(0));
+            EmulateArch::invalid_data(huge);
+            assert!(EmulateArch::try_read::<usize>(huge).is_err());
+        }
+    }
+
+    #[test]
+    fn global_tlb() {
+        unsafe {
+            let mut allocator = emulate_test();
+            let mut mapper = PageMapper::<EmulateArch, _>::current(&mut allocator);
+            let flags = EmulateArch::ENTRY_FLAG_WRITABLE | EmulateArch::ENTRY_FLAG_NO_EXEC;
+            let global = VirtualAddress::new(0xFFFF_C000_0000_0000);
+            let local = VirtualAddress::new(0x1000);
+            mapper
+                .map(global, flags | EmulateArch::ENTRY_FLAG_GLOBAL)
+                .unwrap()
+                .flush();
+            mapper.map(local, flags).unwrap().flush();
+            EmulateArch::write::<usize>(global, 1);
+            EmulateArch::write::<usize>(local, 1);
+
+            // 修改页表之后不刷新，TLB中仍然是映射值为1的页框
+            let mut remap = |virt: VirtualAddress, extra: usize, value: usize| {
+                let frame = mapper.allocator_mut().allocate_one().unwrap();
+                EmulateArch::write::<usize>(EmulateArch::phys_to_virt(frame), value);
+                mapper.unmap_phys(virt).unwrap().1.ignore();
+                mapper
+                    .map_phys(virt, frame, flags | extra)
+                    .unwrap()
+                    .ignore();
+            };
+            remap(global, EmulateArch::ENTRY_FLAG_GLOBAL, 2);
+            remap(local, 0, 2);
+
+            // 重新加载页表只刷新当前ASID，全局页保留
+            EmulateArch::set_table(EmulateArch::table());
+            assert_eq!(EmulateArch::read::<usize>(local), 2);
+            assert_eq!(EmulateArch::read::<usize>(global), 1);
+            EmulateArch::invalid_data_all();
+            assert_eq!(EmulateArch::read::<usize>(global), 1);
+            EmulateArch::invalid_data(global);
+            assert_eq!(EmulateArch::read::<usize>(global), 2);
+
+            remap(global, EmulateArch::ENTRY_FLAG_GLOBAL, 3);
+            EmulateArch::invalid_asid(0);
+            assert_eq!(EmulateArch::read::<usize>(global), 2);
+            EmulateArch::invalid_all_asids();
+            assert_eq!(EmulateArch::read::<usize>(global), 3);
+        }
+    }
+
+    #[test]
+    fn la57() {
+        unsafe {
+            let config = EmulateConfig::new(4 * MEGA_BYTE);
+            let bump = emulate_test_bump_arch::<X8664La57Arch>(&config);
+            let mut allocator = BuddyAllocator::new(bump).unwrap();
+            let mut mapper = PageMapper::<EmulateLa57Arch, _>::current(&mut allocator);
+            assert_eq!(mapper.table().level(), 4);
+            // 超出48位地址空间的用户地址
+            let high = VirtualAddress::new(0x0080_0000_0000_1000);
+            assert!(!EmulateArch::is_canonical(high));
+            let flags = EmulateLa57Arch::ENTRY_FLAG_USER
+                | EmulateLa57Arch::ENTRY_FLAG_WRITABLE
+                | EmulateLa57Arch::ENTRY_FLAG_NO_EXEC;
+            mapper.map(high, flags).unwrap().flush();
+            EmulateLa57Arch::write::<usize>(high.add(8), 57);
+            let (phys, _) = mapper.translate(high).unwrap();
+            let phys_virt = EmulateLa57Arch::phys_to_virt(phys.add(8));
+            assert_eq!(phys_virt.data() >> 48, 0xFF00);
+            assert_eq!(EmulateLa57Arch::read::<usize>(phys_virt), 57);
+            assert!(
+                EmulateLa57Arch::try_read::<usize>(high.add(EmulateLa57Arch::PAGE_SIZE)).is_err()
+            );
+
+            // 同一个线程中两种架构的模拟机器互不影响
+            let mut allocator = emulate_test_with(&config);
+            let mapper4 = PageMapper::<EmulateArch, _>::current(&mut allocator);
+            assert!(mapper4.translate(high).is_none());
+            assert_eq!(EmulateLa57Arch::read::<usize>(high.add(8)), 57);
+        }
     }
 }
@@ -0,0 +1,407 @@
+use core::{cmp::Reverse, marker::PhantomData};
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+use super::{set_machine, with_machine, EmulateArch, Machine, Privilege, TlbEntry};
+use crate::{Arch, PageEntry, PhysicalAddress, VirtualAddress, X8664Arch};
+
+/// 快照文件开头的标识
+const SNAPSHOT_MAGIC: [u8; 8] = *b"MMSNAP01";
+/// 读取快照时内存大小的上限，损坏的文件不会导致分配过多内存
+const SNAPSHOT_MEMORY_MAX: usize = 1 << 32;
+/// 读取快照时TLB项数的上限
+const SNAPSHOT_TLB_MAX: usize = 1 << 20;
+
+/// 模拟机器的完整状态：内存、当前页表和ASID、TLB和特权级
+#[derive(Clone)]
+pub struct EmulateSnapshot {
+    memory: Box<[u8]>,
+    table_addr: PhysicalAddress,
+    asid: usize,
+    privilege: Privilege,
+    tlb: Vec<SavedTlb>,
+}
+
+/// 保存的TLB项
+#[derive(Clone, Copy)]
+struct SavedTlb {
+    tag: usize,
+    virt: VirtualAddress,
+    entry: usize,
+    entry_phys: PhysicalAddress,
+    level: usize,
+}
+
+/// 两个快照之间变化的页表项，不存在的表项为0
+#[derive(Debug, Clone, Copy)]
+pub struct EntryChange {
+    /// 表项覆盖的起始虚拟地址
+    pub virt: VirtualAddress,
+    /// 表项所在的层级，0是最后一级
+    pub level: usize,
+    pub old: PageEntry<EmulateArch>,
+    pub new: PageEntry<EmulateArch>,
+}
+
+/// 两个快照之间的差异
+#[derive(Debug, Clone, Default)]
+pub struct SnapshotDiff {
+    /// 内容变化的物理页
+    pub pages: Vec<PhysicalAddress>,
+    /// 从各自的页表出发，变化的页表项
+    pub entries: Vec<EntryChange>,
+}
+
+impl SnapshotDiff {
+    pub fn is_empty(&self) -> bool {
+        self.pages.is_empty() && self.entries.is_empty()
+    }
+}
+
+impl EmulateSnapshot {
+    pub fn memory_size(&self) -> usize {
+        self.memory.len()
+    }
+    pub fn table(&self) -> PhysicalAddress {
+        self.table_addr
+    }
+    pub fn asid(&self) -> usize {
+        self.asid
+    }
+    pub fn privilege(&self) -> Privilege {
+        self.privilege
+    }
+
+    fn read_entry(&self, phys: PhysicalAddress) -> Option<usize> {
+        let start = phys.data();
+        let bytes = self
+            .memory
+            .get(start..start.checked_add(EmulateArch::PAGE_ENTRY_SIZE)?)?;
+        let mut data = [0; 8];
+        data.copy_from_slice(bytes);
+        Some(u64::from_le_bytes(data) as usize)
+    }
+
+    /// 页表中所有非0的表项，键是(覆盖的起始虚拟地址, 层级)
+    fn entries(&self) -> BTreeMap<(VirtualAddress, usize), usize> {
+        let mut entries = BTreeMap::new();
+        self.walk(
+            self.table_addr,
+            EmulateArch::PAGE_LEVELS - 1,
+            0,
+            &mut entries,
+        );
+        entries
+    }
+
+    fn walk(
+        &self,
+        table: PhysicalAddress,
+        level: usize,
+        base: usize,
+        entries: &mut BTreeMap<(VirtualAddress, usize), usize>,
+    ) {
+        let shift = level * EmulateArch::PAGE_ENTRY_SHIFT + EmulateArch::PAGE_SHIFT;
+        for i in 0..EmulateArch::PAGE_ENTRIES {
+            let e = match self.read_entry(table.add(i * EmulateArch::PAGE_ENTRY_SIZE)) {
+                Some(e) if e != 0 => e,
+                Some(_) => continue,
+                // 表项在内存之外，不再继续
+                None => return,
+            };
+            let virt = base | (i << shift);
+            entries.insert(
+                (EmulateArch::canonicalize(VirtualAddress::new(virt)), level),
+                e,
+            );
+            if level > 0
+                && e & EmulateArch::ENTRY_FLAG_PRESENT != 0
+                && e & EmulateArch::ENTRY_FLAG_HUGE == 0
+            {
+                let next = PhysicalAddress::new(e & EmulateArch::ENTRY_ADDRESS_MASK);
+                self.walk(next, level - 1, virt, entries);
+            }
+        }
+    }
+
+    /// 列出从`self`到`other`变化的物理页和页表项
+    pub fn diff(&self, other: &Self) -> SnapshotDiff {
+        if self.memory.len() != other.memory.len() {
+            panic!(
+                "EmulateSnapshot: memory size 0x{:X} differs from 0x{:X}",
+                self.memory.len(),
+                other.memory.len()
+            );
+        }
+        let pages = self
+            .memory
+            .chunks(EmulateArch::PAGE_SIZE)
+            .zip(other.memory.chunks(EmulateArch::PAGE_SIZE))
+            .enumerate()
+            .filter(|(_, (old, new))| old != new)
+            .map(|(i, _)| PhysicalAddress::new(i * EmulateArch::PAGE_SIZE))
+            .collect();
+        let old_entries = self.entries();
+        let mut new_entries = other.entries();
+        let mut entries = Vec::new();
+        for (&(virt, level), &old) in old_entries.iter() {
+            let new = new_entries.remove(&(virt, level)).unwrap_or(0);
+            if old != new {
+                entries.push(EntryChange {
+                    virt,
+                    level,
+                    old: PageEntry::new(old),
+                    new: PageEntry::new(new),
+                });
+            }
+        }
+        for ((virt, level), new) in new_entries {
+            entries.push(EntryChange {
+                virt,
+                level,
+                old: PageEntry::new(0),
+                new: PageEntry::new(new),
+            });
+        }
+        // 同一地址的表项从上层到下层排列
+        entries.sort_by_key(|change| (change.virt, Reverse(change.level)));
+        SnapshotDiff { pages, entries }
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&SNAPSHOT_MAGIC)?;
+        let privilege = match self.privilege {
+            Privilege::Supervisor => 0,
+            Privilege::User => 1,
+        };
+        write_usize(writer, self.memory.len())?;
+        write_usize(writer, self.table_addr.data())?;
+        write_usize(writer, self.asid)?;
+        write_usize(writer, privilege)?;
+        write_usize(writer, self.tlb.len())?;
+        for tlb in self.tlb.iter() {
+            write_usize(writer, tlb.tag)?;
+            write_usize(writer, tlb.virt.data())?;
+            write_usize(writer, tlb.entry)?;
+            write_usize(writer, tlb.entry_phys.data())?;
+            write_usize(writer, tlb.level)?;
+        }
+        writer.write_all(&self.memory)
+    }
+
+    pub fn read_from<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let mut magic = [0; 8];
+        reader.read_exact(&mut magic)?;
+        if magic != SNAPSHOT_MAGIC {
+            return Err(invalid_data("not an emulator snapshot"));
+        }
+        let memory_size = read_usize(reader)?;
+        if memory_size > SNAPSHOT_MEMORY_MAX {
+            return Err(invalid_data("memory size too large"));
+        }
+        let table_addr = PhysicalAddress::new(read_usize(reader)?);
+        if table_addr.data() >= memory_size {
+            return Err(invalid_data("page table outside of memory"));
+        }
+        let asid = read_usize(reader)?;
+        let privilege = match read_usize(reader)? {
+            0 => Privilege::Supervisor,
+            1 => Privilege::User,
+            _ => return Err(invalid_data("invalid privilege")),
+        };
+        let count = read_usize(reader)?;
+        if count > SNAPSHOT_TLB_MAX {
+            return Err(invalid_data("too many TLB entries"));
+        }
+        let mut tlb = Vec::new();
+        for _ in 0..count {
+            tlb.push(SavedTlb {
+                tag: read_usize(reader)?,
+                virt: VirtualAddress::new(read_usize(reader)?),
+                entry: read_usize(reader)?,
+                entry_phys: PhysicalAddress::new(read_usize(reader)?),
+                level: read_usize(reader)?,
+            });
+        }
+        // 按读到的数据增长，截断的文件不会先分配完整的内存
+        let mut memory = Vec::new();
+        reader
+            .by_ref()
+            .take(memory_size as u64)
+            .read_to_end(&mut memory)?;
+        if memory.len() != memory_size {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "truncated snapshot memory",
+            ));
+        }
+        Ok(Self {
+            memory: memory.into_boxed_slice(),
+            table_addr,
+            asid,
+            privilege,
+            tlb,
+        })
+    }
+
+    /// 保存到文件
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write_to(&mut writer)?;
+        writer.flush()
+    }
+
+    /// 从`save`保存的文件读取
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::read_from(&mut BufReader::new(File::open(path)?))
+    }
+}
+
+fn write_usize<W: Write>(writer: &mut W, value: usize) -> io::Result<()> {
+    writer.write_all(&(value as u64).to_le_bytes())
+}
+
+fn read_usize<R: Read>(reader: &mut R) -> io::Result<usize> {
+    let mut data = [0; 8];
+    reader.read_exact(&mut data)?;
+    Ok(u64::from_le_bytes(data) as usize)
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+impl EmulateArch {
+    /// 保存当前线程模拟机器的状态
+    pub unsafe fn snapshot() -> EmulateSnapshot {
+        with_machine::<X8664Arch, _, _>(|machine| EmulateSnapshot {
+            memory: machine.memory.clone(),
+            table_addr: machine.table_addr,
+            asid: machine.asid,
+            privilege: machine.privilege,
+            tlb: machine
+                .map
+                .iter()
+                .map(|(&(tag, virt), tlb)| SavedTlb {
+                    tag,
+                    virt,
+                    entry: tlb.entry.data(),
+                    entry_phys: tlb.entry_phys,
+                    level: tlb.level,
+                })
+                .collect(),
+        })
+    }
+
+    /// 用快照替换当前线程的模拟机器，TLB也恢复成快照时的内容
+    pub unsafe fn restore(snapshot: &EmulateSnapshot) {
+        let machine = Machine::<EmulateArch> {
+            memory: snapshot.memory.clone(),
+            map: snapshot
+                .tlb
+                .iter()
+                .map(|saved| {
+                    let tlb = TlbEntry {
+                        entry: PageEntry::new(saved.entry),
+                        entry_phys: saved.entry_phys,
+                        level: saved.level,
+                    };
+                    ((saved.tag, saved.virt), tlb)
+                })
+                .collect(),
+            table_addr: snapshot.table_addr,
+            asid: snapshot.asid,
+            privilege: snapshot.privilege,
+            phantom: PhantomData,
+        };
+        set_machine(machine);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{write_usize, EmulateSnapshot, SNAPSHOT_MAGIC, SNAPSHOT_MEMORY_MAX};
+    use crate::{
+        emulate_test_with, Arch, EmulateArch, EmulateConfig, PageMapper, VirtualAddress, MEGA_BYTE,
+    };
+
+    #[test]
+    fn snapshot() {
+        unsafe {
+            let mut allocator = emulate_test_with(&EmulateConfig::new(4 * MEGA_BYTE));
+            let mut mapper = PageMapper::<EmulateArch, _>::current(&mut allocator);
+            let flags = EmulateArch::ENTRY_FLAG_WRITABLE | EmulateArch::ENTRY_FLAG_NO_EXEC;
+            let first = VirtualAddress::new(0x1000);
+            mapper.map(first, flags).unwrap().flush();
+            EmulateArch::write::<usize>(first, 1);
+            let before = EmulateArch::snapshot();
+            assert!(before.diff(&before).is_empty());
+
+            // 新的一页在另一个页目录里，需要新的页表
+            let second = VirtualAddress::new(0x20_0000);
+            mapper.map(second, flags).unwrap().flush();
+            EmulateArch::write::<usize>(first, 2);
+            let after = EmulateArch::snapshot();
+            let diff = before.diff(&after);
+            let (first_phys, _) = mapper.translate(first).unwrap();
+            let (second_phys, _) = mapper.translate(second).unwrap();
+            assert!(diff.pages.contains(&first_phys));
+            // 直接映射的表项也会因为访问位和脏位变化，这里只看用户空间
+            let changed: Vec<_> = diff
+                .entries
+                .iter()
+                .filter(|change| change.virt.data() < EmulateArch::PHYS_OFFSET)
+                .collect();
+            // 第一页的访问位和脏位在写入1时已经设置，表项没有变化
+            assert_eq!(changed.len(), 2);
+            let (table, leaf) = (changed[0], changed[1]);
+            assert_eq!((table.virt, table.level), (second, 1));
+            assert_eq!((leaf.virt, leaf.level), (second, 0));
+            assert_eq!((leaf.old.data(), leaf.new.address()), (0, second_phys));
+            assert!(diff.pages.contains(&table.new.address()));
+
+            let path = std::env::temp_dir().join(format!("mm-snapshot-{}", std::process::id()));
+            after.save(&path).unwrap();
+            let loaded = EmulateSnapshot::load(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+            assert!(loaded.diff(&after).is_empty());
+            assert_eq!(loaded.table(), after.table());
+
+            EmulateArch::restore(&before);
+            assert_eq!(EmulateArch::read::<usize>(first), 1);
+            assert!(EmulateArch::try_read::<usize>(second).is_err());
+            EmulateArch::restore(&loaded);
+            assert_eq!(EmulateArch::read::<usize>(first), 2);
+            assert_eq!(EmulateArch::read::<usize>(second), 0);
+        }
+    }
+
+    #[test]
+    fn read_invalid() {
+        let header = |memory_size: usize, count: usize| {
+            let mut data = SNAPSHOT_MAGIC.to_vec();
+            for &value in [memory_size, 0, 0, 0, count].iter() {
+                write_usize(&mut data, value).unwrap();
+            }
+            data
+        };
+        let read = |data: Vec<u8>| EmulateSnapshot::read_from(&mut data.as_slice()).err();
+
+        assert!(read(header(SNAPSHOT_MEMORY_MAX + 1, 0)).is_some());
+        assert!(read(header(0x1000, usize::MAX)).is_some());
+        // TLB项和内存都按读到的数据增长，截断时返回错误
+        assert!(read(header(0x1000, 1000)).is_some());
+        let mut truncated = header(SNAPSHOT_MEMORY_MAX, 0);
+        truncated.extend_from_slice(&[0; 16]);
+        let error = read(truncated).unwrap();
+        assert_eq!(error.kind(), std::io::ErrorKind::UnexpectedEof);
+
+        let mut data = header(0x1000, 0);
+        data.extend_from_slice(&[0; 0x1000]);
+        assert!(read(data).is_none());
+    }
+}
@@ -3,12 +3,53 @@ use core::ptr;
 use crate::{MemoryArea, PhysicalAddress, VirtualAddress};
 
 mod x86_64;
-pub use self::x86_64::X8664Arch;
+pub use self::x86_64::{X8664Arch, X8664La57Arch};
 
 #[cfg(feature = "std")]
 mod emulate;
 #[cfg(feature = "std")]
-pub use self::emulate::EmulateArch;
+pub use self::emulate::{
+    Emulate, EmulateArch, EmulateConfig, EmulateFault, EmulateLa57Arch, EmulateSnapshot, EntryChange,
+    Privilege, SnapshotDiff,
+};
+#[cfg(test)]
+pub(crate) use self::emulate::{emulate_test, emulate_test_bump, emulate_test_with};
+
+/// 映射的内存类型（缓存策略）
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MemoryType {
+    /// 普通内存
+    WriteBack,
+    /// 写直通，读可以缓存
+    WriteThrough,
+    /// 不缓存，用于设备寄存器
+    Uncached,
+    /// 写合并，用于帧缓冲等
+    WriteCombining,
+}
+
+/// 引起缺页的访问类型
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FaultAccess {
+    Read,
+    Write,
+    /// 取指令
+    Execute,
+}
+
+/// 解码后的缺页信息
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FaultInfo {
+    /// 引起缺页的地址
+    pub address: VirtualAddress,
+    pub access: FaultAccess,
+    /// 在用户态发生
+    pub user: bool,
+    /// 页存在，缺页是因为权限不足
+    pub present: bool,
+    /// 页表项中设置了保留位
+    pub reserved: bool,
+}
 
 pub trait Arch: Clone + Copy {
     /// page最大长度 = 12 (x86中一般为12)
@@ -22,7 +63,17 @@ pub trait Arch: Clone + Copy {
     const ENTRY_FLAG_HUGE: usize;
     const ENTRY_FLAG_GLOBAL: usize;
     const ENTRY_FLAG_NO_EXEC: usize;
+    /// 访问页时由硬件设置
+    const ENTRY_FLAG_ACCESSED: usize;
+    /// 写页时由硬件设置
+    const ENTRY_FLAG_DIRTY: usize;
+    /// 软件使用的位：不存在的表项中保存的是交换槽位
+    const ENTRY_FLAG_SWAP: usize;
+    /// 软件使用的位：写时复制的只读页，写入时复制页框
+    const ENTRY_FLAG_COW: usize;
     const PHYS_OFFSET: usize;
+    /// 地址空间标识（ASID）的数量，0表示不支持，ASID 0保留给不使用ASID的切换
+    const ASID_COUNT: usize = 0;
     /// page_size 页长 1 << 12 也就是 2^12 = 4096
     const PAGE_SIZE: usize = 1 << Self::PAGE_SHIFT;
     const PAGE_OFFSET_MASK: usize = Self::PAGE_SIZE - 1;
@@ -43,6 +94,12 @@ pub trait Arch: Clone + Copy {
     const ENTRY_ADDRESS_MASK: usize = Self::ENTRY_ADDRESS_SIZE - Self::PAGE_SIZE;
     const ENTRY_FLAGS_MASK: usize = !Self::ENTRY_ADDRESS_MASK;
     unsafe fn init() -> &'static [MemoryArea];
+    /// 内存类型对应的表项标志，`huge`表示大页表项
+    fn memory_type_flags(memory_type: MemoryType, huge: bool) -> usize;
+    /// 从表项标志中解析内存类型
+    fn memory_type(flags: usize, huge: bool) -> MemoryType;
+    /// 解码缺页异常的错误码，`address`是引起缺页的地址
+    fn decode_fault(error_code: usize, address: VirtualAddress) -> FaultInfo;
     #[inline(always)]
     unsafe fn read<T>(address: VirtualAddress) -> T {
         ptr::read(address.data() as *const T)
@@ -62,8 +119,39 @@ pub trait Arch: Clone + Copy {
     unsafe fn invalid_data_all() {
         Self::set_table(Self::table());
     }
+    /// 在`asid`下切换页表，不刷新这个ASID在TLB中的表项
+    #[inline(always)]
+    unsafe fn set_table_asid(address: PhysicalAddress, _asid: usize) {
+        Self::set_table(address);
+    }
+    /// 刷新一个ASID在TLB中的所有表项
+    #[inline(always)]
+    unsafe fn invalid_asid(_asid: usize) {
+        Self::invalid_data_all();
+    }
+    /// 刷新所有ASID在TLB中的表项，包括全局页
+    #[inline(always)]
+    unsafe fn invalid_all_asids() {
+        Self::invalid_data_all();
+    }
     #[inline(always)]
     unsafe fn phys_to_virt(phys: PhysicalAddress) -> VirtualAddress {
         VirtualAddress::new(phys.data() + Self::PHYS_OFFSET)
     }
+    /// 虚拟地址是否是规范形式，即高位都是最高有效位的符号扩展
+    #[inline(always)]
+    fn is_canonical(address: VirtualAddress) -> bool {
+        let high_mask = !((1 << (Self::PAGE_ADDRESS_SHIFT - 1)) - 1);
+        let high = address.data() & high_mask;
+        high == 0 || high == high_mask
+    }
+    /// 把页表索引计算出的地址转换为规范形式
+    #[inline(always)]
+    fn canonicalize(address: VirtualAddress) -> VirtualAddress {
+        if address.data() & (1 << (Self::PAGE_ADDRESS_SHIFT - 1)) != 0 {
+            VirtualAddress::new(address.data() | Self::PAGE_NEGATIVE_MASK)
+        } else {
+            VirtualAddress::new(address.data() & !Self::PAGE_NEGATIVE_MASK)
+        }
+    }
 }
@@ -1,8 +1,113 @@
-use crate::{Arch, MemoryArea, PhysicalAddress, VirtualAddress};
+use core::sync::atomic::{AtomicBool, Ordering};
 
-#[derive(Clone, Copy)]
+use crate::{
+    Arch, FaultAccess, FaultInfo, MemoryArea, MemoryType, PhysicalAddress, VirtualAddress,
+};
+
+/// `enable_pcid`设置，之后切换页表不用再读取CR4
+static PCID_ENABLED: AtomicBool = AtomicBool::new(false);
+/// CPU是否支持INVPCID指令，和PCID是分开的特性，在`enable_pcid`中检测
+static INVPCID_SUPPORTED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Clone, Copy, Debug)]
 pub struct X8664Arch;
 
+impl X8664Arch {
+    pub const ENTRY_FLAG_WRITE_THROUGH: usize = 1 << 3;
+    pub const ENTRY_FLAG_NO_CACHE: usize = 1 << 4;
+    /// 普通页的PAT位，和大页的`ENTRY_FLAG_HUGE`是同一位
+    pub const ENTRY_FLAG_PAT: usize = 1 << 7;
+    /// 大页的PAT位
+    pub const ENTRY_FLAG_HUGE_PAT: usize = 1 << 12;
+    /// 缺页错误码：页存在（权限错误）
+    pub const FAULT_PRESENT: usize = 1 << 0;
+    pub const FAULT_WRITE: usize = 1 << 1;
+    pub const FAULT_USER: usize = 1 << 2;
+    pub const FAULT_RESERVED: usize = 1 << 3;
+    pub const FAULT_INSTRUCTION: usize = 1 << 4;
+    /// CR4中启用全局页的位，改变这一位会刷新包括全局页在内的整个TLB
+    pub const CR4_PGE: usize = 1 << 7;
+    /// CR4中启用PCID的位
+    pub const CR4_PCIDE: usize = 1 << 17;
+    /// CPUID leaf 7 EBX中表示支持INVPCID的位
+    pub const CPUID_7_EBX_INVPCID: u32 = 1 << 10;
+    /// 写CR3时设置这一位，不刷新新PCID在TLB中的表项
+    pub const CR3_NO_FLUSH: usize = 1 << 63;
+    /// CR3的低12位是PCID
+    pub const CR3_PCID_MASK: usize = 0xFFF;
+    pub const INVPCID_SINGLE: usize = 1;
+    pub const INVPCID_ALL: usize = 2;
+    pub const MSR_PAT: u32 = 0x277;
+    /// PAT 0-3 和上电默认值相同（WB、WT、UC-、UC），PAT 4 改为 WC，5-7 和 1-3 相同
+    pub const PAT_VALUE: u64 = 0x0007_0401_0007_0406;
+
+    /// 引起缺页的地址，保存在CR2中，需要在缺页处理开始时读取
+    pub unsafe fn fault_address() -> VirtualAddress {
+        let address: usize;
+        asm!("mov {0}, cr2", out(reg) address);
+        VirtualAddress::new(address)
+    }
+
+    /// 设置`PAT_VALUE`，每个 CPU 在使用写合并映射之前都需要调用
+    pub unsafe fn init_pat() {
+        asm!(
+            "wrmsr",
+            in("ecx") Self::MSR_PAT,
+            in("eax") Self::PAT_VALUE as u32,
+            in("edx") (Self::PAT_VALUE >> 32) as u32,
+        );
+    }
+
+    /// 启用PCID，使用`set_table_asid`之前每个 CPU 都需要调用
+    pub unsafe fn enable_pcid() {
+        asm!(
+            "mov {0}, cr4",
+            "or {0}, {1}",
+            "mov cr4, {0}",
+            out(reg) _,
+            in(reg) Self::CR4_PCIDE,
+        );
+        let invpcid = core::arch::x86_64::__cpuid_count(7, 0).ebx & Self::CPUID_7_EBX_INVPCID;
+        INVPCID_SUPPORTED.store(invpcid != 0, Ordering::Relaxed);
+        PCID_ENABLED.store(true, Ordering::Relaxed);
+    }
+
+    /// 是否调用过`enable_pcid`，没有启用时CR3的低12位不是PCID
+    pub fn pcid_enabled() -> bool {
+        PCID_ENABLED.load(Ordering::Relaxed)
+    }
+
+    fn invpcid_supported() -> bool {
+        INVPCID_SUPPORTED.load(Ordering::Relaxed)
+    }
+
+    /// 改变两次CR4的PGE位，刷新所有PCID和全局页
+    unsafe fn toggle_pge() {
+        asm!(
+            "mov {0}, cr4",
+            "xor {0}, {1}",
+            "mov cr4, {0}",
+            "xor {0}, {1}",
+            "mov cr4, {0}",
+            out(reg) _,
+            in(reg) Self::CR4_PGE,
+        );
+    }
+
+    unsafe fn invpcid(kind: usize, pcid: usize) {
+        let descriptor: [u64; 2] = [pcid as u64, 0];
+        asm!("invpcid {0}, [{1}]", in(reg) kind, in(reg) &descriptor);
+    }
+
+    fn pat_flag(huge: bool) -> usize {
+        if huge {
+            Self::ENTRY_FLAG_HUGE_PAT
+        } else {
+            Self::ENTRY_FLAG_PAT
+        }
+    }
+}
+
 impl Arch for X8664Arch {
     /// 4096 bytes  
     const PAGE_SHIFT: usize = 12;
@@ -13,15 +118,59 @@ impl Arch for X8664Arch {
     const ENTRY_FLAG_PRESENT: usize = 1 << 0;
     const ENTRY_FLAG_WRITABLE: usize = 1 << 1;
     const ENTRY_FLAG_USER: usize = 1 << 2;
+    const ENTRY_FLAG_ACCESSED: usize = 1 << 5;
+    const ENTRY_FLAG_DIRTY: usize = 1 << 6;
     const ENTRY_FLAG_HUGE: usize = 1 << 7;
     const ENTRY_FLAG_GLOBAL: usize = 1 << 8;
+    const ENTRY_FLAG_SWAP: usize = 1 << 9;
+    const ENTRY_FLAG_COW: usize = 1 << 10;
     const ENTRY_FLAG_NO_EXEC: usize = 1 << 63;
     const PHYS_OFFSET: usize = Self::PAGE_NEGATIVE_MASK + (Self::PAGE_ADDRESS_SIZE >> 1);
+    /// PCID有12位，没有调用`enable_pcid`时退回到刷新TLB的切换
+    const ASID_COUNT: usize = 4096;
 
     unsafe fn init() -> &'static [MemoryArea] {
         unimplemented!("X8664ARCH::init unimplemented");
     }
 
+    fn memory_type_flags(memory_type: MemoryType, huge: bool) -> usize {
+        match memory_type {
+            MemoryType::WriteBack => 0,
+            MemoryType::WriteThrough => Self::ENTRY_FLAG_WRITE_THROUGH,
+            MemoryType::Uncached => Self::ENTRY_FLAG_NO_CACHE | Self::ENTRY_FLAG_WRITE_THROUGH,
+            MemoryType::WriteCombining => Self::pat_flag(huge),
+        }
+    }
+
+    fn memory_type(flags: usize, huge: bool) -> MemoryType {
+        let pat = flags & Self::pat_flag(huge) != 0;
+        let no_cache = flags & Self::ENTRY_FLAG_NO_CACHE != 0;
+        let write_through = flags & Self::ENTRY_FLAG_WRITE_THROUGH != 0;
+        match (pat, no_cache, write_through) {
+            (_, true, _) => MemoryType::Uncached,
+            (_, false, true) => MemoryType::WriteThrough,
+            (true, false, false) => MemoryType::WriteCombining,
+            (false, false, false) => MemoryType::WriteBack,
+        }
+    }
+
+    fn decode_fault(error_code: usize, address: VirtualAddress) -> FaultInfo {
+        let access = if error_code & Self::FAULT_INSTRUCTION != 0 {
+            FaultAccess::Execute
+        } else if error_code & Self::FAULT_WRITE != 0 {
+            FaultAccess::Write
+        } else {
+            FaultAccess::Read
+        };
+        FaultInfo {
+            address,
+            access,
+            user: error_code & Self::FAULT_USER != 0,
+            present: error_code & Self::FAULT_PRESENT != 0,
+            reserved: error_code & Self::FAULT_RESERVED != 0,
+        }
+    }
+
     unsafe fn invalid_data(address: VirtualAddress) {
         asm!("invlpg [{0}]", in(reg) address.data() );
     }
@@ -29,18 +178,133 @@ impl Arch for X8664Arch {
     unsafe fn table() -> PhysicalAddress {
         let address: usize;
         asm!("mov {0}, cr3", out(reg) address);
-        PhysicalAddress::new(address)
+        PhysicalAddress::new(address & !Self::CR3_PCID_MASK)
     }
 
     unsafe fn set_table(address: PhysicalAddress) {
         asm!("mov cr3, {0}", in(reg) address.data());
     }
+
+    /// 重新写入CR3，保留其中的PCID，只刷新当前PCID
+    unsafe fn invalid_data_all() {
+        let cr3: usize;
+        asm!("mov {0}, cr3", out(reg) cr3);
+        asm!("mov cr3, {0}", in(reg) cr3);
+    }
+
+    unsafe fn set_table_asid(address: PhysicalAddress, asid: usize) {
+        if !Self::pcid_enabled() {
+            return Self::set_table(address);
+        }
+        let cr3 = address.data() | (asid & Self::CR3_PCID_MASK) | Self::CR3_NO_FLUSH;
+        asm!("mov cr3, {0}", in(reg) cr3);
+    }
+
+    /// 没有INVPCID时临时切换到这个PCID写入CR3来刷新，再不刷新地切换回来，
+    /// 调用时需要关闭中断
+    unsafe fn invalid_asid(asid: usize) {
+        if !Self::pcid_enabled() {
+            return Self::invalid_data_all();
+        }
+        if Self::invpcid_supported() {
+            return Self::invpcid(Self::INVPCID_SINGLE, asid);
+        }
+        let cr3: usize;
+        asm!("mov {0}, cr3", out(reg) cr3);
+        let pcid = asid & Self::CR3_PCID_MASK;
+        if cr3 & Self::CR3_PCID_MASK == pcid {
+            return Self::invalid_data_all();
+        }
+        let flush = (cr3 & !Self::CR3_PCID_MASK) | pcid;
+        asm!(
+            "mov cr3, {0}",
+            "mov cr3, {1}",
+            in(reg) flush,
+            in(reg) cr3 | Self::CR3_NO_FLUSH,
+        );
+    }
+
+    unsafe fn invalid_all_asids() {
+        if Self::pcid_enabled() && Self::invpcid_supported() {
+            Self::invpcid(Self::INVPCID_ALL, 0);
+        } else {
+            Self::toggle_pge();
+        }
+    }
+}
+
+/// 5级分页（LA57）的x86_64，虚拟地址有57位，表项格式和4级分页相同
+#[derive(Clone, Copy, Debug)]
+pub struct X8664La57Arch;
+
+impl Arch for X8664La57Arch {
+    const PAGE_SHIFT: usize = X8664Arch::PAGE_SHIFT;
+    const PAGE_ENTRY_SHIFT: usize = X8664Arch::PAGE_ENTRY_SHIFT;
+    const PAGE_LEVELS: usize = 5;
+    const ENTRY_ADDRESS_SHIFT: usize = X8664Arch::ENTRY_ADDRESS_SHIFT;
+    const ENTRY_FLAG_PRESENT: usize = X8664Arch::ENTRY_FLAG_PRESENT;
+    const ENTRY_FLAG_WRITABLE: usize = X8664Arch::ENTRY_FLAG_WRITABLE;
+    const ENTRY_FLAG_USER: usize = X8664Arch::ENTRY_FLAG_USER;
+    const ENTRY_FLAG_ACCESSED: usize = X8664Arch::ENTRY_FLAG_ACCESSED;
+    const ENTRY_FLAG_DIRTY: usize = X8664Arch::ENTRY_FLAG_DIRTY;
+    const ENTRY_FLAG_HUGE: usize = X8664Arch::ENTRY_FLAG_HUGE;
+    const ENTRY_FLAG_GLOBAL: usize = X8664Arch::ENTRY_FLAG_GLOBAL;
+    const ENTRY_FLAG_SWAP: usize = X8664Arch::ENTRY_FLAG_SWAP;
+    const ENTRY_FLAG_COW: usize = X8664Arch::ENTRY_FLAG_COW;
+    const ENTRY_FLAG_NO_EXEC: usize = X8664Arch::ENTRY_FLAG_NO_EXEC;
+    const PHYS_OFFSET: usize = Self::PAGE_NEGATIVE_MASK + (Self::PAGE_ADDRESS_SIZE >> 1);
+    const ASID_COUNT: usize = X8664Arch::ASID_COUNT;
+
+    unsafe fn init() -> &'static [MemoryArea] {
+        unimplemented!("X8664La57Arch::init unimplemented");
+    }
+
+    fn memory_type_flags(memory_type: MemoryType, huge: bool) -> usize {
+        X8664Arch::memory_type_flags(memory_type, huge)
+    }
+
+    fn memory_type(flags: usize, huge: bool) -> MemoryType {
+        X8664Arch::memory_type(flags, huge)
+    }
+
+    fn decode_fault(error_code: usize, address: VirtualAddress) -> FaultInfo {
+        X8664Arch::decode_fault(error_code, address)
+    }
+
+    unsafe fn invalid_data(address: VirtualAddress) {
+        X8664Arch::invalid_data(address);
+    }
+
+    /// CR3指向PML5，格式和4级分页相同
+    unsafe fn table() -> PhysicalAddress {
+        X8664Arch::table()
+    }
+
+    unsafe fn set_table(address: PhysicalAddress) {
+        X8664Arch::set_table(address);
+    }
+
+    unsafe fn invalid_data_all() {
+        X8664Arch::invalid_data_all();
+    }
+
+    unsafe fn set_table_asid(address: PhysicalAddress, asid: usize) {
+        X8664Arch::set_table_asid(address, asid);
+    }
+
+    unsafe fn invalid_asid(asid: usize) {
+        X8664Arch::invalid_asid(asid);
+    }
+
+    unsafe fn invalid_all_asids() {
+        X8664Arch::invalid_all_asids();
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::X8664Arch;
-    use crate::Arch;
+    use super::{X8664Arch, X8664La57Arch};
+    use crate::{Arch, FaultAccess, FaultInfo, MemoryType, VirtualAddress};
 
     #[test]
     fn constants() {
@@ -61,4 +325,89 @@ mod tests {
 
         assert_eq!(X8664Arch::PHYS_OFFSET, 0xFFFF_8000_0000_0000);
     }
+
+    #[test]
+    fn memory_types() {
+        let types = [
+            MemoryType::WriteBack,
+            MemoryType::WriteThrough,
+            MemoryType::Uncached,
+            MemoryType::WriteCombining,
+        ];
+        for &huge in [false, true].iter() {
+            for &memory_type in types.iter() {
+                let flags = X8664Arch::memory_type_flags(memory_type, huge);
+                assert_eq!(X8664Arch::memory_type(flags, huge), memory_type);
+            }
+        }
+        assert_eq!(
+            X8664Arch::memory_type_flags(MemoryType::WriteCombining, false),
+            1 << 7
+        );
+        assert_eq!(
+            X8664Arch::memory_type_flags(MemoryType::WriteCombining, true),
+            1 << 12
+        );
+        // 大页的 HUGE 位不是 PAT 位
+        assert_eq!(
+            X8664Arch::memory_type(X8664Arch::ENTRY_FLAG_HUGE, true),
+            MemoryType::WriteBack
+        );
+    }
+
+    #[test]
+    fn la57_constants() {
+        assert_eq!(X8664La57Arch::PAGE_ADDRESS_SHIFT, 57);
+        assert_eq!(X8664La57Arch::PAGE_ADDRESS_MASK, 0x01FF_FFFF_FFFF_F000);
+        assert_eq!(X8664La57Arch::PAGE_NEGATIVE_MASK, 0xFE00_0000_0000_0000);
+        assert_eq!(
+            X8664La57Arch::ENTRY_ADDRESS_MASK,
+            X8664Arch::ENTRY_ADDRESS_MASK
+        );
+        assert_eq!(X8664La57Arch::PHYS_OFFSET, 0xFF00_0000_0000_0000);
+
+        // 4级分页中不规范的地址在5级分页中是规范的
+        let virt = VirtualAddress::new(0x0000_8000_0000_0000);
+        assert!(!X8664Arch::is_canonical(virt));
+        assert!(X8664La57Arch::is_canonical(virt));
+        assert!(X8664La57Arch::is_canonical(VirtualAddress::new(
+            0xFF00_0000_0000_0000
+        )));
+        assert!(!X8664La57Arch::is_canonical(VirtualAddress::new(
+            0x0100_0000_0000_0000
+        )));
+        assert_eq!(
+            X8664La57Arch::canonicalize(VirtualAddress::new(0x0100_0000_0000_0000)),
+            VirtualAddress::new(0xFF00_0000_0000_0000)
+        );
+    }
+
+    #[test]
+    fn decode_fault() {
+        let address = VirtualAddress::new(0x1000);
+        let info = |access, user, present, reserved| FaultInfo {
+            address,
+            access,
+            user,
+            present,
+            reserved,
+        };
+        assert_eq!(
+            X8664Arch::decode_fault(0, address),
+            info(FaultAccess::Read, false, false, false)
+        );
+        // 用户态写只读页
+        let error_code = X8664Arch::FAULT_PRESENT | X8664Arch::FAULT_WRITE | X8664Arch::FAULT_USER;
+        assert_eq!(
+            X8664Arch::decode_fault(error_code, address),
+            info(FaultAccess::Write, true, true, false)
+        );
+        let error_code = X8664Arch::FAULT_PRESENT | X8664Arch::FAULT_INSTRUCTION;
+        assert_eq!(
+            X8664La57Arch::decode_fault(error_code, address),
+            info(FaultAccess::Execute, false, true, false)
+        );
+        let error_code = X8664Arch::FAULT_PRESENT | X8664Arch::FAULT_RESERVED;
+        assert!(X8664Arch::decode_fault(error_code, address).reserved);
+    }
 }
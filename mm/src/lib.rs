@@ -8,12 +8,33 @@ pub use crate::arch::*;
 mod arch;
 pub use crate::page::*;
 mod page;
+pub use crate::swap::*;
+mod swap;
+#[cfg(test)]
+mod model;
 
 pub const KILO_BYTE: usize = 1024;
 pub const MEGA_BYTE: usize = KILO_BYTE * KILO_BYTE;
 pub const GIGA_BYTE: usize = MEGA_BYTE * KILO_BYTE;
 pub const TERA_BYTE: usize = GIGA_BYTE * KILO_BYTE;
 
+/// 向下对齐，`align`必须是2的幂
+#[inline(always)]
+pub const fn align_down(value: usize, align: usize) -> usize {
+    value & !(align - 1)
+}
+
+/// 向上对齐，`align`必须是2的幂
+#[inline(always)]
+pub const fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
+
+#[inline(always)]
+pub const fn is_aligned(value: usize, align: usize) -> bool {
+    value & (align - 1) == 0
+}
+
 /// 物理内存地址
 /// 告诉编译器，想C一样进行内存布局
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
@@ -33,6 +54,36 @@ impl PhysicalAddress {
     pub fn add(self, offset: usize) -> Self {
         Self(self.0 + offset)
     }
+    #[inline(always)]
+    pub fn checked_add(self, offset: usize) -> Option<Self> {
+        self.0.checked_add(offset).map(Self)
+    }
+    #[inline(always)]
+    pub fn checked_sub(self, offset: usize) -> Option<Self> {
+        self.0.checked_sub(offset).map(Self)
+    }
+    #[inline(always)]
+    pub fn wrapping_add(self, offset: usize) -> Self {
+        Self(self.0.wrapping_add(offset))
+    }
+    #[inline(always)]
+    pub fn wrapping_sub(self, offset: usize) -> Self {
+        Self(self.0.wrapping_sub(offset))
+    }
+    /// 向下对齐，`align`必须是2的幂
+    #[inline(always)]
+    pub fn align_down(self, align: usize) -> Self {
+        Self(align_down(self.0, align))
+    }
+    /// 向上对齐，`align`必须是2的幂
+    #[inline(always)]
+    pub fn align_up(self, align: usize) -> Self {
+        Self(align_up(self.0, align))
+    }
+    #[inline(always)]
+    pub fn is_aligned(self, align: usize) -> bool {
+        is_aligned(self.0, align)
+    }
 }
 
 /// 虚拟内存地址
@@ -53,6 +104,36 @@ impl VirtualAddress {
     pub fn add(self, offset: usize) -> Self {
         Self(self.0 + offset)
     }
+    #[inline(always)]
+    pub fn checked_add(self, offset: usize) -> Option<Self> {
+        self.0.checked_add(offset).map(Self)
+    }
+    #[inline(always)]
+    pub fn checked_sub(self, offset: usize) -> Option<Self> {
+        self.0.checked_sub(offset).map(Self)
+    }
+    #[inline(always)]
+    pub fn wrapping_add(self, offset: usize) -> Self {
+        Self(self.0.wrapping_add(offset))
+    }
+    #[inline(always)]
+    pub fn wrapping_sub(self, offset: usize) -> Self {
+        Self(self.0.wrapping_sub(offset))
+    }
+    /// 向下对齐，`align`必须是2的幂
+    #[inline(always)]
+    pub fn align_down(self, align: usize) -> Self {
+        Self(align_down(self.0, align))
+    }
+    /// 向上对齐，`align`必须是2的幂
+    #[inline(always)]
+    pub fn align_up(self, align: usize) -> Self {
+        Self(align_up(self.0, align))
+    }
+    #[inline(always)]
+    pub fn is_aligned(self, align: usize) -> bool {
+        is_aligned(self.0, align)
+    }
 }
 
 /// 存储块
@@ -61,3 +142,28 @@ pub struct MemoryArea {
     pub base: PhysicalAddress,
     pub size: usize,
 }
+
+impl MemoryArea {
+    /// 向外对齐到页边界，为空或者地址溢出时返回`None`
+    pub fn page_aligned<A: Arch>(&self) -> Option<Self> {
+        let start = self.base.align_down(A::PAGE_SIZE);
+        let end = self.base.checked_add(self.size)?.data();
+        let end = end.checked_add(A::PAGE_OFFSET_MASK)? & !A::PAGE_OFFSET_MASK;
+        if start.data() >= end {
+            return None;
+        }
+        Some(Self {
+            base: start,
+            size: end - start.data(),
+        })
+    }
+    pub fn end(&self) -> PhysicalAddress {
+        self.base.add(self.size)
+    }
+    pub fn overlaps(&self, other: &MemoryArea) -> bool {
+        self.base < other.end() && other.base < self.end()
+    }
+    pub fn contains(&self, other: &MemoryArea) -> bool {
+        self.base <= other.base && other.end() <= self.end()
+    }
+}
@@ -1,11 +1,17 @@
-use std::marker::PhantomData;
-
 use mm::{
-    Arch, BuddyAllocator, BumpAllocator, EmulateArch, FrameAllocator, FrameCount, MemoryArea,
-    PageFlush, PageFlushAll, PageMapper, PageTable, PhysicalAddress, VirtualAddress, GIGA_BYTE,
+    Arch, BuddyAllocator, BumpAllocator, EmulateArch, FrameAllocator, FrameCount, MappedRanges,
+    MemoryArea, PageFlushAll, PageMapper, PageTable, PhysicalAddress, VirtualAddress, GIGA_BYTE,
     KILO_BYTE, MEGA_BYTE, TERA_BYTE,
 };
 
+mod replay;
+
+const USAGE: &str = "\
+usage: mm <command> [args]
+commands:
+    demo      run the built-in allocation scenario
+    replay    replay an allocation trace, see `mm replay --help`";
+
 pub fn format_size(size: usize) -> String {
     if size >= 2 * TERA_BYTE {
         format!("{}TB", size / TERA_BYTE)
@@ -20,130 +26,6 @@ pub fn format_size(size: usize) -> String {
     }
 }
 
-unsafe fn dump_tables<A: Arch>(table: PageTable<A>) {
-    let level = table.level();
-    for i in 0..A::PAGE_ENTRIES {
-        if level == 0 {
-            if let Some(entry) = table.entry(i) {
-                if entry.present() {
-                    let base = table.entry_base(i).unwrap();
-                    println!("0x{:X}: 0x{:X}", base.data(), entry.address().data())
-                }
-            }
-        } else {
-            if let Some(next) = table.next(i) {
-                dump_tables(next);
-            }
-        }
-    }
-}
-
-pub struct SlabNode<A> {
-    next: PhysicalAddress,
-    count: usize,
-    phantom: PhantomData<A>,
-}
-
-impl<A: Arch> SlabNode<A> {
-    pub fn new(next: PhysicalAddress, count: usize) -> Self {
-        Self {
-            next,
-            count,
-            phantom: PhantomData,
-        }
-    }
-
-    pub fn empty() -> Self {
-        Self::new(PhysicalAddress::new(0), 0)
-    }
-
-    pub unsafe fn insert(&mut self, phys: PhysicalAddress) {
-        let virt = A::phys_to_virt(phys);
-        A::write(virt, self.next);
-        self.next = phys;
-        self.count += 1;
-    }
-
-    pub unsafe fn remove(&mut self) -> Option<PhysicalAddress> {
-        if self.count > 0 {
-            let phys = self.next;
-            let virt = A::phys_to_virt(phys);
-            self.next = A::read(virt);
-            self.count -= 1;
-            Some(phys)
-        } else {
-            None
-        }
-    }
-}
-
-pub struct SlabAllocator<A> {
-    nodes: [SlabNode<A>; 4],
-    phantom: PhantomData<A>,
-}
-
-impl<A: Arch> SlabAllocator<A> {
-    pub unsafe fn new(areas: &'static [MemoryArea], offset: usize) -> Self {
-        let mut allocator = Self {
-            nodes: [
-                SlabNode::empty(),
-                SlabNode::empty(),
-                SlabNode::empty(),
-                SlabNode::empty(),
-            ],
-            phantom: PhantomData,
-        };
-        let mut area_offset = offset;
-        for area in areas.iter() {
-            if area_offset < area.size {
-                let area_base = area.base.add(area_offset);
-                let area_size = area.size - area_offset;
-                allocator.free(area_base, area_size);
-                area_offset = 0;
-            } else {
-                area_offset -= area.size;
-            }
-        }
-        allocator
-    }
-
-    pub unsafe fn allocator(&mut self, size: usize) -> Option<PhysicalAddress> {
-        for level in 0..A::PAGE_LEVELS - 1 {
-            let level_shift = level * A::PAGE_ENTRY_SHIFT + A::PAGE_SHIFT;
-            let level_size = 1 << level_shift;
-            if size <= level_size {
-                if let Some(base) = self.nodes[level].remove() {
-                    self.free(base.add(size), level_size - size);
-                    return Some(base);
-                }
-            }
-        }
-    }
-
-    pub unsafe fn free(&mut self, mut base: PhysicalAddress, mut size: usize) {
-        for level in (0..A::PAGE_LEVELS - 1).rev() {
-            let level_shift = level * A::PAGE_ENTRY_SHIFT + A::PAGE_SHIFT;
-            let level_size = 1 << level_shift;
-            while size >= level_size {
-                println!("add {:X} {}", base.data(), format_size(level_size));
-                self.nodes[level].insert(base);
-                base = base.add(level_size);
-                size -= level_size;
-            }
-        }
-    }
-
-    pub unsafe fn remaining(&mut self) -> usize {
-        let mut remaining = 0;
-        for level in (0..A::PAGE_LEVELS - 1).rev() {
-            let level_shift = level * A::PAGE_ENTRY_SHIFT + A::PAGE_SHIFT;
-            let level_size = 1 << level_shift;
-            remaining += self.nodes[level].count * level_size;
-        }
-        remaining
-    }
-}
-
 unsafe fn new_tables<A: Arch>(areas: &'static [MemoryArea]) {
     let mut size = 0;
     for area in areas.iter() {
@@ -166,6 +48,10 @@ unsafe fn new_tables<A: Arch>(areas: &'static [MemoryArea]) {
         }
         mapper.make_current();
     }
+    println!("Mappings:");
+    for range in MappedRanges::new(PageTable::<A>::top()) {
+        println!("{}", range);
+    }
     let offset = bump_allocator.offset();
     println!("Permanently used:{}", format_size(offset));
     let mut allocator = BuddyAllocator::<A>::new(bump_allocator).unwrap();
@@ -202,17 +88,51 @@ unsafe fn new_tables<A: Arch>(areas: &'static [MemoryArea]) {
         flush_all.consume(flush);
     }
     flush_all.flush();
+    let flush_all = PageFlushAll::new();
     for i in 0..16 {
         let virt = VirtualAddress::new(MEGA_BYTE + i * A::PAGE_SIZE);
-        let flush = mapper.unmap_phys(virt).expect("failed to unmap page");
+        let flush = mapper.unmap(virt).expect("failed to unmap page");
         flush_all.consume(flush);
     }
     flush_all.flush();
+    drop(mapper);
+    print_usage::<A, _>(&allocator);
+}
+
+unsafe fn print_usage<A: Arch, F: FrameAllocator>(allocator: &F) {
     let usage = allocator.usage();
-    println!("Allocator usgae:");
+    println!("Allocator usage:");
     println!("Used:{}", format_size(usage.used().data() * A::PAGE_SIZE));
     println!("Free:{}", format_size(usage.free().data() * A::PAGE_SIZE));
     println!("Total:{}", format_size(usage.tatal().data() * A::PAGE_SIZE));
+    println!(
+        "Shared:{}",
+        format_size(usage.shared().data() * A::PAGE_SIZE)
+    );
+    println!(
+        "Largest free:{}",
+        format_size(usage.largest_free().data() * A::PAGE_SIZE)
+    );
+    println!("Free runs:");
+    for (order, count) in usage.free_runs().iter().enumerate() {
+        if *count > 0 {
+            println!("  >={}: {}", format_size(A::PAGE_SIZE << order), count);
+        }
+    }
+    let mut i = 0;
+    while let Some((area, area_usage)) = allocator.area_usage(i) {
+        println!(
+            "Area {}: 0x{:X}-0x{:X} used {} free {} largest free {} shared {}",
+            i,
+            area.base.data(),
+            area.base.data() + area.size,
+            format_size(area_usage.used().data() * A::PAGE_SIZE),
+            format_size(area_usage.free().data() * A::PAGE_SIZE),
+            format_size(area_usage.largest_free().data() * A::PAGE_SIZE),
+            format_size(area_usage.shared().data() * A::PAGE_SIZE),
+        );
+        i += 1;
+    }
 }
 
 unsafe fn inner<A: Arch>() {
@@ -241,7 +161,16 @@ unsafe fn inner<A: Arch>() {
 }
 
 fn main() {
-    unsafe {
-        inner::<EmulateArch>();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("demo") => unsafe {
+            inner::<EmulateArch>();
+        },
+        Some("replay") => replay::run(&args[1..]),
+        Some("-h") | Some("--help") => println!("{}", USAGE),
+        _ => {
+            eprintln!("{}", USAGE);
+            std::process::exit(2);
+        }
     }
 }
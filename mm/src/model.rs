@@ -0,0 +1,401 @@
+//! 随机操作序列的差分测试：在`EmulateArch`上执行映射和分配，
+//! 每一步之后和参考模型比较，失败时把序列缩小到最短的复现
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    panic::{self, AssertUnwindSafe},
+};
+
+use crate::{
+    emulate_test_with, Arch, EmulateArch, EmulateConfig, FrameAllocator, FrameCount, PageMapper,
+    PhysicalAddress, VirtualAddress, MEGA_BYTE,
+};
+
+/// 测试用的页数，分布在不同的页表里
+const MODEL_PAGES: usize = 32;
+/// 单次分配的最大页框数
+const MODEL_ALLOCATE_MAX: usize = 4;
+/// 比较时关心的表项标志
+const MODEL_FLAGS_MASK: usize = EmulateArch::ENTRY_FLAG_WRITABLE
+    | EmulateArch::ENTRY_FLAG_USER
+    | EmulateArch::ENTRY_FLAG_NO_EXEC;
+const MODEL_FLAGS: [usize; 4] = [
+    EmulateArch::ENTRY_FLAG_WRITABLE | EmulateArch::ENTRY_FLAG_NO_EXEC,
+    EmulateArch::ENTRY_FLAG_USER | EmulateArch::ENTRY_FLAG_NO_EXEC,
+    EmulateArch::ENTRY_FLAG_USER | EmulateArch::ENTRY_FLAG_WRITABLE,
+    0,
+];
+
+/// xorshift64，同一个种子总是生成同样的序列
+pub struct XorShift(u64);
+
+impl XorShift {
+    pub fn new(seed: u64) -> Self {
+        // 状态不能为0
+        Self(seed ^ 0x9E37_79B9_7F4A_7C15)
+    }
+    pub fn next(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.0 = x;
+        x
+    }
+    /// `[0, bound)`之间的数
+    pub fn below(&mut self, bound: usize) -> usize {
+        (self.next() % bound as u64) as usize
+    }
+}
+
+/// 一步操作，页和内存块用下标表示，缩小序列后仍然有意义
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Op {
+    /// 分配页框并映射
+    Map {
+        page: usize,
+        flags: usize,
+    },
+    /// 映射到之前分配的内存块的第一个页框
+    MapPhys {
+        page: usize,
+        block: usize,
+        flags: usize,
+    },
+    Unmap {
+        page: usize,
+    },
+    /// 保留页框，只修改标志
+    Remap {
+        page: usize,
+        flags: usize,
+    },
+    Allocate {
+        count: usize,
+    },
+    Free {
+        block: usize,
+    },
+}
+
+impl Op {
+    pub fn random(rng: &mut XorShift) -> Self {
+        let page = rng.below(MODEL_PAGES);
+        let flags = MODEL_FLAGS[rng.below(MODEL_FLAGS.len())];
+        match rng.below(6) {
+            0 => Op::Map { page, flags },
+            1 => Op::MapPhys {
+                page,
+                block: rng.below(8),
+                flags,
+            },
+            2 => Op::Unmap { page },
+            3 => Op::Remap { page, flags },
+            4 => Op::Allocate {
+                count: rng.below(MODEL_ALLOCATE_MAX) + 1,
+            },
+            _ => Op::Free {
+                block: rng.below(8),
+            },
+        }
+    }
+}
+
+pub fn random_ops(seed: u64, len: usize) -> Vec<Op> {
+    let mut rng = XorShift::new(seed);
+    (0..len).map(|_| Op::random(&mut rng)).collect()
+}
+
+/// 第i个测试页，相邻的4页共用一个页表，16页共用一个页目录
+pub fn model_page(i: usize) -> VirtualAddress {
+    VirtualAddress::new(
+        0x10_0000_0000 + (i / 16) * 0x4000_0000 + (i / 4 % 4) * 0x20_0000 + (i % 4) * 0x1000,
+    )
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Mapping {
+    phys: PhysicalAddress,
+    flags: usize,
+    // 由`map`分配，解除映射时需要释放
+    owned: bool,
+}
+
+/// 参考模型
+#[derive(Default)]
+struct Model {
+    mapped: BTreeMap<usize, Mapping>,
+    blocks: Vec<(PhysicalAddress, usize)>,
+    // 建立过的页表，页表不会被释放，键是(层级, 覆盖的地址范围编号)
+    tables: BTreeSet<(usize, usize)>,
+}
+
+impl Model {
+    /// 模型认为已经使用的页框数
+    fn used(&self) -> usize {
+        let owned = self.mapped.values().filter(|mapping| mapping.owned).count();
+        let blocks: usize = self.blocks.iter().map(|(_, count)| count).sum();
+        self.tables.len() + owned + blocks
+    }
+
+    fn add_tables(&mut self, virt: VirtualAddress) {
+        for level in 0..EmulateArch::PAGE_LEVELS - 1 {
+            let shift = (level + 1) * EmulateArch::PAGE_ENTRY_SHIFT + EmulateArch::PAGE_SHIFT;
+            self.tables.insert((level, virt.data() >> shift));
+        }
+    }
+
+    /// 新分配的页框不能和模型中已经使用的重叠
+    fn check_fresh(&self, phys: PhysicalAddress, count: usize) -> Result<(), String> {
+        let end = phys.add(count * EmulateArch::PAGE_SIZE);
+        let overlaps = |base: PhysicalAddress, count: usize| {
+            phys < base.add(count * EmulateArch::PAGE_SIZE) && base < end
+        };
+        let owned = self
+            .mapped
+            .values()
+            .filter(|mapping| mapping.owned)
+            .any(|mapping| overlaps(mapping.phys, 1));
+        if owned
+            || self
+                .blocks
+                .iter()
+                .any(|&(base, count)| overlaps(base, count))
+        {
+            return Err(format!(
+                "frames 0x{:X} count {} are already in use",
+                phys.data(),
+                count
+            ));
+        }
+        Ok(())
+    }
+}
+
+unsafe fn step<F: FrameAllocator>(
+    mapper: &mut PageMapper<EmulateArch, F>,
+    model: &mut Model,
+    op: Op,
+) -> Result<(), String> {
+    match op {
+        Op::Map { page, flags } => {
+            if model.mapped.contains_key(&page) {
+                return Ok(());
+            }
+            let virt = model_page(page);
+            mapper.map(virt, flags).ok_or("map failed")?.flush();
+            let (phys, _) = mapper.translate(virt).ok_or("map did not map")?;
+            model.check_fresh(phys, 1)?;
+            model.add_tables(virt);
+            model.mapped.insert(
+                page,
+                Mapping {
+                    phys,
+                    flags,
+                    owned: true,
+                },
+            );
+        }
+        Op::MapPhys { page, block, flags } => {
+            if model.mapped.contains_key(&page) || model.blocks.is_empty() {
+                return Ok(());
+            }
+            let (phys, _) = model.blocks[block % model.blocks.len()];
+            let virt = model_page(page);
+            mapper
+                .map_phys(virt, phys, flags)
+                .ok_or("map_phys failed")?
+                .flush();
+            model.add_tables(virt);
+            model.mapped.insert(
+                page,
+                Mapping {
+                    phys,
+                    flags,
+                    owned: false,
+                },
+            );
+        }
+        Op::Unmap { page } => {
+            let virt = model_page(page);
+            match model.mapped.remove(&page) {
+                Some(mapping) if mapping.owned => {
+                    mapper.unmap(virt).ok_or("unmap failed")?.flush();
+                }
+                Some(_) => {
+                    mapper
+                        .unmap_phys(virt)
+                        .ok_or("unmap_phys failed")?
+                        .1
+                        .flush();
+                }
+                None => {
+                    if mapper.unmap_phys(virt).is_some() {
+                        return Err("unmapped a page that was not mapped".into());
+                    }
+                }
+            }
+        }
+        Op::Remap { page, flags } => {
+            if let Some(mapping) = model.mapped.get_mut(&page) {
+                mapper
+                    .map_phys(model_page(page), mapping.phys, flags)
+                    .ok_or("remap failed")?
+                    .flush();
+                mapping.flags = flags;
+            }
+        }
+        Op::Allocate { count } => {
+            let phys = mapper
+                .allocator_mut()
+                .allocate(FrameCount::new(count))
+                .ok_or("allocate failed")?;
+            if !phys.is_aligned(EmulateArch::PAGE_SIZE) {
+                return Err(format!("allocate returned 0x{:X}", phys.data()));
+            }
+            model.check_fresh(phys, count)?;
+            model.blocks.push((phys, count));
+        }
+        Op::Free { block } => {
+            if model.blocks.is_empty() {
+                return Ok(());
+            }
+            let (phys, count) = model.blocks.remove(block % model.blocks.len());
+            mapper.allocator_mut().free(phys, FrameCount::new(count));
+        }
+    }
+    Ok(())
+}
+
+/// 比较页表和模型
+unsafe fn check<F: FrameAllocator>(
+    mapper: &PageMapper<EmulateArch, F>,
+    model: &Model,
+    base_used: usize,
+) -> Result<(), String> {
+    for page in 0..MODEL_PAGES {
+        let actual = mapper
+            .translate(model_page(page))
+            .map(|(phys, flags)| (phys, flags & MODEL_FLAGS_MASK));
+        let expected = model
+            .mapped
+            .get(&page)
+            .map(|mapping| (mapping.phys, mapping.flags));
+        if actual != expected {
+            return Err(format!(
+                "page {}: translate {:X?}, model {:X?}",
+                page, actual, expected
+            ));
+        }
+    }
+    let used = mapper.allocator().usage().used().data();
+    if used != base_used + model.used() {
+        return Err(format!(
+            "used frames {}, model {}",
+            used,
+            base_used + model.used()
+        ));
+    }
+    Ok(())
+}
+
+/// 在新的模拟机器上执行`ops`，返回第一次和模型不一致的步骤。
+/// 实现中的panic也当作失败
+pub fn run(ops: &[Op]) -> Result<(), (usize, String)> {
+    let mut current = 0;
+    let result = panic::catch_unwind(AssertUnwindSafe(|| unsafe {
+        let mut allocator = emulate_test_with(&EmulateConfig::new(4 * MEGA_BYTE));
+        let mut mapper = PageMapper::<EmulateArch, _>::current(&mut allocator);
+        let base_used = mapper.allocator().usage().used().data();
+        let mut model = Model::default();
+        for (i, op) in ops.iter().enumerate() {
+            current = i;
+            step(&mut mapper, &mut model, *op)
+                .and_then(|_| check(&mapper, &model, base_used))
+                .map_err(|message| (i, message))?;
+        }
+        Ok(())
+    }));
+    match result {
+        Ok(result) => result,
+        Err(payload) => {
+            let message = payload
+                .downcast_ref::<&str>()
+                .map(|message| message.to_string())
+                .or_else(|| payload.downcast_ref::<String>().cloned())
+                .unwrap_or_default();
+            Err((current, format!("panicked: {}", message)))
+        }
+    }
+}
+
+/// 缩小失败的序列：不断尝试删除一段操作，删除后仍然失败就保留删除
+pub fn shrink<T: Clone, P: FnMut(&[T]) -> bool>(mut items: Vec<T>, mut fails: P) -> Vec<T> {
+    let mut chunk = items.len() / 2;
+    while chunk > 0 {
+        let mut shrunk = false;
+        let mut i = 0;
+        while i < items.len() {
+            let mut candidate = items.clone();
+            candidate.drain(i..(i + chunk).min(items.len()));
+            if fails(&candidate) {
+                items = candidate;
+                shrunk = true;
+            } else {
+                i += chunk;
+            }
+        }
+        if !shrunk {
+            chunk /= 2;
+        }
+    }
+    items
+}
+
+/// 用多个种子执行随机序列，失败时panic并给出缩小后的序列
+pub fn model_check(seeds: core::ops::Range<u64>, len: usize) {
+    for seed in seeds {
+        let ops = random_ops(seed, len);
+        if let Err((i, _)) = run(&ops) {
+            let ops = shrink(ops[..=i].to_vec(), |ops| run(ops).is_err());
+            let (_, message) = run(&ops).unwrap_err();
+            panic!(
+                "model check failed with seed {}: {}\nminimal sequence: {:#?}",
+                seed, message, ops
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{model_check, random_ops, run, shrink, Op, XorShift};
+
+    #[test]
+    fn mapper_and_buddy() {
+        model_check(0..16, 200);
+    }
+
+    #[test]
+    fn shrinking() {
+        let mut rng = XorShift::new(1);
+        assert_ne!(rng.next(), rng.next());
+        assert_eq!(random_ops(7, 50), random_ops(7, 50));
+
+        // 同一页先映射再解除的序列
+        let fails = |ops: &[Op]| {
+            ops.iter().enumerate().any(|(i, op)| {
+                *op == Op::Map { page: 3, flags: 0 } && ops[i..].contains(&Op::Unmap { page: 3 })
+            })
+        };
+        let mut ops = random_ops(3, 100);
+        ops.insert(20, Op::Map { page: 3, flags: 0 });
+        ops.insert(70, Op::Unmap { page: 3 });
+        let minimal = shrink(ops, fails);
+        assert_eq!(
+            minimal,
+            [Op::Map { page: 3, flags: 0 }, Op::Unmap { page: 3 }]
+        );
+        assert!(run(&minimal).is_ok());
+    }
+}
@@ -0,0 +1,184 @@
+use core::marker::PhantomData;
+
+use crate::{Arch, PhysicalAddress};
+
+/// 地址空间持有的ASID，所属的代过期后需要重新分配
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Asid {
+    generation: usize,
+    asid: usize,
+}
+
+impl Asid {
+    /// 还没有分配的ASID，第0代永远不是当前的一代
+    pub const fn new() -> Self {
+        Self {
+            generation: 0,
+            asid: 0,
+        }
+    }
+    pub fn generation(&self) -> usize {
+        self.generation
+    }
+    pub fn asid(&self) -> usize {
+        self.asid
+    }
+}
+
+/// ASID分配器：一代之内依次分配，用完时进入下一代并刷新所有ASID，
+/// 上一代分配的ASID在下一次切换时重新分配。ASID 0不参与分配。
+/// 进入下一代时正在使用的ASID在刷新之后还会有表项，这一代不再分配给别的地址空间
+pub struct AsidAllocator<A> {
+    generation: usize,
+    next: usize,
+    // 最近一次切换使用的ASID
+    active: Asid,
+    // 进入这一代时正在使用的ASID，原来的地址空间切换回来时继续使用
+    reserved: Asid,
+    phantom: PhantomData<A>,
+}
+
+impl<A: Arch> AsidAllocator<A> {
+    pub fn new() -> Self {
+        Self {
+            generation: 1,
+            next: 1,
+            active: Asid::new(),
+            reserved: Asid::new(),
+            phantom: PhantomData,
+        }
+    }
+    pub fn generation(&self) -> usize {
+        self.generation
+    }
+    pub fn is_current(&self, asid: Asid) -> bool {
+        asid.generation == self.generation
+    }
+
+    /// 分配一个ASID，新分配的ASID在TLB中没有表项。
+    /// 不支持ASID时总是返回ASID 0
+    pub unsafe fn allocate(&mut self) -> Asid {
+        if A::ASID_COUNT <= 1 {
+            return Asid {
+                generation: self.generation,
+                asid: 0,
+            };
+        }
+        loop {
+            if self.next >= A::ASID_COUNT {
+                self.generation += 1;
+                self.next = 1;
+                self.reserved = self.active;
+                A::invalid_all_asids();
+            }
+            let asid = Asid {
+                generation: self.generation,
+                asid: self.next,
+            };
+            self.next += 1;
+            // 只有一个ASID时无法保留
+            if asid.asid != self.reserved.asid || A::ASID_COUNT <= 2 {
+                return asid;
+            }
+        }
+    }
+
+    /// 切换到`table`，`asid`过期时重新分配，返回是否重新分配。
+    /// ASID没有过期时不刷新TLB，修改页表后需要自己刷新
+    pub unsafe fn switch(&mut self, table: PhysicalAddress, asid: &mut Asid) -> bool {
+        if *asid == self.reserved && asid.asid != 0 {
+            asid.generation = self.generation;
+        }
+        let renewed = !self.is_current(*asid);
+        if renewed {
+            *asid = self.allocate();
+        }
+        self.active = *asid;
+        if asid.asid == 0 {
+            A::set_table(table);
+        } else {
+            A::set_table_asid(table, asid.asid);
+        }
+        renewed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Asid, AsidAllocator};
+    use crate::{emulate_test, Arch, EmulateArch, FrameAllocator, PageMapper, VirtualAddress};
+
+    #[test]
+    fn asid_switch() {
+        unsafe {
+            let mut allocator = emulate_test();
+            let kernel = PageMapper::<EmulateArch, _>::current(&mut allocator)
+                .table()
+                .phys();
+            let user = VirtualAddress::new(0x1000);
+            let flags = EmulateArch::ENTRY_FLAG_USER
+                | EmulateArch::ENTRY_FLAG_WRITABLE
+                | EmulateArch::ENTRY_FLAG_NO_EXEC;
+            // 两个共享内核部分的地址空间，在同一个地址写入不同的值
+            let mut tables = Vec::new();
+            for value in 1..=2usize {
+                let mut mapper =
+                    PageMapper::<EmulateArch, _>::create_from(kernel, &mut allocator).unwrap();
+                let table = mapper.table();
+                mapper.map(user, flags).unwrap().ignore();
+                let (phys, _) = mapper.translate(user).unwrap();
+                EmulateArch::write::<usize>(EmulateArch::phys_to_virt(phys), value);
+                tables.push((table.phys(), phys));
+            }
+            let (table_a, _) = tables[0];
+            let (table_b, frame_b) = tables[1];
+
+            let mut asids = AsidAllocator::<EmulateArch>::new();
+            let mut asid_a = Asid::new();
+            let mut asid_b = Asid::new();
+            assert!(asids.switch(table_a, &mut asid_a));
+            assert_eq!(
+                (EmulateArch::asid(), EmulateArch::read::<usize>(user)),
+                (1, 1)
+            );
+            assert!(asids.switch(table_b, &mut asid_b));
+            assert_eq!(
+                (EmulateArch::asid(), EmulateArch::read::<usize>(user)),
+                (2, 2)
+            );
+
+            // 在B中修改A的页表，切换回A时没有刷新，仍然使用TLB中的旧表项
+            let mut mapper = PageMapper::<EmulateArch, _>::new(table_a, &mut allocator);
+            mapper.map_phys(user, frame_b, flags).unwrap().ignore();
+            assert!(!asids.switch(table_a, &mut asid_a));
+            assert_eq!(EmulateArch::read::<usize>(user), 1);
+            EmulateArch::invalid_asid(asid_a.asid());
+            assert_eq!(EmulateArch::read::<usize>(user), 2);
+
+            // 用完所有ASID后进入下一代，TLB被全部刷新
+            let frame = mapper.allocator_mut().allocate_one().unwrap();
+            EmulateArch::write::<usize>(EmulateArch::phys_to_virt(frame), 3);
+            mapper.map_phys(user, frame, flags).unwrap().ignore();
+            while asids.generation() == 1 {
+                asids.allocate();
+            }
+            assert_eq!(EmulateArch::read::<usize>(user), 3);
+            assert!(!asids.is_current(asid_a));
+            // A在进入下一代时正在使用，切换回来时继续使用原来的ASID
+            assert!(!asids.switch(table_a, &mut asid_a));
+            assert_eq!((asid_a.generation(), asid_a.asid()), (2, 1));
+
+            // 刷新之后A继续运行，ASID 1又有了A的表项，新的一代不能把它分配给B
+            while asids.generation() == 2 {
+                asid_b = asids.allocate();
+            }
+            assert_eq!((asid_b.generation(), asid_b.asid()), (3, 2));
+            assert_eq!(EmulateArch::read::<usize>(user), 3);
+            assert!(!asids.switch(table_b, &mut asid_b));
+            assert_eq!(EmulateArch::read::<usize>(user), 2);
+            assert!(!asids.switch(table_a, &mut asid_a));
+            assert_eq!((asid_a.generation(), asid_a.asid()), (3, 1));
+            assert_eq!(EmulateArch::read::<usize>(user), 3);
+        }
+    }
+}
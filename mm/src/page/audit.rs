@@ -0,0 +1,148 @@
+use crate::{Arch, PageLeaf, PageTable, PageWalker};
+
+/// 页表安全检查发现的问题
+#[derive(Debug, Clone, Copy)]
+pub enum AuditViolation<A> {
+    /// 可写并且可执行（可写的表项没有`ENTRY_FLAG_NO_EXEC`）
+    WritableExecutable(PageLeaf<A>),
+    /// 内核空间（`PHYS_OFFSET`以上）中用户可以访问的映射
+    UserInKernel(PageLeaf<A>),
+    /// 叶子表项有用户位，但中间表项没有，用户实际无法访问
+    UserIntermediate(PageLeaf<A>),
+    /// 同一个页框的两个映射，一个可写，另一个可执行
+    ConflictingAlias(PageLeaf<A>, PageLeaf<A>),
+    /// 别名缓冲区太小，之后的叶子没有做别名检查
+    AliasOverflow,
+}
+
+/// 遍历页表，报告违反安全策略的映射，返回问题数量。
+/// `aliases`用来检查别名，长度不够时报告`AliasOverflow`，传入空切片可以跳过别名检查
+pub unsafe fn audit<A: Arch, F: FnMut(AuditViolation<A>)>(
+    table: PageTable<A>,
+    aliases: &mut [Option<PageLeaf<A>>],
+    mut report: F,
+) -> usize {
+    let mut count = 0;
+    let mut aliases_len = 0;
+    for leaf in PageWalker::new(table) {
+        let flags = leaf.flags();
+        let writable = flags & A::ENTRY_FLAG_WRITABLE != 0;
+        let executable = flags & A::ENTRY_FLAG_NO_EXEC == 0;
+        if writable && executable {
+            report(AuditViolation::WritableExecutable(leaf));
+            count += 1;
+        }
+        if flags & A::ENTRY_FLAG_USER != 0 && leaf.virt().data() >= A::PHYS_OFFSET {
+            report(AuditViolation::UserInKernel(leaf));
+            count += 1;
+        }
+        if leaf.entry().flags() & A::ENTRY_FLAG_USER != 0 && flags & A::ENTRY_FLAG_USER == 0 {
+            report(AuditViolation::UserIntermediate(leaf));
+            count += 1;
+        }
+        if aliases_len < aliases.len() {
+            aliases[aliases_len] = Some(leaf);
+            aliases_len += 1;
+        } else if !aliases.is_empty() && aliases_len == aliases.len() {
+            report(AuditViolation::AliasOverflow);
+            count += 1;
+            aliases_len += 1;
+        }
+    }
+
+    let aliases_len = aliases_len.min(aliases.len());
+    let aliases = &mut aliases[..aliases_len];
+    aliases.sort_unstable_by_key(|leaf| leaf.map(|leaf| leaf.phys()));
+    // 按物理地址重叠分组，组内两两检查
+    let mut group_start = 0;
+    let mut group_end = None;
+    for i in 0..=aliases.len() {
+        let leaf = aliases.get(i).and_then(|leaf| *leaf);
+        if let (Some(leaf), Some(end)) = (leaf, group_end) {
+            if leaf.phys() < end {
+                if leaf.phys().add(leaf.size()) > end {
+                    group_end = Some(leaf.phys().add(leaf.size()));
+                }
+                continue;
+            }
+        }
+        for a in group_start..i {
+            for b in a + 1..i {
+                let (a, b) = (aliases[a].unwrap(), aliases[b].unwrap());
+                if a.phys().add(a.size()) <= b.phys() || b.phys().add(b.size()) <= a.phys() {
+                    continue;
+                }
+                let writable = |leaf: PageLeaf<A>| leaf.flags() & A::ENTRY_FLAG_WRITABLE != 0;
+                let executable = |leaf: PageLeaf<A>| leaf.flags() & A::ENTRY_FLAG_NO_EXEC == 0;
+                if (writable(a) && executable(b)) || (writable(b) && executable(a)) {
+                    report(AuditViolation::ConflictingAlias(a, b));
+                    count += 1;
+                }
+            }
+        }
+        group_start = i;
+        group_end = leaf.map(|leaf| leaf.phys().add(leaf.size()));
+    }
+    count
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{audit, AuditViolation};
+    use crate::{
+        emulate_test, Arch, EmulateArch, PageEntry, PageMapper, PhysicalAddress, VirtualAddress,
+    };
+
+    #[test]
+    fn violations() {
+        unsafe {
+            let mut allocator = emulate_test();
+            let mut mapper = PageMapper::<EmulateArch, _>::current(&mut allocator);
+            let user = EmulateArch::ENTRY_FLAG_USER;
+            let writable = EmulateArch::ENTRY_FLAG_WRITABLE;
+            let no_exec = EmulateArch::ENTRY_FLAG_NO_EXEC;
+            // 可写可执行
+            mapper
+                .map(VirtualAddress::new(0x1000), writable)
+                .unwrap()
+                .ignore();
+            // 映射器会给中间表项加上用户位，这里手动去掉
+            mapper
+                .map(VirtualAddress::new(0x2000), user | no_exec)
+                .unwrap()
+                .ignore();
+            let mut table = mapper.table();
+            let entry = table.entry(0).unwrap();
+            table.set_entry(0, PageEntry::new(entry.data() & !user));
+            // 同一个页框，一个映射可写，一个映射可执行
+            let phys = PhysicalAddress::new(0x10_0000);
+            mapper
+                .map_phys(VirtualAddress::new(0x3000), phys, writable | no_exec)
+                .unwrap()
+                .ignore();
+            mapper
+                .map_phys(VirtualAddress::new(0x4000), phys, 0)
+                .unwrap()
+                .ignore();
+
+            let mut aliases = vec![None; 32 * 1024];
+            let (mut wx, mut intermediate, mut alias, mut other) = (0, 0, 0, 0);
+            let count = audit(mapper.table(), &mut aliases, |violation| match violation {
+                AuditViolation::WritableExecutable(leaf) => {
+                    assert_eq!(leaf.virt(), VirtualAddress::new(0x1000));
+                    wx += 1;
+                }
+                AuditViolation::UserIntermediate(leaf) => {
+                    assert_eq!(leaf.virt(), VirtualAddress::new(0x2000));
+                    intermediate += 1;
+                }
+                AuditViolation::ConflictingAlias(_, _) => alias += 1,
+                _ => other += 1,
+            });
+            assert_eq!((wx, intermediate, other), (1, 1, 0));
+            // 0x3000和0x4000，0x4000和直接映射，0x1000和直接映射
+            assert_eq!(alias, 3);
+            assert_eq!(count, 5);
+        }
+    }
+}
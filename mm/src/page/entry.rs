@@ -31,4 +31,37 @@ impl<A: Arch> PageEntry<A> {
     pub fn present(&self) -> bool {
         self.data & A::ENTRY_FLAG_PRESENT != 0
     }
+    #[inline(always)]
+    pub fn accessed(&self) -> bool {
+        self.data & A::ENTRY_FLAG_ACCESSED != 0
+    }
+    #[inline(always)]
+    pub fn dirty(&self) -> bool {
+        self.data & A::ENTRY_FLAG_DIRTY != 0
+    }
+    #[inline(always)]
+    pub fn cow(&self) -> bool {
+        self.data & A::ENTRY_FLAG_COW != 0
+    }
+    /// 换出到交换设备的页，地址位保存槽位，其余标志保留换出之前的权限
+    #[inline(always)]
+    pub fn swap(slot: usize, flags: usize) -> Self {
+        Self::new(
+            ((slot << A::PAGE_SHIFT) & A::ENTRY_ADDRESS_MASK)
+                | (flags & A::ENTRY_FLAGS_MASK & !A::ENTRY_FLAG_PRESENT)
+                | A::ENTRY_FLAG_SWAP,
+        )
+    }
+    #[inline(always)]
+    pub fn is_swap(&self) -> bool {
+        !self.present() && self.data & A::ENTRY_FLAG_SWAP != 0
+    }
+    #[inline(always)]
+    pub fn swap_slot(&self) -> Option<usize> {
+        if self.is_swap() {
+            Some(self.address().data() >> A::PAGE_SHIFT)
+        } else {
+            None
+        }
+    }
 }
@@ -0,0 +1,355 @@
+use crate::{
+    Arch, FaultAccess, FaultInfo, FrameAllocator, Page, PageFlush, PageMapper, PageRange,
+    StackAllocator, VmAllocator,
+};
+
+/// 按需分配清零页的区域（堆、用户栈等），第一次访问时才分配页框
+#[derive(Clone, Copy, Debug)]
+pub struct AnonymousRegion<A> {
+    pub pages: PageRange<A>,
+    /// 映射时使用的标志，也决定了允许的访问
+    pub flags: usize,
+}
+
+impl<A: Arch> AnonymousRegion<A> {
+    /// 区域的标志是否允许这次访问
+    pub fn allows(&self, info: &FaultInfo) -> bool {
+        flags_allow::<A>(self.flags, info)
+    }
+}
+
+/// 表项标志是否允许这次访问
+fn flags_allow<A: Arch>(flags: usize, info: &FaultInfo) -> bool {
+    let user = !info.user || flags & A::ENTRY_FLAG_USER != 0;
+    let access = match info.access {
+        FaultAccess::Read => true,
+        FaultAccess::Write => flags & A::ENTRY_FLAG_WRITABLE != 0,
+        FaultAccess::Execute => flags & A::ENTRY_FLAG_NO_EXEC == 0,
+    };
+    user && access
+}
+
+/// 缺页处理需要知道的地址空间布局
+pub struct FaultRegions<'a, A> {
+    pub anonymous: &'a [AnonymousRegion<A>],
+    /// 栈的保护页
+    pub stacks: Option<&'a StackAllocator<A>>,
+    /// vmalloc分配之间的保护页
+    pub vmalloc: Option<&'a VmAllocator<A>>,
+}
+
+impl<'a, A> Default for FaultRegions<'a, A> {
+    fn default() -> Self {
+        Self {
+            anonymous: &[],
+            stacks: None,
+            vmalloc: None,
+        }
+    }
+}
+
+/// 缺页处理的结果
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FaultAction {
+    /// 映射了清零的页框，可以重新执行
+    DemandZero,
+    /// 复制了写时复制的页，可以重新执行
+    CowBreak,
+    /// 从交换设备换入，可以重新执行
+    SwapIn,
+    /// 表项已经允许这次访问，例如另一个CPU已经处理了同一个缺页，刷新TLB后重新执行
+    Spurious,
+    /// 访问了保护页，一般是栈溢出或者越界
+    GuardPage,
+    /// 合法的访问但是没有内存了
+    OutOfMemory,
+    /// 非法访问，用户态应该终止进程，内核态是bug
+    Segfault,
+}
+
+/// 把缺页分派到对应的处理：按需分配、写时复制、保护页或者非法访问，
+/// 处理之后已经刷新了TLB。换出的页由`Swap::handle_fault`处理
+pub unsafe fn handle_fault<A: Arch, F: FrameAllocator>(
+    mapper: &mut PageMapper<A, F>,
+    regions: &FaultRegions<A>,
+    info: FaultInfo,
+) -> FaultAction {
+    if info.reserved {
+        return FaultAction::Segfault;
+    }
+    // 中间表项也会限制权限，只看叶子表项会把它们引起的缺页当作已经处理过
+    let effective = mapper.effective_flags(info.address);
+    if effective.map_or(false, |flags| flags_allow::<A>(flags, &info)) {
+        // 缺页之后表项已经被修改，TLB中可能还是旧的表项
+        PageFlush::<A>::new(info.address).flush();
+        return FaultAction::Spurious;
+    }
+    if info.present {
+        // 只有写时复制的页允许写入只读页
+        let flags = mapper.translate(info.address).map_or(0, |(_, flags)| flags);
+        let cow = flags & A::ENTRY_FLAG_COW != 0;
+        let user = effective.unwrap_or(0) & A::ENTRY_FLAG_USER != 0;
+        if info.access != FaultAccess::Write || !cow || (info.user && !user) {
+            return FaultAction::Segfault;
+        }
+        return match mapper.break_cow(info.address) {
+            Some(flush) => {
+                flush.flush();
+                FaultAction::CowBreak
+            }
+            None => FaultAction::OutOfMemory,
+        };
+    }
+    let stack_guard = regions
+        .stacks
+        .map_or(false, |stacks| stacks.guard_hit(info.address).is_some());
+    let vmalloc_guard = regions
+        .vmalloc
+        .map_or(false, |vmalloc| vmalloc.is_guard(info.address));
+    if stack_guard || vmalloc_guard {
+        return FaultAction::GuardPage;
+    }
+    let region = regions
+        .anonymous
+        .iter()
+        .find(|region| region.pages.contains(info.address));
+    match region {
+        Some(region) if region.allows(&info) => {
+            // 交换表项等不存在但非空的表项不能直接覆盖
+            if mapper
+                .leaf_entry(info.address)
+                .map_or(false, |entry| entry.data() != 0)
+            {
+                return FaultAction::Segfault;
+            }
+            let page = Page::<A>::containing(info.address);
+            match mapper.map(page.start(), region.flags) {
+                Some(flush) => {
+                    flush.flush();
+                    FaultAction::DemandZero
+                }
+                None => FaultAction::OutOfMemory,
+            }
+        }
+        _ => FaultAction::Segfault,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{handle_fault, AnonymousRegion, FaultAction, FaultRegions};
+    use crate::{
+        emulate_test, Arch, EmulateArch, EmulateFault, FaultInfo, ForkMode, FrameAllocator,
+        PageEntry, PageMapper, PageRange, Privilege, StackAllocator, VirtualAddress,
+    };
+
+    fn info<T>(result: Result<T, EmulateFault>) -> FaultInfo {
+        match result {
+            Ok(_) => panic!("expected a page fault"),
+            Err(fault) => fault.info(),
+        }
+    }
+
+    #[test]
+    fn dispatch() {
+        unsafe {
+            let mut allocator = emulate_test();
+            let mut mapper = PageMapper::<EmulateArch, _>::current(&mut allocator);
+            let user = EmulateArch::ENTRY_FLAG_USER | EmulateArch::ENTRY_FLAG_NO_EXEC;
+            let heap = VirtualAddress::new(0x10_0000);
+            let rodata = VirtualAddress::new(0x20_0000);
+            let kernel = VirtualAddress::new(0x30_0000);
+            let anonymous = [
+                AnonymousRegion {
+                    pages: PageRange::covering(heap, 4 * EmulateArch::PAGE_SIZE),
+                    flags: user | EmulateArch::ENTRY_FLAG_WRITABLE,
+                },
+                AnonymousRegion {
+                    pages: PageRange::covering(rodata, EmulateArch::PAGE_SIZE),
+                    flags: user,
+                },
+                AnonymousRegion {
+                    pages: PageRange::covering(kernel, EmulateArch::PAGE_SIZE),
+                    flags: EmulateArch::ENTRY_FLAG_WRITABLE,
+                },
+            ];
+            let mut stacks = StackAllocator::<EmulateArch>::new(
+                VirtualAddress::new(0xFFFF_C000_0000_0000),
+                0x10_0000,
+                0x4000,
+            );
+            let (stack, flush_all) = stacks
+                .allocate(&mut mapper, EmulateArch::ENTRY_FLAG_WRITABLE)
+                .unwrap();
+            flush_all.flush();
+            let regions = FaultRegions {
+                anonymous: &anonymous,
+                stacks: Some(&stacks),
+                ..FaultRegions::default()
+            };
+
+            // 第一次写入时分配清零页
+            let fault = info(EmulateArch::try_write(heap.add(8), 1usize));
+            assert_eq!(
+                handle_fault(&mut mapper, &regions, fault),
+                FaultAction::DemandZero
+            );
+            EmulateArch::write::<usize>(heap.add(8), 1);
+            assert_eq!(EmulateArch::read::<usize>(heap), 0);
+
+            // 只读区域可以读，写入已经映射的只读页是非法访问
+            let fault = info(EmulateArch::try_read::<usize>(rodata));
+            assert_eq!(
+                handle_fault(&mut mapper, &regions, fault),
+                FaultAction::DemandZero
+            );
+            let fault = info(EmulateArch::try_write(rodata, 1usize));
+            assert!(fault.present);
+            assert_eq!(
+                handle_fault(&mut mapper, &regions, fault),
+                FaultAction::Segfault
+            );
+
+            // 另一个CPU已经把表项改为可写，这里的TLB中还是只读的表项
+            let entry = mapper.leaf_entry(rodata).unwrap();
+            let writable = PageEntry::new(entry.data() | EmulateArch::ENTRY_FLAG_WRITABLE);
+            mapper.replace_entry(rodata, writable).unwrap().1.ignore();
+            let fault = info(EmulateArch::try_write(rodata, 1usize));
+            assert_eq!(
+                handle_fault(&mut mapper, &regions, fault),
+                FaultAction::Spurious
+            );
+            EmulateArch::write::<usize>(rodata, 1);
+            // 同一个缺页被处理两次，第二次不能当作非法访问
+            let fault = info(EmulateArch::try_read::<usize>(
+                heap.add(2 * EmulateArch::PAGE_SIZE),
+            ));
+            assert_eq!(
+                handle_fault(&mut mapper, &regions, fault),
+                FaultAction::DemandZero
+            );
+            assert_eq!(
+                handle_fault(&mut mapper, &regions, fault),
+                FaultAction::Spurious
+            );
+
+            let fault = info(EmulateArch::try_fetch(heap.add(EmulateArch::PAGE_SIZE)));
+            assert_eq!(
+                handle_fault(&mut mapper, &regions, fault),
+                FaultAction::Segfault
+            );
+            let fault = info(EmulateArch::try_read::<usize>(VirtualAddress::new(
+                0x40_0000,
+            )));
+            assert_eq!(
+                handle_fault(&mut mapper, &regions, fault),
+                FaultAction::Segfault
+            );
+            let fault = info(EmulateArch::try_write(stack.guard(), 1usize));
+            assert_eq!(
+                handle_fault(&mut mapper, &regions, fault),
+                FaultAction::GuardPage
+            );
+
+            // 用户态不能访问内核区域，内核态可以
+            EmulateArch::set_privilege(Privilege::User);
+            let fault = info(EmulateArch::try_read::<usize>(kernel));
+            // 缺页处理在内核态执行
+            EmulateArch::set_privilege(Privilege::Supervisor);
+            assert_eq!(
+                handle_fault(&mut mapper, &regions, fault),
+                FaultAction::Segfault
+            );
+            assert_eq!(
+                handle_fault(
+                    &mut mapper,
+                    &regions,
+                    FaultInfo {
+                        user: false,
+                        ..fault
+                    }
+                ),
+                FaultAction::DemandZero
+            );
+            assert_eq!(
+                handle_fault(
+                    &mut mapper,
+                    &regions,
+                    FaultInfo {
+                        reserved: true,
+                        ..fault
+                    }
+                ),
+                FaultAction::Segfault
+            );
+
+            // 写时复制：父进程写入时复制，子进程写入时只剩一个引用，直接改为可写
+            let parent = mapper.table().phys();
+            let (child, flush_all) = mapper.fork(ForkMode::CopyOnWrite).unwrap();
+            flush_all.flush();
+            let (frame, _) = mapper.translate(heap).unwrap();
+            let fault = info(EmulateArch::try_write(heap.add(8), 2usize));
+            assert_eq!(
+                handle_fault(&mut mapper, &regions, fault),
+                FaultAction::CowBreak
+            );
+            EmulateArch::write::<usize>(heap.add(8), 2);
+            assert_ne!(mapper.translate(heap).unwrap().0, frame);
+            assert_eq!(mapper.allocator().ref_count(frame), Some(1));
+
+            EmulateArch::set_table(child);
+            let mut child_mapper = PageMapper::<EmulateArch, _>::new(child, mapper.allocator_mut());
+            let fault = info(EmulateArch::try_write(heap.add(8), 3usize));
+            assert_eq!(
+                handle_fault(&mut child_mapper, &regions, fault),
+                FaultAction::CowBreak
+            );
+            assert_eq!(child_mapper.translate(heap).unwrap().0, frame);
+            assert_eq!(EmulateArch::read::<usize>(heap.add(8)), 1);
+            EmulateArch::set_table(parent);
+            assert_eq!(EmulateArch::read::<usize>(heap.add(8)), 2);
+        }
+    }
+
+    #[test]
+    fn intermediate_flags() {
+        unsafe {
+            let mut allocator = emulate_test();
+            let mut mapper = PageMapper::<EmulateArch, _>::current(&mut allocator);
+            let regions = FaultRegions::default();
+            let user = VirtualAddress::new(0x50_0000);
+            let flags = EmulateArch::ENTRY_FLAG_USER
+                | EmulateArch::ENTRY_FLAG_WRITABLE
+                | EmulateArch::ENTRY_FLAG_NO_EXEC;
+            mapper.map(user, flags).unwrap().flush();
+            let mut pd = mapper.table().next(0).unwrap().next(0).unwrap();
+            let i = pd.index_of(user).unwrap();
+            let entry = pd.entry(i).unwrap();
+
+            // 叶子表项允许访问，但是中间表项没有用户位或者只读，不能当作已经处理过
+            for &cleared in [
+                EmulateArch::ENTRY_FLAG_USER,
+                EmulateArch::ENTRY_FLAG_WRITABLE,
+            ]
+            .iter()
+            {
+                pd.set_entry(i, PageEntry::new(entry.data() & !cleared));
+                EmulateArch::invalid_data_all();
+                EmulateArch::set_privilege(Privilege::User);
+                let fault = info(EmulateArch::try_write(user, 1usize));
+                EmulateArch::set_privilege(Privilege::Supervisor);
+                assert!(fault.present);
+                assert_eq!(
+                    handle_fault(&mut mapper, &regions, fault),
+                    FaultAction::Segfault
+                );
+            }
+
+            pd.set_entry(i, entry);
+            EmulateArch::invalid_data_all();
+            EmulateArch::set_privilege(Privilege::User);
+            EmulateArch::write::<usize>(user, 1);
+            EmulateArch::set_privilege(Privilege::Supervisor);
+        }
+    }
+}
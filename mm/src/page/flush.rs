@@ -40,6 +40,12 @@ impl<A: Arch> PageFlushAll<A> {
             flush.ignore();
         }
     }
+    /// 合并另一个`PageFlushAll`
+    pub fn consume_all(&self, other: PageFlushAll<A>) {
+        unsafe {
+            other.ignore();
+        }
+    }
     pub fn flush(self) {
         unsafe {
             A::invalid_data_all();
@@ -0,0 +1,400 @@
+use core::ops::Range;
+
+use crate::{
+    Arch, FrameAllocator, FrameCount, PageEntry, PageFlush, PageFlushAll, PageMapper, PageTable,
+    PhysicalAddress, VirtualAddress,
+};
+
+/// 复制地址空间的方式
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ForkMode {
+    /// 立即复制每个页框
+    Copy,
+    /// 共享页框，可写的页在两边都改为只读并设置`ENTRY_FLAG_COW`，写入时再复制
+    CopyOnWrite,
+}
+
+/// 第`level`级表项映射的页数
+fn level_pages<A: Arch>(level: usize) -> usize {
+    1 << (level * A::PAGE_ENTRY_SHIFT)
+}
+
+/// 复制`size`字节的页框内容
+unsafe fn copy_frames<A: Arch>(dst: PhysicalAddress, src: PhysicalAddress, size: usize) {
+    let word = core::mem::size_of::<usize>();
+    let (dst, src) = (A::phys_to_virt(dst), A::phys_to_virt(src));
+    for offset in (0..size).step_by(word) {
+        A::write::<usize>(dst.add(offset), A::read::<usize>(src.add(offset)));
+    }
+}
+
+/// 把第`level`级的大页表项拆成下一级的第`i`个表项，地址和标志不变，
+/// 拆到4K页时内存类型从大页的编码转换为普通页的编码
+fn split_entry<A: Arch>(entry: PageEntry<A>, level: usize, i: usize) -> PageEntry<A> {
+    let offset = (i * level_pages::<A>(level - 1)) << A::PAGE_SHIFT;
+    if level > 1 {
+        return PageEntry::new(entry.data() + offset);
+    }
+    let base = entry
+        .address()
+        .align_down(level_pages::<A>(level) << A::PAGE_SHIFT);
+    let memory_type = A::memory_type(entry.data(), true);
+    let flags = entry.flags() & !A::ENTRY_FLAG_HUGE & !A::memory_type_flags(memory_type, true);
+    PageEntry::new(base.add(offset).data() | flags | A::memory_type_flags(memory_type, false))
+}
+
+impl<'f, A: Arch, F: FrameAllocator> PageMapper<'f, A, F> {
+    /// 把用户部分的映射复制到新的顶级页表，内核部分和当前页表共享（见`create_from`），
+    /// 返回新页表的地址。写时复制会把当前页表中可写的页改为只读，需要刷新返回的`PageFlushAll`。
+    /// 有换出的页、分配器不支持共享或者内存不足时返回`None`，已经复制的部分会被释放
+    pub unsafe fn fork(&mut self, mode: ForkMode) -> Option<(PhysicalAddress, PageFlushAll<A>)> {
+        let mut parent = self.table();
+        let child_phys = PageMapper::<A, F>::create_from(parent.phys(), self.allocator_mut())?
+            .table()
+            .phys();
+        let mut child = PageTable::<A>::new(parent.base(), child_phys, parent.level());
+        let flush_all = PageFlushAll::new();
+        let user = 0..PageTable::<A>::kernel_entries().start;
+        if self
+            .fork_table(&mut parent, &mut child, user, mode, &flush_all)
+            .is_none()
+        {
+            PageMapper::<A, F>::new(child_phys, self.allocator_mut())
+                .unmap_user()
+                .ignore();
+            self.allocator_mut().free_one(child_phys);
+            flush_all.flush();
+            return None;
+        }
+        Some((child_phys, flush_all))
+    }
+
+    unsafe fn fork_table(
+        &mut self,
+        parent: &mut PageTable<A>,
+        child: &mut PageTable<A>,
+        entries: Range<usize>,
+        mode: ForkMode,
+        flush_all: &PageFlushAll<A>,
+    ) -> Option<()> {
+        for i in entries {
+            let entry = parent.entry(i)?;
+            if !entry.present() {
+                // 交换表项需要先换入，不能和子进程共享槽位
+                if entry.data() != 0 {
+                    return None;
+                }
+                continue;
+            }
+            if parent.level() > 0 && entry.flags() & A::ENTRY_FLAG_HUGE == 0 {
+                let next_phys = self.allocator_mut().allocate_one()?;
+                child.set_entry(i, PageEntry::new(next_phys.data() | entry.flags()));
+                let mut parent_next = parent.next(i)?;
+                let mut child_next = child.next(i)?;
+                self.fork_table(
+                    &mut parent_next,
+                    &mut child_next,
+                    0..A::PAGE_ENTRIES,
+                    mode,
+                    flush_all,
+                )?;
+                continue;
+            }
+            match mode {
+                ForkMode::Copy => self.copy_leaf(entry, parent.level(), child, i)?,
+                ForkMode::CopyOnWrite => {
+                    let pages = level_pages::<A>(parent.level());
+                    let base = entry.address().align_down(pages << A::PAGE_SHIFT);
+                    self.allocator_mut().share(base, FrameCount::new(pages))?;
+                    let mut data = entry.data();
+                    if data & A::ENTRY_FLAG_WRITABLE != 0 {
+                        data = (data & !A::ENTRY_FLAG_WRITABLE) | A::ENTRY_FLAG_COW;
+                        parent.set_entry(i, PageEntry::new(data));
+                        flush_all.consume(PageFlush::new(A::canonicalize(parent.entry_base(i)?)));
+                    }
+                    child.set_entry(i, PageEntry::new(data));
+                }
+            }
+        }
+        Some(())
+    }
+
+    /// 复制第`level`级的叶子表项到`child`的第`i`项。分配到的大页没有对齐时，
+    /// 在子进程中拆成下一级的表项分别复制
+    unsafe fn copy_leaf(
+        &mut self,
+        entry: PageEntry<A>,
+        level: usize,
+        child: &mut PageTable<A>,
+        i: usize,
+    ) -> Option<()> {
+        let pages = level_pages::<A>(level);
+        let size = pages << A::PAGE_SHIFT;
+        let base = entry.address().align_down(size);
+        let count = FrameCount::new(pages);
+        if let Some(frame) = self.allocator_mut().allocate(count) {
+            if frame.data() & (size - 1) == 0 {
+                copy_frames::<A>(frame, base, size);
+                child.set_entry(i, PageEntry::new(entry.data() - base.data() + frame.data()));
+                return Some(());
+            }
+            self.allocator_mut().free(frame, count);
+        }
+        if level == 0 {
+            return None;
+        }
+        let next_phys = self.allocator_mut().allocate_one()?;
+        let flags =
+            A::ENTRY_FLAG_PRESENT | A::ENTRY_FLAG_WRITABLE | (entry.flags() & A::ENTRY_FLAG_USER);
+        child.set_entry(i, PageEntry::new(next_phys.data() | flags));
+        let mut next = child.next(i)?;
+        for j in 0..A::PAGE_ENTRIES {
+            self.copy_leaf(split_entry(entry, level, j), level - 1, &mut next, j)?;
+        }
+        Some(())
+    }
+
+    /// 写时复制的页被写入时调用：页框只剩一个引用时直接改为可写，
+    /// 否则复制一份并释放原来页框的引用。不是写时复制的页或者内存不足时返回`None`
+    pub unsafe fn break_cow(&mut self, virt: VirtualAddress) -> Option<PageFlush<A>> {
+        let (mut table, i) = self.leaf_slot(virt)?;
+        let entry = table.entry(i)?;
+        if !entry.present() || !entry.cow() {
+            return None;
+        }
+        let level = table.level();
+        let pages = level_pages::<A>(level);
+        let base = entry.address().align_down(pages << A::PAGE_SHIFT);
+        let writable = PageEntry::new((entry.data() & !A::ENTRY_FLAG_COW) | A::ENTRY_FLAG_WRITABLE);
+        if self.allocator().ref_count(base) == Some(1) {
+            table.set_entry(i, writable);
+        } else if self.copy_leaf(writable, level, &mut table, i).is_some() {
+            self.allocator_mut().free(base, FrameCount::new(pages));
+        } else {
+            // 大页拆开复制到一半失败，释放已经复制的部分，恢复原来的表项
+            if table.entry(i)?.data() != entry.data() {
+                self.free_entry(&mut table, i);
+            }
+            table.set_entry(i, entry);
+            return None;
+        }
+        Some(PageFlush::new(virt))
+    }
+
+    /// 解除用户部分的所有映射，释放页框和中间页表（进程退出或者`fork`失败时）。
+    /// 交换表项会被直接清除，需要先用`Swap::discard`释放槽位
+    pub unsafe fn unmap_user(&mut self) -> PageFlushAll<A> {
+        let mut table = self.table();
+        for i in 0..PageTable::<A>::kernel_entries().start {
+            self.free_entry(&mut table, i);
+        }
+        PageFlushAll::new()
+    }
+
+    unsafe fn free_entry(&mut self, table: &mut PageTable<A>, i: usize) {
+        let entry = match table.entry(i) {
+            Some(entry) => entry,
+            None => return,
+        };
+        if entry.present() {
+            if table.level() > 0 && entry.flags() & A::ENTRY_FLAG_HUGE == 0 {
+                if let Some(mut next) = table.next(i) {
+                    for j in 0..A::PAGE_ENTRIES {
+                        self.free_entry(&mut next, j);
+                    }
+                }
+                self.allocator_mut().free_one(entry.address());
+            } else {
+                let pages = level_pages::<A>(table.level());
+                let base = entry.address().align_down(pages << A::PAGE_SHIFT);
+                self.allocator_mut().free(base, FrameCount::new(pages));
+            }
+        }
+        table.set_entry(i, PageEntry::new(0));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ForkMode;
+    use crate::{
+        emulate_test, Arch, BuddyAllocator, EmulateArch, FrameAllocator, FrameCount, PageEntry,
+        PageMapper, PhysicalAddress, VirtualAddress, MEGA_BYTE,
+    };
+
+    const SMALL: VirtualAddress = VirtualAddress::new(0x1000);
+    const READ_ONLY: VirtualAddress = VirtualAddress::new(0x2000);
+    const HUGE: VirtualAddress = VirtualAddress::new(0x4020_0000);
+
+    /// 映射一个可写页、一个只读页和一个2MiB的大页，返回大页的页框
+    unsafe fn setup(
+        mapper: &mut PageMapper<EmulateArch, BuddyAllocator<EmulateArch>>,
+    ) -> PhysicalAddress {
+        let flags = EmulateArch::ENTRY_FLAG_USER | EmulateArch::ENTRY_FLAG_NO_EXEC;
+        let writable = flags | EmulateArch::ENTRY_FLAG_WRITABLE;
+        mapper.map(SMALL, writable).unwrap().flush();
+        mapper.map(READ_ONLY, flags).unwrap().flush();
+        // 多分配一些再释放两头，得到对齐的大页页框
+        let pages = EmulateArch::PAGE_ENTRIES;
+        let allocator = mapper.allocator_mut();
+        let block = allocator.allocate(FrameCount::new(2 * pages)).unwrap();
+        let frame = block.align_up(2 * MEGA_BYTE);
+        let head = (frame.data() - block.data()) >> EmulateArch::PAGE_SHIFT;
+        if head > 0 {
+            allocator.free(block, FrameCount::new(head));
+        }
+        allocator.free(frame.add(2 * MEGA_BYTE), FrameCount::new(pages - head));
+        mapper
+            .map(VirtualAddress::new(0x4000_0000), writable)
+            .unwrap()
+            .flush();
+        let mut pd = mapper.table().next(0).unwrap().next(1).unwrap();
+        pd.set_entry(
+            1,
+            PageEntry::new(
+                frame.data()
+                    | writable
+                    | EmulateArch::ENTRY_FLAG_PRESENT
+                    | EmulateArch::ENTRY_FLAG_HUGE,
+            ),
+        );
+        EmulateArch::invalid_data_all();
+        EmulateArch::write::<usize>(SMALL, 1);
+        EmulateArch::write::<usize>(HUGE.add(0x1234 * 8), 2);
+        frame
+    }
+
+    #[test]
+    fn fork_copy() {
+        unsafe {
+            let mut allocator = emulate_test();
+            let mut mapper = PageMapper::<EmulateArch, _>::current(&mut allocator);
+            let parent = mapper.table().phys();
+            setup(&mut mapper);
+            let used = mapper.allocator().usage().used().data();
+
+            // 第二次复制前占住下一个足够大的空闲块的第一页，
+            // 分配到的大页没有对齐，子进程中拆成4K页
+            for &split in [false, true].iter() {
+                let blocker = if split {
+                    let allocator = mapper.allocator_mut();
+                    let pages = EmulateArch::PAGE_ENTRIES;
+                    let block = allocator.allocate(FrameCount::new(pages)).unwrap();
+                    allocator.free(
+                        block.add(EmulateArch::PAGE_SIZE),
+                        FrameCount::new(pages - 1),
+                    );
+                    Some(block)
+                } else {
+                    None
+                };
+                let (child, flush_all) = mapper.fork(ForkMode::Copy).unwrap();
+                flush_all.flush();
+                let entry = mapper.leaf_entry(SMALL).unwrap();
+                assert!(entry.flags() & EmulateArch::ENTRY_FLAG_WRITABLE != 0 && !entry.cow());
+                let child_entry = PageMapper::<EmulateArch, _>::new(child, mapper.allocator_mut())
+                    .leaf_entry(HUGE)
+                    .unwrap();
+                assert_eq!(
+                    child_entry.flags() & EmulateArch::ENTRY_FLAG_HUGE == 0,
+                    split
+                );
+
+                EmulateArch::set_table(child);
+                assert_eq!(EmulateArch::read::<usize>(SMALL), 1);
+                assert_eq!(EmulateArch::read::<usize>(HUGE.add(0x1234 * 8)), 2);
+                EmulateArch::write::<usize>(SMALL, 3);
+                EmulateArch::write::<usize>(HUGE, 4);
+                assert!(EmulateArch::try_write(READ_ONLY, 0usize).is_err());
+                EmulateArch::set_table(parent);
+                assert_eq!(EmulateArch::read::<usize>(SMALL), 1);
+                assert_eq!(EmulateArch::read::<usize>(HUGE), 0);
+
+                // 释放子进程的页表之后没有泄漏
+                PageMapper::<EmulateArch, _>::new(child, mapper.allocator_mut())
+                    .unmap_user()
+                    .flush();
+                mapper.allocator_mut().free_one(child);
+                if let Some(blocker) = blocker {
+                    mapper.allocator_mut().free_one(blocker);
+                }
+                assert_eq!(mapper.allocator().usage().used().data(), used);
+            }
+
+            // 有换出的页时失败，已经复制的部分被释放
+            let swap = PageEntry::swap(1, EmulateArch::ENTRY_FLAG_USER);
+            let (old, flush) = mapper
+                .replace_entry(VirtualAddress::new(0x3000), swap)
+                .unwrap();
+            flush.flush();
+            assert!(mapper.fork(ForkMode::Copy).is_none());
+            assert_eq!(mapper.allocator().usage().used().data(), used);
+            mapper
+                .replace_entry(VirtualAddress::new(0x3000), old)
+                .unwrap()
+                .1
+                .flush();
+        }
+    }
+
+    #[test]
+    fn fork_cow() {
+        unsafe {
+            let mut allocator = emulate_test();
+            let mut mapper = PageMapper::<EmulateArch, _>::current(&mut allocator);
+            let parent = mapper.table().phys();
+            let huge = setup(&mut mapper);
+            let (small, _) = mapper.translate(SMALL).unwrap();
+            let (read_only, _) = mapper.translate(READ_ONLY).unwrap();
+            let used = mapper.allocator().usage().used().data();
+
+            let (child, flush_all) = mapper.fork(ForkMode::CopyOnWrite).unwrap();
+            flush_all.flush();
+            for virt in [SMALL, HUGE].iter() {
+                let entry = mapper.leaf_entry(*virt).unwrap();
+                assert!(entry.cow() && entry.flags() & EmulateArch::ENTRY_FLAG_WRITABLE == 0);
+            }
+            assert!(!mapper.leaf_entry(READ_ONLY).unwrap().cow());
+            for frame in [small, read_only, huge, huge.add(0x1F_F000)].iter() {
+                assert_eq!(mapper.allocator().ref_count(*frame), Some(2));
+            }
+            assert!(EmulateArch::try_write(SMALL, 0usize).is_err());
+
+            EmulateArch::set_table(child);
+            assert_eq!(EmulateArch::read::<usize>(SMALL), 1);
+            assert_eq!(EmulateArch::read::<usize>(HUGE.add(0x1234 * 8)), 2);
+            assert!(EmulateArch::try_write(HUGE, 0usize).is_err());
+            EmulateArch::set_table(parent);
+
+            // 子进程写入时复制一份，父进程之后写入时只剩一个引用，直接改为可写
+            {
+                let mut child_mapper =
+                    PageMapper::<EmulateArch, _>::new(child, mapper.allocator_mut());
+                child_mapper.break_cow(SMALL).unwrap().ignore();
+                assert!(child_mapper.break_cow(READ_ONLY).is_none());
+                assert_ne!(child_mapper.translate(SMALL).unwrap().0, small);
+            }
+            assert_eq!(mapper.allocator().ref_count(small), Some(1));
+            mapper.break_cow(SMALL).unwrap().flush();
+            assert_eq!(mapper.translate(SMALL).unwrap().0, small);
+            EmulateArch::write::<usize>(SMALL, 5);
+            EmulateArch::set_table(child);
+            assert_eq!(EmulateArch::read::<usize>(SMALL), 1);
+            EmulateArch::write::<usize>(SMALL, 6);
+            EmulateArch::set_table(parent);
+            assert_eq!(EmulateArch::read::<usize>(SMALL), 5);
+
+            // 子进程退出后页框只剩一个引用，使用的页数和复制之前相同
+            let shared = mapper.allocator().usage().shared().data();
+            // 两个4K页（只读页和建立页目录时映射的0x4000_0000）和大页
+            assert_eq!(shared, 2 + EmulateArch::PAGE_ENTRIES);
+            PageMapper::<EmulateArch, _>::new(child, mapper.allocator_mut())
+                .unmap_user()
+                .flush();
+            mapper.allocator_mut().free_one(child);
+            assert_eq!(mapper.allocator().ref_count(huge), Some(1));
+            assert_eq!(mapper.allocator().usage().shared().data(), 0);
+            assert_eq!(mapper.allocator().usage().used().data(), used);
+        }
+    }
+}
@@ -1,7 +1,8 @@
 use core::marker::PhantomData;
 
 use crate::{
-    Arch, FrameAllocator, PageEntry, PageFlush, PageTable, PhysicalAddress, VirtualAddress,
+    Arch, FrameAllocator, FrameCount, FrameRange, MemoryType, Page, PageEntry, PageFlush,
+    PageFlushAll, PageRange, PageTable, PhysicalAddress, VirtualAddress,
 };
 
 pub struct PageMapper<'f, A, F> {
@@ -17,34 +18,85 @@ impl<'f, A: Arch, F: FrameAllocator> PageMapper<'f, A, F> {
             phantom: PhantomData,
         }
     }
+    #[track_caller]
     pub unsafe fn create(allocator: &'f mut F) -> Option<Self> {
         let table_addr = allocator.allocate_one()?;
         Some(Self::new(table_addr, allocator))
     }
+    /// 创建新的顶级页表，内核部分的表项和`template`相同，共享下级页表。
+    /// `template`需要先用`preallocate_kernel`分配所有内核表项，
+    /// 这样之后的内核映射只修改共享的下级页表，在每个地址空间中都一致
+    #[track_caller]
+    pub unsafe fn create_from(template: PhysicalAddress, allocator: &'f mut F) -> Option<Self> {
+        let mapper = Self::create(allocator)?;
+        let template = PageTable::<A>::new(VirtualAddress::new(0), template, A::PAGE_LEVELS - 1);
+        let mut table = mapper.table();
+        for i in PageTable::<A>::kernel_entries() {
+            table.set_entry(i, template.entry(i)?);
+        }
+        Some(mapper)
+    }
     pub unsafe fn current(allocator: &'f mut F) -> Self {
         let table_addr = A::table();
         Self::new(table_addr, allocator)
     }
 
+    pub fn allocator(&self) -> &F {
+        self.allocator
+    }
+
+    pub fn allocator_mut(&mut self) -> &mut F {
+        self.allocator
+    }
+
     pub unsafe fn make_current(&mut self) {
         A::set_table(self.table_addr)
     }
 
+    /// 为顶级页表中每个空的内核表项分配下级页表，
+    /// 用作`create_from`的模板之前调用
+    #[track_caller]
+    pub unsafe fn preallocate_kernel(&mut self) -> Option<()> {
+        let mut table = self.table();
+        for i in PageTable::<A>::kernel_entries() {
+            if table.entry(i)?.present() {
+                continue;
+            }
+            let next_phys = self.allocator.allocate_one()?;
+            table.set_entry(
+                i,
+                PageEntry::new(next_phys.data() | A::ENTRY_FLAG_WRITABLE | A::ENTRY_FLAG_PRESENT),
+            );
+        }
+        Some(())
+    }
+
     pub unsafe fn table(&self) -> PageTable<A> {
         PageTable::new(VirtualAddress::new(0), self.table_addr, A::PAGE_LEVELS - 1)
     }
 
+    /// 分配页框映射到`virt`。分配页框和页表的函数都带`#[track_caller]`，
+    /// `TrackAllocator`记录的是调用这些函数的位置
+    #[track_caller]
     pub unsafe fn map(&mut self, virt: VirtualAddress, flags: usize) -> Option<PageFlush<A>> {
         let phys = self.allocator.allocate_one()?;
-        self.map_phys(virt, phys, flags)
+        let flush = self.map_phys(virt, phys, flags);
+        if flush.is_none() {
+            self.allocator.free_one(phys);
+        }
+        flush
     }
 
+    #[track_caller]
     pub unsafe fn map_phys(
         &mut self,
         virt: VirtualAddress,
         phys: PhysicalAddress,
         flags: usize,
     ) -> Option<PageFlush<A>> {
+        if !A::is_canonical(virt) {
+            return None;
+        }
         let entry = PageEntry::<A>::new(phys.data() | flags | A::ENTRY_FLAG_PRESENT);
         let mut table = self.table();
         loop {
@@ -53,15 +105,30 @@ impl<'f, A: Arch, F: FrameAllocator> PageMapper<'f, A, F> {
                 table.set_entry(i, entry);
                 return Some(PageFlush::new(virt));
             } else {
+                // 地址已经在一个大页中，不能把大页当作页表写入
+                if table.entry(i)?.flags() & A::ENTRY_FLAG_HUGE != 0 {
+                    return None;
+                }
                 let next_opt = table.next(i);
+                // 用户页的每一级中间表项都需要用户位
+                let user = flags & A::ENTRY_FLAG_USER;
                 let next = match next_opt {
-                    Some(some) => some,
+                    Some(some) => {
+                        let old = table.entry(i)?;
+                        if old.flags() & user != user {
+                            table.set_entry(i, PageEntry::new(old.data() | user));
+                        }
+                        some
+                    }
                     None => {
                         let next_phys = self.allocator.allocate_one()?;
                         table.set_entry(
                             i,
                             PageEntry::new(
-                                next_phys.data() | A::ENTRY_FLAG_WRITABLE | A::ENTRY_FLAG_PRESENT,
+                                next_phys.data()
+                                    | A::ENTRY_FLAG_WRITABLE
+                                    | A::ENTRY_FLAG_PRESENT
+                                    | user,
                             ),
                         );
                         table.next(i)?
@@ -72,27 +139,381 @@ impl<'f, A: Arch, F: FrameAllocator> PageMapper<'f, A, F> {
         }
     }
 
+    /// 映射物理页并设置内存类型，physmap按照`PhysmapFlags`选择类型
+    #[track_caller]
+    pub unsafe fn map_phys_typed(
+        &mut self,
+        virt: VirtualAddress,
+        phys: PhysicalAddress,
+        flags: usize,
+        memory_type: MemoryType,
+    ) -> Option<PageFlush<A>> {
+        self.map_phys(virt, phys, flags | A::memory_type_flags(memory_type, false))
+    }
+
+    /// 为每一页分配页框并映射，失败时撤销已经建立的映射
+    #[track_caller]
+    pub unsafe fn map_range(
+        &mut self,
+        pages: PageRange<A>,
+        flags: usize,
+    ) -> Option<PageFlushAll<A>> {
+        let flush_all = PageFlushAll::new();
+        for (i, page) in pages.enumerate() {
+            match self.map(page.start(), flags) {
+                Some(flush) => flush_all.consume(flush),
+                None => {
+                    flush_all.consume_all(self.unmap_range(PageRange::new(pages.start(), i)));
+                    flush_all.flush();
+                    return None;
+                }
+            }
+        }
+        Some(flush_all)
+    }
+
+    /// 把连续的页框映射到连续的页，数量必须相同，失败时撤销已经建立的映射
+    #[track_caller]
+    pub unsafe fn map_phys_range(
+        &mut self,
+        pages: PageRange<A>,
+        frames: FrameRange<A>,
+        flags: usize,
+    ) -> Option<PageFlushAll<A>> {
+        if pages.len() != frames.len() {
+            return None;
+        }
+        let flush_all = PageFlushAll::new();
+        for (i, (page, frame)) in pages.zip(frames).enumerate() {
+            match self.map_phys(page.start(), frame.start(), flags) {
+                Some(flush) => flush_all.consume(flush),
+                None => {
+                    for page in PageRange::new(pages.start(), i) {
+                        if let Some((_, flush)) = self.unmap_phys(page.start()) {
+                            flush_all.consume(flush);
+                        }
+                    }
+                    flush_all.flush();
+                    return None;
+                }
+            }
+        }
+        Some(flush_all)
+    }
+
+    /// 查询虚拟地址对应的物理地址和表项标志，支持大页
+    pub unsafe fn translate(&self, virt: VirtualAddress) -> Option<(PhysicalAddress, usize)> {
+        if !A::is_canonical(virt) {
+            return None;
+        }
+        let mut table = self.table();
+        loop {
+            let i = table.index_of(virt)?;
+            let entry = table.entry(i)?;
+            if !entry.present() {
+                return None;
+            }
+            if table.level() == 0 || entry.flags() & A::ENTRY_FLAG_HUGE != 0 {
+                let level_shift = table.level() * A::PAGE_ENTRY_SHIFT + A::PAGE_SHIFT;
+                let offset = virt.data() & ((1 << level_shift) - 1);
+                let base = entry.address().align_down(1 << level_shift);
+                return Some((base.add(offset), entry.flags()));
+            }
+            table = table.next(i)?;
+        }
+    }
+
+    /// 叶子表项结合所有中间表项后的实际权限，和`PageLeaf::flags`相同
+    pub unsafe fn effective_flags(&self, virt: VirtualAddress) -> Option<usize> {
+        if !A::is_canonical(virt) {
+            return None;
+        }
+        let inherited = A::ENTRY_FLAG_WRITABLE | A::ENTRY_FLAG_USER;
+        let mut and_flags = inherited;
+        let mut no_exec = 0;
+        let mut table = self.table();
+        loop {
+            let i = table.index_of(virt)?;
+            let entry = table.entry(i)?;
+            if !entry.present() {
+                return None;
+            }
+            no_exec |= entry.flags() & A::ENTRY_FLAG_NO_EXEC;
+            if table.level() == 0 || entry.flags() & A::ENTRY_FLAG_HUGE != 0 {
+                return Some((entry.flags() & !inherited) | (entry.flags() & and_flags) | no_exec);
+            }
+            and_flags &= entry.flags();
+            table = table.next(i)?;
+        }
+    }
+
+    /// 叶子表项所在的页表和索引，表项本身不一定存在
+    pub(crate) unsafe fn leaf_slot(&self, virt: VirtualAddress) -> Option<(PageTable<A>, usize)> {
+        let mut table = self.table();
+        loop {
+            let i = table.index_of(virt)?;
+            if table.level() == 0 || table.entry(i)?.flags() & A::ENTRY_FLAG_HUGE != 0 {
+                return Some((table, i));
+            }
+            table = table.next(i)?;
+        }
+    }
+
+    /// 读取叶子表项，包括不存在的表项（例如交换表项）
+    pub unsafe fn leaf_entry(&self, virt: VirtualAddress) -> Option<PageEntry<A>> {
+        let (table, i) = self.leaf_slot(virt)?;
+        table.entry(i)
+    }
+
+    /// 直接替换叶子表项，不分配或释放页框，返回原来的表项
+    pub unsafe fn replace_entry(
+        &mut self,
+        virt: VirtualAddress,
+        entry: PageEntry<A>,
+    ) -> Option<(PageEntry<A>, PageFlush<A>)> {
+        let (mut table, i) = self.leaf_slot(virt)?;
+        let old = table.entry(i)?;
+        table.set_entry(i, entry);
+        Some((old, PageFlush::new(virt)))
+    }
+
+    /// 清除表项中的`flags`（一般是访问位和脏位），返回清除之前的表项
+    pub unsafe fn clear_flags(
+        &mut self,
+        virt: VirtualAddress,
+        flags: usize,
+    ) -> Option<(PageEntry<A>, PageFlush<A>)> {
+        let (mut table, i) = self.leaf_slot(virt)?;
+        let entry = table.entry(i)?;
+        if !entry.present() {
+            return None;
+        }
+        table.set_entry(i, PageEntry::new(entry.data() & !flags));
+        Some((entry, PageFlush::new(virt)))
+    }
+
+    /// 扫描一段地址，表项设置了`flags`中任意一位时，
+    /// 用清除之前的表项调用`f`，然后清除这些位
+    pub unsafe fn harvest<H: FnMut(Page<A>, PageEntry<A>)>(
+        &mut self,
+        pages: PageRange<A>,
+        flags: usize,
+        mut f: H,
+    ) -> PageFlushAll<A> {
+        for page in pages {
+            let (mut table, i) = match self.leaf_slot(page.start()) {
+                Some(slot) => slot,
+                None => continue,
+            };
+            match table.entry(i) {
+                Some(entry) if entry.present() && entry.flags() & flags != 0 => {
+                    table.set_entry(i, PageEntry::new(entry.data() & !flags));
+                    f(page, entry);
+                }
+                _ => (),
+            }
+        }
+        PageFlushAll::new()
+    }
+
+    /// 解除映射并释放页框，大页释放整个块
     pub unsafe fn unmap(&mut self, virt: VirtualAddress) -> Option<PageFlush<A>> {
+        let pages = 1 << (self.leaf_slot(virt)?.0.level() * A::PAGE_ENTRY_SHIFT);
         let (old, flush) = self.unmap_phys(virt)?;
-        self.allocator.free_one(old.address());
+        let base = old.address().align_down(pages << A::PAGE_SHIFT);
+        self.allocator.free(base, FrameCount::new(pages));
         Some(flush)
     }
 
+    /// 解除每一页的映射并释放页框，没有映射的页会被跳过
+    pub unsafe fn unmap_range(&mut self, pages: PageRange<A>) -> PageFlushAll<A> {
+        let flush_all = PageFlushAll::new();
+        for page in pages {
+            if let Some(flush) = self.unmap(page.start()) {
+                flush_all.consume(flush);
+            }
+        }
+        flush_all
+    }
+
+    /// 解除映射，返回原来的叶子表项，可能是大页
     pub unsafe fn unmap_phys(
         &mut self,
         virt: VirtualAddress,
     ) -> Option<(PageEntry<A>, PageFlush<A>)> {
-        let mut table = self.table();
-        loop {
-            let i = table.index_of(virt)?;
-            if table.level() == 0 {
-                let entry_opt = table.entry(i);
-                table.set_entry(i, PageEntry::new(0));
-                let entry = entry_opt?;
-                return Some((entry, PageFlush::new(virt)));
-            } else {
-                table = table.next(i)?;
+        let (mut table, i) = self.leaf_slot(virt)?;
+        let entry = table.entry(i)?;
+        if !entry.present() {
+            return None;
+        }
+        table.set_entry(i, PageEntry::new(0));
+        Some((entry, PageFlush::new(virt)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        emulate_test, Arch, EmulateArch, FrameAllocator, FrameCount, MemoryType, PageEntry,
+        PageMapper, PageRange, PageTable, VirtualAddress,
+    };
+
+    #[test]
+    fn accessed_dirty() {
+        unsafe {
+            let mut allocator = emulate_test();
+            let mut mapper = PageMapper::<EmulateArch, _>::current(&mut allocator);
+            let accessed = EmulateArch::ENTRY_FLAG_ACCESSED;
+            let dirty = EmulateArch::ENTRY_FLAG_DIRTY;
+            let pages =
+                PageRange::covering(VirtualAddress::new(0x1000), 3 * EmulateArch::PAGE_SIZE);
+            mapper
+                .map_range(pages, EmulateArch::ENTRY_FLAG_WRITABLE)
+                .unwrap()
+                .flush();
+            EmulateArch::read::<usize>(VirtualAddress::new(0x1000));
+            EmulateArch::write::<usize>(VirtualAddress::new(0x2000), 1);
+
+            let mut found = Vec::new();
+            mapper
+                .harvest(pages, accessed | dirty, |page, entry| {
+                    found.push((page.start().data(), entry.accessed(), entry.dirty()))
+                })
+                .flush();
+            assert_eq!(found, [(0x1000, true, false), (0x2000, true, true)]);
+
+            // 清除之后再次访问会重新设置
+            EmulateArch::read::<usize>(VirtualAddress::new(0x2000));
+            let (entry, flush) = mapper
+                .clear_flags(VirtualAddress::new(0x2000), accessed)
+                .unwrap();
+            flush.ignore();
+            assert!(entry.accessed() && !entry.dirty());
+            let mut count = 0;
+            mapper.harvest(pages, accessed, |_, _| count += 1).flush();
+            assert_eq!(count, 0);
+        }
+    }
+
+    #[test]
+    fn kernel_half() {
+        unsafe {
+            let mut allocator = emulate_test();
+            let template = {
+                let mut mapper = PageMapper::<EmulateArch, _>::current(&mut allocator);
+                mapper.preallocate_kernel().unwrap();
+                mapper.table()
+            };
+            for i in PageTable::<EmulateArch>::kernel_entries() {
+                assert!(template.entry(i).unwrap().present());
+            }
+            assert_eq!(PageTable::<EmulateArch>::kernel_entries().start, 256);
+
+            let user = VirtualAddress::new(0x1000);
+            let kernel = VirtualAddress::new(0xFFFF_C000_0000_0000);
+            let table = {
+                let mut mapper =
+                    PageMapper::<EmulateArch, _>::create_from(template.phys(), &mut allocator)
+                        .unwrap();
+                // 切换之后直接映射仍然可用，用户部分是空的
+                mapper.make_current();
+                assert!(mapper.translate(user).is_none());
+                mapper
+                    .map(kernel, EmulateArch::ENTRY_FLAG_WRITABLE)
+                    .unwrap()
+                    .flush();
+                mapper.table()
+            };
+            EmulateArch::write::<usize>(kernel, 0x5A);
+
+            // 在新地址空间中建立的内核映射在模板中也能看到
+            EmulateArch::set_table(template.phys());
+            assert_eq!(EmulateArch::read::<usize>(kernel), 0x5A);
+            for i in PageTable::<EmulateArch>::kernel_entries() {
+                assert_eq!(
+                    table.entry(i).unwrap().data(),
+                    template.entry(i).unwrap().data()
+                );
             }
         }
     }
+
+    #[test]
+    fn memory_types() {
+        unsafe {
+            let mut allocator = emulate_test();
+            let mut mapper = PageMapper::<EmulateArch, _>::current(&mut allocator);
+            let types = [
+                MemoryType::WriteBack,
+                MemoryType::WriteThrough,
+                MemoryType::Uncached,
+                MemoryType::WriteCombining,
+            ];
+            for (i, &memory_type) in types.iter().enumerate() {
+                let virt = VirtualAddress::new(0x1000 * (i + 1));
+                let phys = mapper.allocator_mut().allocate_one().unwrap();
+                mapper
+                    .map_phys_typed(virt, phys, EmulateArch::ENTRY_FLAG_WRITABLE, memory_type)
+                    .unwrap()
+                    .flush();
+                let entry = mapper.leaf_entry(virt).unwrap();
+                assert_eq!(entry.address(), phys);
+                assert_eq!(EmulateArch::memory_type(entry.flags(), false), memory_type);
+            }
+        }
+    }
+
+    #[test]
+    fn huge_leaf() {
+        unsafe {
+            let mut allocator = emulate_test();
+            let mut mapper = PageMapper::<EmulateArch, _>::current(&mut allocator);
+            let writable = EmulateArch::ENTRY_FLAG_WRITABLE;
+            let pages = EmulateArch::PAGE_ENTRIES;
+            let size = pages * EmulateArch::PAGE_SIZE;
+            let block = mapper
+                .allocator_mut()
+                .allocate(FrameCount::new(2 * pages))
+                .unwrap();
+            let frame = block.align_up(size);
+            mapper
+                .map(VirtualAddress::new(0x4000_0000), writable)
+                .unwrap()
+                .flush();
+            let mut pd = mapper.table().next(0).unwrap().next(1).unwrap();
+            let huge = PageEntry::<EmulateArch>::new(
+                frame.data()
+                    | writable
+                    | EmulateArch::ENTRY_FLAG_PRESENT
+                    | EmulateArch::ENTRY_FLAG_HUGE,
+            );
+            pd.set_entry(1, huge);
+            EmulateArch::invalid_data_all();
+            let virt = VirtualAddress::new(0x4000_0000 + size);
+            assert!(pd.next(1).is_none());
+            assert_eq!(
+                mapper.translate(virt.add(0x1234)),
+                Some((frame.add(0x1234), huge.flags()))
+            );
+
+            // 大页中的地址不能再映射，大页本身和其中的数据都不变
+            let phys = mapper.allocator_mut().allocate_one().unwrap();
+            let user = EmulateArch::ENTRY_FLAG_USER;
+            assert!(mapper
+                .map_phys(virt.add(0x1000), phys, writable | user)
+                .is_none());
+            assert_eq!(pd.entry(1).unwrap().data(), huge.data());
+            assert_eq!(
+                EmulateArch::read::<usize>(EmulateArch::phys_to_virt(frame).add(8)),
+                0
+            );
+
+            // 解除映射清除整个大页表项
+            let (old, flush) = mapper.unmap_phys(virt.add(0x5000)).unwrap();
+            flush.flush();
+            assert_eq!(old.data(), huge.data());
+            assert_eq!(pd.entry(1).unwrap().data(), 0);
+            assert!(mapper.translate(virt).is_none());
+        }
+    }
 }
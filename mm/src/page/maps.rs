@@ -0,0 +1,207 @@
+use core::fmt;
+
+use crate::{Arch, MemoryType, PageLeaf, PageTable, PageWalker, PhysicalAddress, VirtualAddress};
+
+/// 一段虚拟地址和物理地址都连续、权限相同的映射
+#[derive(Debug, Clone, Copy)]
+pub struct MappedRange<A> {
+    virt: VirtualAddress,
+    phys: PhysicalAddress,
+    size: usize,
+    flags: usize,
+    huge: bool,
+    leaf: PageLeaf<A>,
+}
+
+impl<A: Arch> MappedRange<A> {
+    fn new(leaf: PageLeaf<A>) -> Self {
+        Self {
+            virt: leaf.virt(),
+            phys: leaf.phys(),
+            size: leaf.size(),
+            flags: leaf.flags(),
+            huge: leaf.huge(),
+            leaf,
+        }
+    }
+    fn flags_mask() -> usize {
+        A::ENTRY_FLAG_WRITABLE | A::ENTRY_FLAG_USER | A::ENTRY_FLAG_GLOBAL | A::ENTRY_FLAG_NO_EXEC
+    }
+    fn extend(&mut self, leaf: &PageLeaf<A>) -> bool {
+        if leaf.virt().data() == self.virt.data().wrapping_add(self.size)
+            && leaf.phys() == self.phys.add(self.size)
+            && leaf.flags() & Self::flags_mask() == self.flags & Self::flags_mask()
+            && leaf.huge() == self.huge
+            && leaf.memory_type() == self.leaf.memory_type()
+        {
+            self.size += leaf.size();
+            true
+        } else {
+            false
+        }
+    }
+    pub fn virt(&self) -> VirtualAddress {
+        self.virt
+    }
+    pub fn phys(&self) -> PhysicalAddress {
+        self.phys
+    }
+    pub fn size(&self) -> usize {
+        self.size
+    }
+    /// 实际权限，见`PageLeaf::flags`
+    pub fn flags(&self) -> usize {
+        self.flags
+    }
+    /// 这段映射中的第一个叶子表项
+    pub fn first(&self) -> PageLeaf<A> {
+        self.leaf
+    }
+    pub fn writable(&self) -> bool {
+        self.flags & A::ENTRY_FLAG_WRITABLE != 0
+    }
+    pub fn executable(&self) -> bool {
+        self.flags & A::ENTRY_FLAG_NO_EXEC == 0
+    }
+    pub fn user(&self) -> bool {
+        self.flags & A::ENTRY_FLAG_USER != 0
+    }
+    pub fn global(&self) -> bool {
+        self.flags & A::ENTRY_FLAG_GLOBAL != 0
+    }
+    pub fn huge(&self) -> bool {
+        self.huge
+    }
+    pub fn memory_type(&self) -> MemoryType {
+        self.leaf.memory_type()
+    }
+}
+
+/// 和/proc/pid/maps类似的格式：
+/// `ffff800000000000-ffff800004000000 0000000000000000 65536K rw-ug-`
+impl<A: Arch> fmt::Display for MappedRange<A> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:016x}-{:016x} {:016x} {}K ",
+            self.virt.data(),
+            self.virt.data().wrapping_add(self.size),
+            self.phys.data(),
+            self.size / 1024
+        )?;
+        let flags = [
+            (true, 'r'),
+            (self.writable(), 'w'),
+            (self.executable(), 'x'),
+            (self.user(), 'u'),
+            (self.global(), 'g'),
+            (self.huge(), 'h'),
+        ];
+        for (set, c) in flags.iter() {
+            write!(f, "{}", if *set { *c } else { '-' })?;
+        }
+        Ok(())
+    }
+}
+
+/// 把相邻的叶子表项合并成`MappedRange`
+pub struct MappedRanges<A> {
+    walker: PageWalker<A>,
+    pending: Option<MappedRange<A>>,
+}
+
+impl<A: Arch> MappedRanges<A> {
+    pub unsafe fn new(table: PageTable<A>) -> Self {
+        Self {
+            walker: PageWalker::new(table),
+            pending: None,
+        }
+    }
+}
+
+impl<A: Arch> Iterator for MappedRanges<A> {
+    type Item = MappedRange<A>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for leaf in &mut self.walker {
+            if let Some(ref mut range) = self.pending {
+                if range.extend(&leaf) {
+                    continue;
+                }
+            }
+            if let Some(range) = self.pending.replace(MappedRange::new(leaf)) {
+                return Some(range);
+            }
+        }
+        self.pending.take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        emulate_test, Arch, EmulateArch, FrameAllocator, FrameCount, MappedRanges, PageMapper,
+        VirtualAddress,
+    };
+
+    #[test]
+    fn coalesce() {
+        unsafe {
+            let page = EmulateArch::PAGE_SIZE;
+            let mut allocator = emulate_test();
+            let frames = allocator.allocate(FrameCount::new(4)).unwrap();
+            let mut mapper = PageMapper::<EmulateArch, _>::create(&mut allocator).unwrap();
+            let read = EmulateArch::ENTRY_FLAG_USER | EmulateArch::ENTRY_FLAG_NO_EXEC;
+            let write = read | EmulateArch::ENTRY_FLAG_WRITABLE;
+            let global = EmulateArch::ENTRY_FLAG_WRITABLE | EmulateArch::ENTRY_FLAG_GLOBAL;
+            let mut map = |virt: usize, frame: usize, flags: usize| {
+                mapper
+                    .map_phys(VirtualAddress::new(virt), frames.add(frame * page), flags)
+                    .unwrap()
+                    .ignore();
+            };
+            // 虚拟地址和物理地址都连续、权限相同的三页合并
+            for i in 0..3 {
+                map(0x1000 + i * page, i, write);
+            }
+            // 物理地址连续但权限不同、权限相同但物理地址不连续、不相邻
+            map(0x4000, 3, read);
+            map(0x5000, 0, write);
+            map(0x10_0000, 1, write);
+            map(0xFFFF_8000_0000_0000, 0, global);
+
+            let ranges: Vec<_> = MappedRanges::new(mapper.table()).collect();
+            let lines: Vec<_> = ranges.iter().map(|range| range.to_string()).collect();
+            let phys = |frame: usize| frames.add(frame * page).data();
+            assert_eq!(
+                lines,
+                vec![
+                    format!(
+                        "0000000000001000-0000000000004000 {:016x} 12K rw-u--",
+                        phys(0)
+                    ),
+                    format!(
+                        "0000000000004000-0000000000005000 {:016x} 4K r--u--",
+                        phys(3)
+                    ),
+                    format!(
+                        "0000000000005000-0000000000006000 {:016x} 4K rw-u--",
+                        phys(0)
+                    ),
+                    format!(
+                        "0000000000100000-0000000000101000 {:016x} 4K rw-u--",
+                        phys(1)
+                    ),
+                    format!(
+                        "ffff800000000000-ffff800000001000 {:016x} 4K rwx-g-",
+                        phys(0)
+                    ),
+                ]
+            );
+            assert_eq!(ranges[0].size(), 3 * page);
+            assert_eq!(ranges[0].first().virt(), VirtualAddress::new(0x1000));
+            assert!(ranges[0].writable() && ranges[0].user() && !ranges[0].executable());
+            assert!(ranges[4].global() && !ranges[4].huge());
+        }
+    }
+}
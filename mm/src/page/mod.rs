@@ -1,5 +1,17 @@
 pub use self::{entry::*, flush::*, table::*,mapper::*};
+pub use self::{
+    asid::*, audit::*, fault::*, fork::*, maps::*, range::*, stack::*, vmalloc::*, walk::*,
+};
+mod asid;
+mod audit;
 mod entry;
+mod fault;
 mod flush;
+mod fork;
 mod table;
 mod mapper;
+mod maps;
+mod range;
+mod stack;
+mod vmalloc;
+mod walk;
@@ -0,0 +1,236 @@
+use core::marker::PhantomData;
+
+use crate::{Arch, FrameCount, PhysicalAddress, VirtualAddress};
+
+/// 虚拟页，起始地址按页对齐
+#[derive(Debug, Clone, Copy)]
+pub struct Page<A> {
+    start: VirtualAddress,
+    phantom: PhantomData<A>,
+}
+
+impl<A: Arch> Page<A> {
+    /// 包含这个地址的页
+    pub fn containing(virt: VirtualAddress) -> Self {
+        Self {
+            start: virt.align_down(A::PAGE_SIZE),
+            phantom: PhantomData,
+        }
+    }
+    /// 地址没有按页对齐时返回`None`
+    pub fn from_start(virt: VirtualAddress) -> Option<Self> {
+        if virt.is_aligned(A::PAGE_SIZE) {
+            Some(Self::containing(virt))
+        } else {
+            None
+        }
+    }
+    pub fn start(&self) -> VirtualAddress {
+        self.start
+    }
+    /// 之后的第`count`页，越过地址空间末尾时返回`None`
+    pub fn checked_add(&self, count: usize) -> Option<Self> {
+        let start = self.start.checked_add(count.checked_mul(A::PAGE_SIZE)?)?;
+        Some(Self::containing(start))
+    }
+}
+
+impl<A> PartialEq for Page<A> {
+    fn eq(&self, other: &Self) -> bool {
+        self.start == other.start
+    }
+}
+
+impl<A> Eq for Page<A> {}
+
+/// 物理页框，起始地址按页对齐
+#[derive(Debug, Clone, Copy)]
+pub struct Frame<A> {
+    start: PhysicalAddress,
+    phantom: PhantomData<A>,
+}
+
+impl<A: Arch> Frame<A> {
+    /// 包含这个地址的页框
+    pub fn containing(phys: PhysicalAddress) -> Self {
+        Self {
+            start: phys.align_down(A::PAGE_SIZE),
+            phantom: PhantomData,
+        }
+    }
+    /// 地址没有按页对齐时返回`None`
+    pub fn from_start(phys: PhysicalAddress) -> Option<Self> {
+        if phys.is_aligned(A::PAGE_SIZE) {
+            Some(Self::containing(phys))
+        } else {
+            None
+        }
+    }
+    pub fn start(&self) -> PhysicalAddress {
+        self.start
+    }
+    /// 之后的第`count`个页框，溢出时返回`None`
+    pub fn checked_add(&self, count: usize) -> Option<Self> {
+        let start = self.start.checked_add(count.checked_mul(A::PAGE_SIZE)?)?;
+        Some(Self::containing(start))
+    }
+}
+
+impl<A> PartialEq for Frame<A> {
+    fn eq(&self, other: &Self) -> bool {
+        self.start == other.start
+    }
+}
+
+impl<A> Eq for Frame<A> {}
+
+/// 连续的虚拟页，作为迭代器时从前往后依次返回每一页，`len`是剩余的页数
+#[derive(Debug, Clone, Copy)]
+pub struct PageRange<A> {
+    start: Page<A>,
+    count: usize,
+}
+
+impl<A: Arch> PageRange<A> {
+    pub fn new(start: Page<A>, count: usize) -> Self {
+        Self { start, count }
+    }
+    /// 覆盖`[virt, virt + size)`的所有页
+    pub fn covering(virt: VirtualAddress, size: usize) -> Self {
+        let start = Page::containing(virt);
+        let end = virt.add(size).align_up(A::PAGE_SIZE);
+        Self::new(start, (end.data() - start.start().data()) >> A::PAGE_SHIFT)
+    }
+    pub fn start(&self) -> Page<A> {
+        self.start
+    }
+    pub fn size(&self) -> usize {
+        self.count << A::PAGE_SHIFT
+    }
+    /// 最后一页之后的地址
+    pub fn end(&self) -> VirtualAddress {
+        self.start.start().wrapping_add(self.size())
+    }
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+    pub fn contains(&self, virt: VirtualAddress) -> bool {
+        virt >= self.start.start() && virt.data() - self.start.start().data() < self.size()
+    }
+}
+
+impl<A: Arch> Iterator for PageRange<A> {
+    type Item = Page<A>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.count == 0 {
+            return None;
+        }
+        let page = self.start;
+        self.count -= 1;
+        if self.count > 0 {
+            self.start = page.checked_add(1)?;
+        }
+        Some(page)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.count, Some(self.count))
+    }
+}
+
+impl<A: Arch> ExactSizeIterator for PageRange<A> {}
+
+/// 连续的物理页框，作为迭代器时从前往后依次返回每一个页框，`len`是剩余的页框数
+#[derive(Debug, Clone, Copy)]
+pub struct FrameRange<A> {
+    start: Frame<A>,
+    count: usize,
+}
+
+impl<A: Arch> FrameRange<A> {
+    pub fn new(start: Frame<A>, count: FrameCount) -> Self {
+        Self {
+            start,
+            count: count.data(),
+        }
+    }
+    pub fn start(&self) -> Frame<A> {
+        self.start
+    }
+    pub fn size(&self) -> usize {
+        self.count << A::PAGE_SHIFT
+    }
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+    pub fn contains(&self, phys: PhysicalAddress) -> bool {
+        phys >= self.start.start() && phys.data() - self.start.start().data() < self.size()
+    }
+}
+
+impl<A: Arch> Iterator for FrameRange<A> {
+    type Item = Frame<A>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.count == 0 {
+            return None;
+        }
+        let frame = self.start;
+        self.count -= 1;
+        if self.count > 0 {
+            self.start = frame.checked_add(1)?;
+        }
+        Some(frame)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.count, Some(self.count))
+    }
+}
+
+impl<A: Arch> ExactSizeIterator for FrameRange<A> {}
+
+#[cfg(test)]
+mod tests {
+    use super::{Page, PageRange};
+    use crate::{Arch, EmulateArch, VirtualAddress};
+
+    #[test]
+    fn page_range() {
+        let virt = VirtualAddress::new(0xFFFF_8000_0000_1234);
+        let range = PageRange::<EmulateArch>::covering(virt, EmulateArch::PAGE_SIZE);
+        assert_eq!(range.len(), 2);
+        assert_eq!(
+            range.start().start(),
+            VirtualAddress::new(0xFFFF_8000_0000_1000)
+        );
+        assert_eq!(range.end(), VirtualAddress::new(0xFFFF_8000_0000_3000));
+        assert!(range.contains(virt));
+        assert!(!range.contains(range.end()));
+        let pages: Vec<_> = range.map(|page| page.start().data()).collect();
+        assert_eq!(pages, [0xFFFF_8000_0000_1000, 0xFFFF_8000_0000_2000]);
+
+        assert!(Page::<EmulateArch>::from_start(virt).is_none());
+        // 地址空间最后一页
+        let last = Page::<EmulateArch>::containing(VirtualAddress::new(usize::MAX));
+        assert!(last.checked_add(1).is_none());
+        assert_eq!(PageRange::new(last, 1).count(), 1);
+        assert_eq!(PageRange::new(last, 1).end(), VirtualAddress::new(0));
+        assert!(VirtualAddress::new(usize::MAX).checked_add(1).is_none());
+
+        assert!(EmulateArch::is_canonical(VirtualAddress::new(
+            0xFFFF_8000_0000_0000
+        )));
+        assert!(EmulateArch::is_canonical(VirtualAddress::new(
+            0x0000_7FFF_FFFF_F000
+        )));
+        assert!(!EmulateArch::is_canonical(VirtualAddress::new(
+            0x0000_8000_0000_0000
+        )));
+        assert_eq!(
+            EmulateArch::canonicalize(VirtualAddress::new(0x0000_8000_0000_0000)),
+            VirtualAddress::new(0xFFFF_8000_0000_0000)
+        );
+    }
+}
@@ -0,0 +1,179 @@
+use core::marker::PhantomData;
+
+use crate::{Arch, FrameAllocator, PageFlushAll, PageMapper, PageRange, VirtualAddress};
+
+/// 一个线程栈，`guard`是栈底下方未映射的保护页
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Stack {
+    slot: usize,
+    guard: VirtualAddress,
+    bottom: VirtualAddress,
+    top: VirtualAddress,
+}
+
+impl Stack {
+    pub fn slot(&self) -> usize {
+        self.slot
+    }
+    pub fn guard(&self) -> VirtualAddress {
+        self.guard
+    }
+    /// 栈的最低地址
+    pub fn bottom(&self) -> VirtualAddress {
+        self.bottom
+    }
+    /// 栈顶，栈从这里向下增长
+    pub fn top(&self) -> VirtualAddress {
+        self.top
+    }
+    pub fn size(&self) -> usize {
+        self.top.data() - self.bottom.data()
+    }
+    /// 栈占用的页，不包括保护页
+    pub fn pages<A: Arch>(&self) -> PageRange<A> {
+        PageRange::covering(self.bottom, self.size())
+    }
+}
+
+/// 内核栈和用户栈分配器：在一段专用的虚拟地址空间中，
+/// 每个栈占用一个固定大小的槽，槽的第一页是保护页，之后是栈
+pub struct StackAllocator<A> {
+    base: VirtualAddress,
+    slots: usize,
+    // 每个栈的页数，不包括保护页
+    pages: usize,
+    next: usize,
+    phantom: PhantomData<A>,
+}
+
+impl<A: Arch> StackAllocator<A> {
+    /// `base`、`size`和`stack_size`需要按页对齐
+    pub fn new(base: VirtualAddress, size: usize, stack_size: usize) -> Self {
+        let pages = stack_size >> A::PAGE_SHIFT;
+        Self {
+            base,
+            slots: (size >> A::PAGE_SHIFT) / (pages + 1),
+            pages,
+            next: 0,
+            phantom: PhantomData,
+        }
+    }
+    fn slot_size(&self) -> usize {
+        (self.pages + 1) << A::PAGE_SHIFT
+    }
+    pub fn contains(&self, virt: VirtualAddress) -> bool {
+        virt >= self.base && virt.data() - self.base.data() < self.slots * self.slot_size()
+    }
+    /// 第`slot`个槽中的栈
+    pub fn stack(&self, slot: usize) -> Option<Stack> {
+        if slot >= self.slots {
+            return None;
+        }
+        let guard = self.base.add(slot * self.slot_size());
+        let bottom = guard.add(A::PAGE_SIZE);
+        Some(Stack {
+            slot,
+            guard,
+            bottom,
+            top: bottom.add(self.pages << A::PAGE_SHIFT),
+        })
+    }
+    /// 如果地址落在某个栈的保护页上，返回这个栈，用于在缺页处理中报告栈溢出
+    pub fn guard_hit(&self, virt: VirtualAddress) -> Option<Stack> {
+        if !self.contains(virt) {
+            return None;
+        }
+        let stack = self.stack((virt.data() - self.base.data()) / self.slot_size())?;
+        if virt < stack.bottom() {
+            Some(stack)
+        } else {
+            None
+        }
+    }
+
+    /// 分配一个栈并映射所有页，内核栈一般使用`ENTRY_FLAG_WRITABLE | ENTRY_FLAG_NO_EXEC`，
+    /// 用户栈再加上`ENTRY_FLAG_USER`
+    #[track_caller]
+    pub unsafe fn allocate<F: FrameAllocator>(
+        &mut self,
+        mapper: &mut PageMapper<A, F>,
+        flags: usize,
+    ) -> Option<(Stack, PageFlushAll<A>)> {
+        let stack = (self.next..self.slots)
+            .chain(0..self.next)
+            .filter_map(|slot| self.stack(slot))
+            .find(|stack| mapper.translate(stack.bottom()).is_none())?;
+        let flush_all = mapper.map_range(stack.pages(), flags)?;
+        self.next = stack.slot() + 1;
+        Some((stack, flush_all))
+    }
+
+    /// 解除映射并释放栈的所有页框
+    pub unsafe fn free<F: FrameAllocator>(
+        &mut self,
+        mapper: &mut PageMapper<A, F>,
+        stack: Stack,
+    ) -> Option<PageFlushAll<A>> {
+        if self.stack(stack.slot())? != stack {
+            return None;
+        }
+        let flush_all = mapper.unmap_range(stack.pages());
+        if stack.slot() < self.next {
+            self.next = stack.slot();
+        }
+        Some(flush_all)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::StackAllocator;
+    use crate::{emulate_test, Arch, EmulateArch, FrameAllocator, PageMapper, VirtualAddress};
+
+    #[test]
+    fn stacks() {
+        unsafe {
+            let page = EmulateArch::PAGE_SIZE;
+            let mut allocator = emulate_test();
+            let used = allocator.usage().used().data();
+            let mut mapper = PageMapper::<EmulateArch, _>::current(&mut allocator);
+            let base = VirtualAddress::new(0xFFFF_C000_0000_0000);
+            // 两个槽，每个栈4页加1页保护页
+            let mut stacks = StackAllocator::<EmulateArch>::new(base, 11 * page, 4 * page);
+            let flags = EmulateArch::ENTRY_FLAG_WRITABLE | EmulateArch::ENTRY_FLAG_NO_EXEC;
+
+            let (a, flush_all) = stacks.allocate(&mut mapper, flags).unwrap();
+            flush_all.flush();
+            let (b, flush_all) = stacks.allocate(&mut mapper, flags).unwrap();
+            flush_all.flush();
+            assert!(stacks.allocate(&mut mapper, flags).is_none());
+            assert_eq!((a.slot(), b.slot()), (0, 1));
+            assert_eq!((a.guard(), a.bottom()), (base, base.add(page)));
+            assert_eq!(a.size(), 4 * page);
+            assert_eq!(b.guard(), a.top());
+
+            // 整个栈可以访问，越过栈底落在保护页上
+            EmulateArch::write::<usize>(a.bottom().add(4 * page - 8), 1);
+            EmulateArch::write::<usize>(a.bottom(), 2);
+            assert!(EmulateArch::try_write(a.guard().add(page - 8), 3usize).is_err());
+            assert_eq!(stacks.guard_hit(a.guard().add(page - 8)), Some(a));
+            assert_eq!(stacks.guard_hit(b.guard()), Some(b));
+            assert_eq!(stacks.guard_hit(a.bottom()), None);
+            assert_eq!(stacks.guard_hit(b.top()), None);
+
+            // 释放后槽可以重新使用，不是这个分配器的栈不能释放
+            let other = StackAllocator::<EmulateArch>::new(base, 11 * page, 2 * page);
+            assert!(stacks.free(&mut mapper, other.stack(0).unwrap()).is_none());
+            stacks.free(&mut mapper, a).unwrap().flush();
+            assert!(EmulateArch::try_read::<usize>(a.bottom()).is_err());
+            let (c, flush_all) = stacks.allocate(&mut mapper, flags).unwrap();
+            flush_all.flush();
+            assert_eq!(c, a);
+            stacks.free(&mut mapper, c).unwrap().flush();
+            stacks.free(&mut mapper, b).unwrap().flush();
+            drop(mapper);
+            // 只剩下新建的页表
+            assert_eq!(allocator.usage().used().data(), used + 3);
+        }
+    }
+}
@@ -1,4 +1,5 @@
 use core::marker::PhantomData;
+use core::ops::Range;
 
 use crate::{Arch, PageEntry, PhysicalAddress, VirtualAddress};
 
@@ -21,6 +22,12 @@ impl<A: Arch> PageTable<A> {
     pub unsafe fn top() -> Self {
         Self::new(VirtualAddress::new(0), A::table(), A::PAGE_LEVELS - 1)
     }
+    /// 顶级页表中内核部分（从`PHYS_OFFSET`开始）的表项
+    pub fn kernel_entries() -> Range<usize> {
+        let level_shift = (A::PAGE_LEVELS - 1) * A::PAGE_ENTRY_SHIFT + A::PAGE_SHIFT;
+        let start = ((A::PHYS_OFFSET & A::PAGE_ADDRESS_MASK) >> level_shift) & A::PAGE_ENTRY_MASK;
+        start..A::PAGE_ENTRIES
+    }
     pub fn base(&self) -> VirtualAddress {
         self.base
     }
@@ -69,10 +76,11 @@ impl<A: Arch> PageTable<A> {
         }
     }
 
+    /// 下一级页表，表项不存在或者是大页时返回`None`
     pub unsafe fn next(&self, i: usize) -> Option<Self> {
         if self.level() > 0 {
             let entry = self.entry(i)?;
-            if entry.present() {
+            if entry.present() && entry.flags() & A::ENTRY_FLAG_HUGE == 0 {
                 return Some(PageTable::new(
                     self.entry_base(i)?,
                     entry.address(),
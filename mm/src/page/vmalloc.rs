@@ -0,0 +1,215 @@
+use core::marker::PhantomData;
+
+use crate::{
+    Arch, FrameAllocator, FrameCount, Page, PageFlushAll, PageMapper, PageRange, VirtualAddress,
+};
+
+/// `VmAllocator`最多同时存在的分配数
+pub const VMALLOC_AREAS_MAX: usize = 128;
+
+/// 一次分配占用的页，序号相对于`VmAllocator::base`
+#[derive(Clone, Copy, Debug)]
+struct VmArea {
+    start: usize,
+    pages: usize,
+}
+
+impl VmArea {
+    fn end(&self) -> usize {
+        self.start + self.pages
+    }
+}
+
+/// 内核虚拟连续内存分配器：在一段保留的内核虚拟地址空间中分配，
+/// 每一页单独分配页框，分配之间留有未映射的保护页
+pub struct VmAllocator<A> {
+    base: VirtualAddress,
+    pages: usize,
+    // 按起始页排序的分配，之间的空隙就是空闲的地址空间
+    areas: [VmArea; VMALLOC_AREAS_MAX],
+    count: usize,
+    // 下一次开始查找的页，避免每次都从头查找
+    next: usize,
+    phantom: PhantomData<A>,
+}
+
+impl<A: Arch> VmAllocator<A> {
+    /// `base`和`size`需要按页对齐，这段地址空间只能由这个分配器映射
+    pub fn new(base: VirtualAddress, size: usize) -> Self {
+        Self {
+            base,
+            pages: size >> A::PAGE_SHIFT,
+            areas: [VmArea { start: 0, pages: 0 }; VMALLOC_AREAS_MAX],
+            count: 0,
+            next: 0,
+            phantom: PhantomData,
+        }
+    }
+    pub fn base(&self) -> VirtualAddress {
+        self.base
+    }
+    pub fn size(&self) -> usize {
+        self.pages << A::PAGE_SHIFT
+    }
+    pub fn contains(&self, virt: VirtualAddress) -> bool {
+        virt >= self.base && virt.data() - self.base.data() < self.size()
+    }
+    /// 地址是否落在这段地址空间中不属于任何分配的页（保护页）上
+    pub fn is_guard(&self, virt: VirtualAddress) -> bool {
+        if !self.contains(virt) {
+            return false;
+        }
+        let page = (virt.data() - self.base.data()) >> A::PAGE_SHIFT;
+        match self.areas().binary_search_by_key(&page, |area| area.start) {
+            Ok(_) => false,
+            Err(0) => true,
+            Err(i) => self.areas[i - 1].end() <= page,
+        }
+    }
+    /// 正在使用的分配数
+    pub fn allocations(&self) -> usize {
+        self.count
+    }
+
+    fn areas(&self) -> &[VmArea] {
+        &self.areas[..self.count]
+    }
+
+    fn page(&self, page: usize) -> VirtualAddress {
+        self.base.add(page << A::PAGE_SHIFT)
+    }
+
+    /// 分配`count`页虚拟连续的内存，前后至少各有一个保护页
+    #[track_caller]
+    pub unsafe fn allocate<F: FrameAllocator>(
+        &mut self,
+        mapper: &mut PageMapper<A, F>,
+        count: FrameCount,
+        flags: usize,
+    ) -> Option<(VirtualAddress, PageFlushAll<A>)> {
+        if count.data() == 0 || self.count >= VMALLOC_AREAS_MAX {
+            return None;
+        }
+        let (start, i) = self
+            .find(self.next, count.data())
+            .or_else(|| self.find(0, count.data()))?;
+        let pages = PageRange::new(Page::containing(self.page(start)), count.data());
+        let flush_all = mapper.map_range(pages, flags)?;
+        self.areas.copy_within(i..self.count, i + 1);
+        self.areas[i] = VmArea {
+            start,
+            pages: count.data(),
+        };
+        self.count += 1;
+        self.next = start + count.data();
+        Some((self.page(start), flush_all))
+    }
+
+    /// 在第`from`页之后查找能放下`count`页和前后保护页的空隙，
+    /// 返回起始页和在`areas`中插入的位置
+    fn find(&self, from: usize, count: usize) -> Option<(usize, usize)> {
+        let mut prev_end = 0;
+        for i in 0..=self.count {
+            let next_start = if i < self.count {
+                self.areas[i].start
+            } else {
+                self.pages
+            };
+            let start = prev_end.max(from) + 1;
+            if start.checked_add(count)? < next_start {
+                return Some((start, i));
+            }
+            if i < self.count {
+                prev_end = self.areas[i].end();
+            }
+        }
+        None
+    }
+
+    /// 释放`allocate`返回的内存，解除映射并释放所有页框，返回释放的页数
+    pub unsafe fn free<F: FrameAllocator>(
+        &mut self,
+        mapper: &mut PageMapper<A, F>,
+        virt: VirtualAddress,
+    ) -> Option<(FrameCount, PageFlushAll<A>)> {
+        if !self.contains(virt) || !virt.is_aligned(A::PAGE_SIZE) {
+            return None;
+        }
+        let start = (virt.data() - self.base.data()) >> A::PAGE_SHIFT;
+        let i = self
+            .areas()
+            .binary_search_by_key(&start, |area| area.start)
+            .ok()?;
+        let area = self.areas[i];
+        let pages = PageRange::new(Page::containing(virt), area.pages);
+        let flush_all = mapper.unmap_range(pages);
+        self.areas.copy_within(i + 1..self.count, i);
+        self.count -= 1;
+        if start < self.next {
+            self.next = start - 1;
+        }
+        Some((FrameCount::new(area.pages), flush_all))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::VmAllocator;
+    use crate::{
+        emulate_test, Arch, EmulateArch, FrameAllocator, FrameCount, PageMapper, VirtualAddress,
+    };
+
+    #[test]
+    fn guard_pages() {
+        unsafe {
+            let mut allocator = emulate_test();
+            let used = allocator.usage().used().data();
+            let mut mapper = PageMapper::<EmulateArch, _>::current(&mut allocator);
+            let base = VirtualAddress::new(0xFFFF_C000_0000_0000);
+            let mut vm = VmAllocator::<EmulateArch>::new(base, 64 * EmulateArch::PAGE_SIZE);
+            let flags = EmulateArch::ENTRY_FLAG_WRITABLE | EmulateArch::ENTRY_FLAG_NO_EXEC;
+
+            let (a, flush) = vm.allocate(&mut mapper, FrameCount::new(4), flags).unwrap();
+            flush.flush();
+            let (b, flush) = vm.allocate(&mut mapper, FrameCount::new(3), flags).unwrap();
+            flush.flush();
+            assert_eq!(a, base.add(EmulateArch::PAGE_SIZE));
+            assert_eq!(b, a.add(5 * EmulateArch::PAGE_SIZE));
+            EmulateArch::write::<usize>(a.add(4 * EmulateArch::PAGE_SIZE - 8), 0x5A);
+            assert!(vm.is_guard(a.add(4 * EmulateArch::PAGE_SIZE)));
+            assert!(!vm.is_guard(a.add(3 * EmulateArch::PAGE_SIZE)));
+
+            assert!(vm
+                .free(&mut mapper, a.add(EmulateArch::PAGE_SIZE))
+                .is_none());
+            let (count, flush) = vm.free(&mut mapper, a).unwrap();
+            flush.flush();
+            assert_eq!(count.data(), 4);
+            let (count, flush) = vm.free(&mut mapper, b).unwrap();
+            flush.flush();
+            assert_eq!(count.data(), 3);
+            assert!(vm
+                .allocate(&mut mapper, FrameCount::new(63), flags)
+                .is_none());
+            assert!(vm
+                .allocate(&mut mapper, FrameCount::new(usize::MAX), flags)
+                .is_none());
+            assert_eq!(vm.allocations(), 0);
+
+            // 记录了每次分配的大小，很大的窗口也不需要逐页查找
+            let size = 1 << 40;
+            let mut vm = VmAllocator::<EmulateArch>::new(base, size);
+            let (c, flush) = vm.allocate(&mut mapper, FrameCount::new(2), flags).unwrap();
+            flush.flush();
+            assert!(vm.is_guard(base.add(size - EmulateArch::PAGE_SIZE)));
+            assert!(!vm.is_guard(c.add(EmulateArch::PAGE_SIZE)));
+            let (count, flush) = vm.free(&mut mapper, c).unwrap();
+            flush.flush();
+            assert_eq!(count.data(), 2);
+            assert!(vm.free(&mut mapper, c).is_none());
+            drop(mapper);
+            // 只剩下新建的页表
+            assert_eq!(allocator.usage().used().data(), used + 3);
+        }
+    }
+}
@@ -0,0 +1,161 @@
+use core::marker::PhantomData;
+
+use crate::{Arch, MemoryType, PageEntry, PageTable, PhysicalAddress, VirtualAddress};
+
+/// 页表中的一个叶子表项（普通页或大页）
+#[derive(Debug, Clone, Copy)]
+pub struct PageLeaf<A> {
+    virt: VirtualAddress,
+    entry: PageEntry<A>,
+    level: usize,
+    flags: usize,
+}
+
+impl<A: Arch> PageLeaf<A> {
+    /// 规范形式（高位符号扩展）的虚拟地址
+    pub fn virt(&self) -> VirtualAddress {
+        self.virt
+    }
+    pub fn phys(&self) -> PhysicalAddress {
+        // 大页的PAT位在地址位中
+        self.entry.address().align_down(self.size())
+    }
+    pub fn entry(&self) -> PageEntry<A> {
+        self.entry
+    }
+    /// 叶子所在的层级，0为普通页
+    pub fn level(&self) -> usize {
+        self.level
+    }
+    pub fn size(&self) -> usize {
+        1 << (self.level * A::PAGE_ENTRY_SHIFT + A::PAGE_SHIFT)
+    }
+    pub fn huge(&self) -> bool {
+        self.level > 0
+    }
+    /// 结合所有中间表项后的实际权限：可写和用户位需要每一级都有，任何一级不可执行即不可执行
+    pub fn flags(&self) -> usize {
+        self.flags
+    }
+    pub fn memory_type(&self) -> MemoryType {
+        A::memory_type(self.entry.flags(), self.huge())
+    }
+}
+
+/// 按虚拟地址顺序遍历页表中所有存在的叶子表项
+pub struct PageWalker<A> {
+    table_base: VirtualAddress,
+    table_phys: PhysicalAddress,
+    table_level: usize,
+    next: usize,
+    end: usize,
+    phantom: PhantomData<A>,
+}
+
+impl<A: Arch> PageWalker<A> {
+    pub unsafe fn new(table: PageTable<A>) -> Self {
+        let level_shift = (table.level() + 1) * A::PAGE_ENTRY_SHIFT + A::PAGE_SHIFT;
+        let start = table.base().data();
+        Self {
+            table_base: table.base(),
+            table_phys: table.phys(),
+            table_level: table.level(),
+            next: start,
+            end: start + (1 << level_shift),
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<A: Arch> Iterator for PageWalker<A> {
+    type Item = PageLeaf<A>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let inherited = A::ENTRY_FLAG_WRITABLE | A::ENTRY_FLAG_USER;
+        while self.next < self.end {
+            let virt = VirtualAddress::new(self.next);
+            let mut table =
+                unsafe { PageTable::<A>::new(self.table_base, self.table_phys, self.table_level) };
+            let mut and_flags = inherited;
+            let mut no_exec = 0;
+            loop {
+                let level_size = 1 << (table.level() * A::PAGE_ENTRY_SHIFT + A::PAGE_SHIFT);
+                let page = self.next & !(level_size - 1);
+                let i = unsafe { table.index_of(virt)? };
+                let entry = unsafe { table.entry(i)? };
+                if !entry.present() {
+                    self.next = page + level_size;
+                    break;
+                }
+                no_exec |= entry.flags() & A::ENTRY_FLAG_NO_EXEC;
+                if table.level() == 0 || entry.flags() & A::ENTRY_FLAG_HUGE != 0 {
+                    self.next = page + level_size;
+                    let flags =
+                        (entry.flags() & !inherited) | (entry.flags() & and_flags) | no_exec;
+                    return Some(PageLeaf {
+                        virt: A::canonicalize(VirtualAddress::new(page)),
+                        entry,
+                        level: table.level(),
+                        flags,
+                    });
+                }
+                and_flags &= entry.flags();
+                table = unsafe { table.next(i)? };
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        emulate_test, Arch, EmulateArch, FrameAllocator, FrameCount, PageEntry, PageMapper,
+        PageWalker, VirtualAddress,
+    };
+
+    #[test]
+    fn walk() {
+        unsafe {
+            let page = EmulateArch::PAGE_SIZE;
+            let mut allocator = emulate_test();
+            let frames = allocator.allocate(FrameCount::new(3)).unwrap();
+            let mut mapper = PageMapper::<EmulateArch, _>::create(&mut allocator).unwrap();
+            let mask = EmulateArch::ENTRY_FLAG_USER
+                | EmulateArch::ENTRY_FLAG_WRITABLE
+                | EmulateArch::ENTRY_FLAG_NO_EXEC;
+            let user = EmulateArch::ENTRY_FLAG_USER | EmulateArch::ENTRY_FLAG_WRITABLE;
+            let kernel = EmulateArch::ENTRY_FLAG_WRITABLE | EmulateArch::ENTRY_FLAG_NO_EXEC;
+            let high = VirtualAddress::new(0xFFFF_8000_0000_1000);
+            for &(virt, frame, flags) in [
+                (high, frames.add(2 * page), kernel),
+                (VirtualAddress::new(0x40_0000), frames.add(page), kernel),
+                (VirtualAddress::new(0x1000), frames, user),
+            ]
+            .iter()
+            {
+                mapper.map_phys(virt, frame, flags).unwrap().ignore();
+            }
+            // 中间表项不可写时，叶子表项的实际权限也不可写
+            let mut pd = mapper.table().next(0).unwrap().next(0).unwrap();
+            let entry = pd.entry(2).unwrap();
+            pd.set_entry(
+                2,
+                PageEntry::new(entry.data() & !EmulateArch::ENTRY_FLAG_WRITABLE),
+            );
+
+            // 按虚拟地址顺序，高半部分的地址是规范形式
+            let leaves: Vec<_> = PageWalker::new(mapper.table()).collect();
+            assert_eq!(leaves.len(), 3);
+            assert_eq!(leaves[0].virt(), VirtualAddress::new(0x1000));
+            assert_eq!(leaves[0].phys(), frames);
+            assert_eq!((leaves[0].level(), leaves[0].size()), (0, page));
+            assert!(!leaves[0].huge());
+            assert_eq!(leaves[0].flags() & mask, user);
+            assert_eq!(leaves[1].phys(), frames.add(page));
+            assert_eq!(leaves[1].flags() & mask, EmulateArch::ENTRY_FLAG_NO_EXEC);
+            assert_eq!(leaves[2].virt(), high);
+            assert_eq!(leaves[2].flags() & mask, kernel);
+        }
+    }
+}
@@ -0,0 +1,570 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::Read;
+use std::time::{Duration, Instant};
+
+use mm::{
+    Arch, BuddyAllocator, BumpAllocator, EmulateArch, EmulateConfig, FrameAllocator, FrameCount,
+    Page, PageMapper, PageRange, PhysicalAddress, SlabAllocator, VirtualAddress, GIGA_BYTE,
+    KILO_BYTE, MEGA_BYTE,
+};
+
+use crate::format_size;
+
+pub const USAGE: &str = "\
+usage: mm replay [options] <trace|->
+options:
+    --allocator <buddy|slab>  allocator to replay against (default buddy)
+    --memory <size>           emulated memory size, e.g. 256M (default 64M)
+    --interval <time>         sample usage every <time> trace time units (default 1000)
+
+trace format, one event per line, `#` starts a comment:
+    <time> alloc <pages> <address>
+    <time> free <address>
+    <time> map <virt> [pages]
+    <time> unmap <virt> [pages]
+<address> is the address recorded in the trace, later frees refer to it";
+
+/// 记录中的一个操作
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TraceOp {
+    /// 分配连续的页框，`address`是记录时得到的地址，之后的`Free`用它指代这次分配
+    Alloc { pages: usize, address: usize },
+    /// 释放记录时在`address`分配的页框
+    Free { address: usize },
+    /// 分配页框并映射`virt`开始的`pages`页
+    Map { virt: VirtualAddress, pages: usize },
+    /// 解除映射并释放页框
+    Unmap { virt: VirtualAddress, pages: usize },
+}
+
+impl TraceOp {
+    fn kind(&self) -> usize {
+        match self {
+            TraceOp::Alloc { .. } => 0,
+            TraceOp::Free { .. } => 1,
+            TraceOp::Map { .. } => 2,
+            TraceOp::Unmap { .. } => 3,
+        }
+    }
+}
+
+const OP_NAMES: [&str; 4] = ["alloc", "free", "map", "unmap"];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TraceEvent {
+    pub time: u64,
+    pub op: TraceOp,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+/// 十进制或`0x`开头的十六进制
+fn parse_number(text: &str) -> Option<usize> {
+    if let Some(hex) = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        usize::from_str_radix(hex, 16).ok()
+    } else {
+        text.parse().ok()
+    }
+}
+
+/// 带`K`、`M`、`G`后缀的大小
+pub fn parse_size(text: &str) -> Option<usize> {
+    let (number, unit) = match text.char_indices().last()? {
+        (i, 'K') | (i, 'k') => (&text[..i], KILO_BYTE),
+        (i, 'M') | (i, 'm') => (&text[..i], MEGA_BYTE),
+        (i, 'G') | (i, 'g') => (&text[..i], GIGA_BYTE),
+        _ => (text, 1),
+    };
+    parse_number(number)?.checked_mul(unit)
+}
+
+/// 解析记录，时间不能倒退
+pub fn parse_trace(text: &str) -> Result<Vec<TraceEvent>, ParseError> {
+    let mut events = Vec::new();
+    let mut last_time = 0;
+    for (i, line) in text.lines().enumerate() {
+        let error = |message: String| ParseError {
+            line: i + 1,
+            message,
+        };
+        let line = line.split('#').next().unwrap_or("");
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.is_empty() {
+            continue;
+        }
+        if fields.len() < 3 {
+            return Err(error(format!(
+                "expected `<time> <op> <args>`, got `{}`",
+                line.trim()
+            )));
+        }
+        let number = |index: usize| -> Result<usize, ParseError> {
+            let field = fields[index];
+            parse_number(field).ok_or_else(|| error(format!("invalid number `{}`", field)))
+        };
+        let time = number(0)? as u64;
+        if time < last_time {
+            return Err(error(format!("time {} is before {}", time, last_time)));
+        }
+        last_time = time;
+        let arity = match fields[1] {
+            "alloc" => 4..=4,
+            "free" => 3..=3,
+            "map" | "unmap" => 3..=4,
+            other => return Err(error(format!("unknown operation `{}`", other))),
+        };
+        if !arity.contains(&fields.len()) {
+            return Err(error(format!(
+                "wrong number of arguments for `{}`",
+                fields[1]
+            )));
+        }
+        let op = match fields[1] {
+            "alloc" => TraceOp::Alloc {
+                pages: number(2)?,
+                address: number(3)?,
+            },
+            "free" => TraceOp::Free {
+                address: number(2)?,
+            },
+            _ => {
+                let virt = VirtualAddress::new(number(2)?);
+                let pages = if fields.len() > 3 { number(3)? } else { 1 };
+                if fields[1] == "map" {
+                    TraceOp::Map { virt, pages }
+                } else {
+                    TraceOp::Unmap { virt, pages }
+                }
+            }
+        };
+        events.push(TraceEvent { time, op });
+    }
+    Ok(events)
+}
+
+/// 某个时间点的内存使用情况，单位是页
+#[derive(Clone, Copy, Debug)]
+pub struct Sample {
+    pub time: u64,
+    pub used: usize,
+    pub free: usize,
+    pub largest_free: usize,
+}
+
+impl Sample {
+    /// 外部碎片：空闲内存中不在最大连续块里的比例
+    pub fn fragmentation(&self) -> f64 {
+        if self.free == 0 {
+            0.0
+        } else {
+            1.0 - self.largest_free as f64 / self.free as f64
+        }
+    }
+}
+
+/// 每种操作的统计
+#[derive(Clone, Copy, Debug, Default)]
+pub struct OpStats {
+    pub count: usize,
+    pub failed: usize,
+    pub elapsed: Duration,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ReplayReport {
+    pub ops: [OpStats; 4],
+    /// 使用的页数最多的时候
+    pub peak: Option<Sample>,
+    pub samples: Vec<Sample>,
+}
+
+impl ReplayReport {
+    pub fn count(&self) -> usize {
+        self.ops.iter().map(|stats| stats.count).sum()
+    }
+    pub fn failed(&self) -> usize {
+        self.ops.iter().map(|stats| stats.failed).sum()
+    }
+    pub fn elapsed(&self) -> Duration {
+        self.ops.iter().map(|stats| stats.elapsed).sum()
+    }
+}
+
+fn per_second(count: usize, elapsed: Duration) -> f64 {
+    if elapsed.as_secs_f64() == 0.0 {
+        0.0
+    } else {
+        count as f64 / elapsed.as_secs_f64()
+    }
+}
+
+impl fmt::Display for ReplayReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let page_size = |pages: usize| format_size(pages * EmulateArch::PAGE_SIZE);
+        writeln!(
+            f,
+            "Replayed {} events ({} failed) in {:?}, {:.0} ops/s",
+            self.count(),
+            self.failed(),
+            self.elapsed(),
+            per_second(self.count(), self.elapsed())
+        )?;
+        for (name, stats) in OP_NAMES.iter().zip(self.ops.iter()) {
+            if stats.count > 0 {
+                writeln!(
+                    f,
+                    "  {:<6}{} events ({} failed) in {:?}, {:.0} ops/s",
+                    name,
+                    stats.count,
+                    stats.failed,
+                    stats.elapsed,
+                    per_second(stats.count, stats.elapsed)
+                )?;
+            }
+        }
+        if let Some(peak) = self.peak {
+            writeln!(
+                f,
+                "Peak used:{} at time {}",
+                page_size(peak.used),
+                peak.time
+            )?;
+        }
+        writeln!(
+            f,
+            "{:>12} {:>10} {:>10} {:>14} {:>14}",
+            "Time", "Used", "Free", "Largest free", "Fragmentation"
+        )?;
+        for sample in self.samples.iter() {
+            writeln!(
+                f,
+                "{:>12} {:>10} {:>10} {:>14} {:>13.1}%",
+                sample.time,
+                page_size(sample.used),
+                page_size(sample.free),
+                page_size(sample.largest_free),
+                sample.fragmentation() * 100.0
+            )?;
+        }
+        Ok(())
+    }
+}
+
+unsafe fn sample<F: FrameAllocator>(allocator: &F, time: u64) -> Sample {
+    let usage = allocator.usage();
+    Sample {
+        time,
+        used: usage.used().data(),
+        free: usage.free().data(),
+        largest_free: usage.largest_free().data(),
+    }
+}
+
+/// 执行一个操作，返回是否成功。映射已经映射的页、释放不存在的分配都算失败
+unsafe fn apply<F: FrameAllocator>(
+    allocator: &mut F,
+    allocations: &mut BTreeMap<usize, (PhysicalAddress, FrameCount)>,
+    op: TraceOp,
+) -> bool {
+    let flags = EmulateArch::ENTRY_FLAG_USER
+        | EmulateArch::ENTRY_FLAG_WRITABLE
+        | EmulateArch::ENTRY_FLAG_NO_EXEC;
+    match op {
+        TraceOp::Alloc { pages, address } => {
+            if allocations.contains_key(&address) {
+                return false;
+            }
+            let count = FrameCount::new(pages);
+            match allocator.allocate(count) {
+                Some(phys) => {
+                    allocations.insert(address, (phys, count));
+                    true
+                }
+                None => false,
+            }
+        }
+        TraceOp::Free { address } => match allocations.remove(&address) {
+            Some((phys, count)) => {
+                allocator.free(phys, count);
+                true
+            }
+            None => false,
+        },
+        TraceOp::Map { virt, pages } => {
+            let mut mapper = PageMapper::<EmulateArch, _>::current(allocator);
+            let range = PageRange::new(Page::containing(virt), pages);
+            if range
+                .clone()
+                .any(|page| mapper.translate(page.start()).is_some())
+            {
+                return false;
+            }
+            match mapper.map_range(range, flags) {
+                Some(flush_all) => {
+                    flush_all.flush();
+                    true
+                }
+                None => false,
+            }
+        }
+        TraceOp::Unmap { virt, pages } => {
+            let mut mapper = PageMapper::<EmulateArch, _>::current(allocator);
+            let range = PageRange::new(Page::containing(virt), pages);
+            if range
+                .clone()
+                .any(|page| mapper.translate(page.start()).is_none())
+            {
+                return false;
+            }
+            mapper.unmap_range(range).flush();
+            true
+        }
+    }
+}
+
+/// 在当前的`EmulateArch`页表上重放记录，每隔`interval`个时间单位记录一次使用情况。
+/// 只统计操作本身的时间，采样不计入吞吐量
+pub unsafe fn replay<F: FrameAllocator>(
+    allocator: &mut F,
+    events: &[TraceEvent],
+    interval: u64,
+) -> ReplayReport {
+    let mut report = ReplayReport::default();
+    let mut allocations = BTreeMap::new();
+    let first = sample(allocator, events.first().map_or(0, |event| event.time));
+    let mut next_sample = first.time.saturating_add(interval.max(1));
+    report.samples.push(first);
+    report.peak = Some(first);
+    for event in events.iter() {
+        if event.time >= next_sample {
+            report.samples.push(sample(allocator, event.time));
+            while next_sample <= event.time {
+                next_sample = next_sample.saturating_add(interval.max(1));
+            }
+        }
+        let start = Instant::now();
+        let ok = apply(allocator, &mut allocations, event.op);
+        let elapsed = start.elapsed();
+        let stats = &mut report.ops[event.op.kind()];
+        stats.count += 1;
+        stats.elapsed += elapsed;
+        if !ok {
+            stats.failed += 1;
+        }
+        let current = sample(allocator, event.time);
+        if report.peak.map_or(true, |peak| current.used > peak.used) {
+            report.peak = Some(current);
+        }
+    }
+    // 最后一个时间点已经在操作之前采样过时，用操作之后的结果替换
+    if let Some(last) = events.last() {
+        let current = sample(allocator, last.time);
+        match report.samples.last_mut() {
+            Some(previous) if previous.time == last.time => *previous = current,
+            _ => report.samples.push(current),
+        }
+    }
+    report
+}
+
+/// 创建`memory_size`大小的模拟机器，直接映射所有内存
+pub unsafe fn setup(memory_size: usize) -> BumpAllocator<EmulateArch> {
+    let areas = EmulateArch::init_with(&EmulateConfig::new(memory_size));
+    let mut bump_allocator = BumpAllocator::<EmulateArch>::new(areas, 0);
+    {
+        let mut mapper = PageMapper::<EmulateArch, _>::create(&mut bump_allocator)
+            .expect("failed to create mapper");
+        for area in areas.iter() {
+            for i in 0..area.size / EmulateArch::PAGE_SIZE {
+                let phys = area.base.add(i * EmulateArch::PAGE_SIZE);
+                let virt = EmulateArch::phys_to_virt(phys);
+                mapper
+                    .map_phys(
+                        virt,
+                        phys,
+                        EmulateArch::ENTRY_FLAG_WRITABLE | EmulateArch::ENTRY_FLAG_NO_EXEC,
+                    )
+                    .expect("failed to map page to frame")
+                    .ignore();
+            }
+        }
+        mapper.make_current();
+    }
+    bump_allocator
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("mm replay: {}", message);
+    eprintln!("{}", USAGE);
+    std::process::exit(2);
+}
+
+/// `mm replay`子命令
+pub fn run(args: &[String]) {
+    let mut allocator = "buddy";
+    let mut memory_size = 64 * MEGA_BYTE;
+    let mut interval = 1000;
+    let mut path = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .map(String::as_str)
+                .unwrap_or_else(|| fail(&format!("missing value for {}", arg)))
+        };
+        match arg.as_str() {
+            "--allocator" => allocator = value(),
+            "--memory" => {
+                let text = value();
+                memory_size = parse_size(text)
+                    .unwrap_or_else(|| fail(&format!("invalid memory size `{}`", text)));
+            }
+            "--interval" => {
+                let text = value();
+                interval = parse_number(text)
+                    .unwrap_or_else(|| fail(&format!("invalid interval `{}`", text)))
+                    as u64;
+            }
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return;
+            }
+            _ if path.is_none() => path = Some(arg.as_str()),
+            _ => fail(&format!("unexpected argument `{}`", arg)),
+        }
+    }
+    let path = path.unwrap_or_else(|| fail("missing trace file"));
+    let mut text = String::new();
+    let result = if path == "-" {
+        std::io::stdin().read_to_string(&mut text)
+    } else {
+        std::fs::File::open(path).and_then(|mut file| file.read_to_string(&mut text))
+    };
+    if let Err(err) = result {
+        eprintln!("mm replay: failed to read {}: {}", path, err);
+        std::process::exit(1);
+    }
+    let events = parse_trace(&text).unwrap_or_else(|err| {
+        eprintln!("mm replay: {}: {}", path, err);
+        std::process::exit(1);
+    });
+    unsafe {
+        let bump_allocator = setup(memory_size);
+        let report = match allocator {
+            "buddy" => {
+                let mut buddy =
+                    BuddyAllocator::new(bump_allocator).expect("failed to create buddy allocator");
+                replay(&mut buddy, &events, interval)
+            }
+            "slab" => replay(&mut SlabAllocator::new(bump_allocator), &events, interval),
+            other => fail(&format!("unknown allocator `{}`", other)),
+        };
+        println!(
+            "Allocator:{} Memory:{}",
+            allocator,
+            format_size(memory_size)
+        );
+        print!("{}", report);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_size, parse_trace, replay, setup, TraceEvent, TraceOp};
+    use mm::{BuddyAllocator, VirtualAddress, MEGA_BYTE};
+
+    #[test]
+    fn parse() {
+        let text = "\
+# time op args
+0 alloc 4 0x1000
+10 map 0x400000 2   # two pages
+20 unmap 0x400000
+30 free 4096
+";
+        let events = parse_trace(text).unwrap();
+        assert_eq!(
+            events,
+            vec![
+                TraceEvent {
+                    time: 0,
+                    op: TraceOp::Alloc {
+                        pages: 4,
+                        address: 0x1000
+                    }
+                },
+                TraceEvent {
+                    time: 10,
+                    op: TraceOp::Map {
+                        virt: VirtualAddress::new(0x400000),
+                        pages: 2
+                    }
+                },
+                TraceEvent {
+                    time: 20,
+                    op: TraceOp::Unmap {
+                        virt: VirtualAddress::new(0x400000),
+                        pages: 1
+                    }
+                },
+                TraceEvent {
+                    time: 30,
+                    op: TraceOp::Free { address: 0x1000 }
+                },
+            ]
+        );
+        assert_eq!(parse_trace("5 alloc 1 0\n4 free 0").unwrap_err().line, 2);
+        assert_eq!(parse_trace("0 alloc 1").unwrap_err().line, 1);
+        assert_eq!(parse_trace("0 steal 1").unwrap_err().line, 1);
+        assert_eq!(parse_size("64M"), Some(64 * MEGA_BYTE));
+        assert_eq!(parse_size("0x1000"), Some(0x1000));
+    }
+
+    #[test]
+    fn replay_trace() {
+        let text = "\
+0 alloc 16 0xA000
+1 alloc 1 0xB000
+2 alloc 1 0xB000
+3 map 0x400000 4
+4 map 0x401000
+5 free 0xA000
+6 free 0xA000
+7 unmap 0x400000 4
+8 free 0xB000
+";
+        let events = parse_trace(text).unwrap();
+        unsafe {
+            let mut allocator = BuddyAllocator::new(setup(16 * MEGA_BYTE)).unwrap();
+            let report = replay(&mut allocator, &events, 4);
+            assert_eq!(report.count(), 9);
+            // 重复的分配地址、重复映射和重复释放
+            assert_eq!(report.failed(), 3);
+            let first = report.samples.first().unwrap();
+            let last = report.samples.last().unwrap();
+            let peak = report.peak.unwrap();
+            assert_eq!(peak.time, 3);
+            assert!(peak.used >= first.used + 16 + 1 + 4);
+            assert!(last.used < peak.used);
+            assert_eq!(
+                report
+                    .samples
+                    .iter()
+                    .map(|sample| sample.time)
+                    .collect::<Vec<_>>(),
+                vec![0, 4, 8]
+            );
+        }
+    }
+}
@@ -0,0 +1,57 @@
+use crate::SwapDevice;
+
+/// 保存在内存中的交换设备，用于测试
+pub struct MemorySwapDevice {
+    data: Vec<u8>,
+    used: Vec<bool>,
+    slot_size: usize,
+}
+
+impl MemorySwapDevice {
+    pub fn new(slots: usize, slot_size: usize) -> Self {
+        Self {
+            data: vec![0; slots * slot_size],
+            used: vec![false; slots],
+            slot_size,
+        }
+    }
+    /// 已经分配的槽位数
+    pub fn used(&self) -> usize {
+        self.used.iter().filter(|used| **used).count()
+    }
+
+    fn range(&self, slot: usize, offset: usize, len: usize) -> Option<(usize, usize)> {
+        if !*self.used.get(slot)? || offset + len > self.slot_size {
+            return None;
+        }
+        let start = slot * self.slot_size + offset;
+        Some((start, start + len))
+    }
+}
+
+impl SwapDevice for MemorySwapDevice {
+    fn allocate_slot(&mut self) -> Option<usize> {
+        let slot = self.used.iter().position(|used| !*used)?;
+        self.used[slot] = true;
+        Some(slot)
+    }
+
+    fn free_slot(&mut self, slot: usize) {
+        if !self.used[slot] {
+            panic!("MemorySwapDevice: slot {} is already free", slot);
+        }
+        self.used[slot] = false;
+    }
+
+    fn write(&mut self, slot: usize, offset: usize, data: &[u8]) -> Option<()> {
+        let (start, end) = self.range(slot, offset, data.len())?;
+        self.data[start..end].copy_from_slice(data);
+        Some(())
+    }
+
+    fn read(&mut self, slot: usize, offset: usize, data: &mut [u8]) -> Option<()> {
+        let (start, end) = self.range(slot, offset, data.len())?;
+        data.copy_from_slice(&self.data[start..end]);
+        Some(())
+    }
+}
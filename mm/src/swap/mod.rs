@@ -0,0 +1,303 @@
+use crate::{
+    handle_fault, Arch, FaultAction, FaultInfo, FaultRegions, FrameAllocator, PageEntry, PageFlush,
+    PageFlushAll, PageMapper, PageRange, VirtualAddress,
+};
+
+#[cfg(feature = "std")]
+pub use self::memory::*;
+#[cfg(feature = "std")]
+mod memory;
+
+/// 每次和交换设备交换的字节数
+pub const SWAP_CHUNK: usize = 512;
+
+/// 交换设备，每个槽位保存一页
+pub trait SwapDevice {
+    /// 分配一个空闲槽位
+    fn allocate_slot(&mut self) -> Option<usize>;
+    fn free_slot(&mut self, slot: usize);
+    /// 把`data`写入槽位的`offset`处
+    fn write(&mut self, slot: usize, offset: usize, data: &[u8]) -> Option<()>;
+    /// 从槽位的`offset`处读取`data.len()`字节
+    fn read(&mut self, slot: usize, offset: usize, data: &mut [u8]) -> Option<()>;
+}
+
+/// 匿名内存的交换：用时钟算法在`pages`中选择最近没有访问的页换出，
+/// 表项替换为保存槽位的交换表项，缺页时再换入
+pub struct Swap<A, D> {
+    device: D,
+    pages: PageRange<A>,
+    // 时钟指针，下一次扫描开始的页
+    hand: usize,
+    used: usize,
+}
+
+impl<A: Arch, D: SwapDevice> Swap<A, D> {
+    pub fn new(device: D, pages: PageRange<A>) -> Self {
+        Self {
+            device,
+            pages,
+            hand: 0,
+            used: 0,
+        }
+    }
+    pub fn device(&self) -> &D {
+        &self.device
+    }
+    pub fn pages(&self) -> PageRange<A> {
+        self.pages
+    }
+    /// 已经使用的槽位数
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
+    /// 换出最多`count`页，返回换出的页数。访问位被设置的页会清除访问位后跳过，
+    /// 所以最多扫描两圈。大页、映射的物理内存和共享的页框不会被换出
+    pub unsafe fn swap_out<F: FrameAllocator>(
+        &mut self,
+        mapper: &mut PageMapper<A, F>,
+        count: usize,
+    ) -> (usize, PageFlushAll<A>) {
+        let flush_all = PageFlushAll::new();
+        let len = self.pages.len();
+        let mut swapped = 0;
+        let mut scanned = 0;
+        while swapped < count && scanned < 2 * len {
+            let page = self.pages.start().checked_add(self.hand).unwrap();
+            self.hand = (self.hand + 1) % len;
+            scanned += 1;
+            let entry = match mapper.leaf_entry(page.start()) {
+                Some(entry) if entry.present() => entry,
+                _ => continue,
+            };
+            if !Self::swappable(mapper, entry) {
+                continue;
+            }
+            if entry.accessed() {
+                if let Some((_, flush)) = mapper.clear_flags(page.start(), A::ENTRY_FLAG_ACCESSED) {
+                    flush_all.consume(flush);
+                }
+                continue;
+            }
+            if let Some(flush) = self.swap_out_page(mapper, page.start(), entry) {
+                flush_all.consume(flush);
+                swapped += 1;
+            }
+        }
+        (swapped, flush_all)
+    }
+
+    /// 只有引用计数为1的4K页框属于这个地址空间，可以换出
+    unsafe fn swappable<F: FrameAllocator>(mapper: &PageMapper<A, F>, entry: PageEntry<A>) -> bool {
+        entry.flags() & A::ENTRY_FLAG_HUGE == 0
+            && mapper.allocator().ref_count(entry.address()) == Some(1)
+    }
+
+    unsafe fn swap_out_page<F: FrameAllocator>(
+        &mut self,
+        mapper: &mut PageMapper<A, F>,
+        virt: VirtualAddress,
+        entry: PageEntry<A>,
+    ) -> Option<PageFlush<A>> {
+        let slot = self.device.allocate_slot()?;
+        let frame_virt = A::phys_to_virt(entry.address());
+        for offset in (0..A::PAGE_SIZE).step_by(SWAP_CHUNK) {
+            let chunk = A::read::<[u8; SWAP_CHUNK]>(frame_virt.add(offset));
+            if self.device.write(slot, offset, &chunk).is_none() {
+                self.device.free_slot(slot);
+                return None;
+            }
+        }
+        let flags = entry.flags() & !(A::ENTRY_FLAG_ACCESSED | A::ENTRY_FLAG_DIRTY);
+        let (old, flush) = mapper.replace_entry(virt, PageEntry::swap(slot, flags))?;
+        mapper.allocator_mut().free_one(old.address());
+        self.used += 1;
+        Some(flush)
+    }
+
+    /// 缺页时调用，如果是交换表项就分配页框并换入
+    pub unsafe fn swap_in<F: FrameAllocator>(
+        &mut self,
+        mapper: &mut PageMapper<A, F>,
+        virt: VirtualAddress,
+    ) -> Option<PageFlush<A>> {
+        let entry = mapper.leaf_entry(virt)?;
+        let slot = entry.swap_slot()?;
+        let frame = mapper.allocator_mut().allocate_one()?;
+        let frame_virt = A::phys_to_virt(frame);
+        let mut chunk = [0; SWAP_CHUNK];
+        for offset in (0..A::PAGE_SIZE).step_by(SWAP_CHUNK) {
+            if self.device.read(slot, offset, &mut chunk).is_none() {
+                mapper.allocator_mut().free_one(frame);
+                return None;
+            }
+            A::write(frame_virt.add(offset), chunk);
+        }
+        let flags = entry.flags() & !A::ENTRY_FLAG_SWAP;
+        let new_entry = PageEntry::new(frame.data() | flags | A::ENTRY_FLAG_PRESENT);
+        let (_, flush) = mapper.replace_entry(virt, new_entry)?;
+        self.device.free_slot(slot);
+        self.used -= 1;
+        Some(flush)
+    }
+
+    /// 缺页处理：换出的页在这里换入，其余的交给`handle_fault`
+    pub unsafe fn handle_fault<F: FrameAllocator>(
+        &mut self,
+        mapper: &mut PageMapper<A, F>,
+        regions: &FaultRegions<A>,
+        info: FaultInfo,
+    ) -> FaultAction {
+        let swapped = mapper
+            .leaf_entry(info.address)
+            .map_or(false, |entry| entry.is_swap());
+        if info.present || info.reserved || !swapped {
+            return handle_fault(mapper, regions, info);
+        }
+        match self.swap_in(mapper, info.address) {
+            Some(flush) => {
+                flush.flush();
+                FaultAction::SwapIn
+            }
+            None => FaultAction::OutOfMemory,
+        }
+    }
+
+    /// 解除映射时调用，释放交换表项占用的槽位
+    pub unsafe fn discard<F: FrameAllocator>(
+        &mut self,
+        mapper: &mut PageMapper<A, F>,
+        virt: VirtualAddress,
+    ) -> Option<()> {
+        let slot = mapper.leaf_entry(virt)?.swap_slot()?;
+        mapper.replace_entry(virt, PageEntry::new(0))?.1.ignore();
+        self.device.free_slot(slot);
+        self.used -= 1;
+        Some(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MemorySwapDevice, Swap};
+    use crate::{
+        emulate_test, Arch, EmulateArch, FaultAction, FaultRegions, FrameAllocator, FrameCount,
+        PageEntry, PageMapper, PageRange, PhysicalAddress, VirtualAddress, MEGA_BYTE,
+    };
+
+    #[test]
+    fn swap_out_in() {
+        unsafe {
+            let mut allocator = emulate_test();
+            let mut mapper = PageMapper::<EmulateArch, _>::current(&mut allocator);
+            let pages =
+                PageRange::covering(VirtualAddress::new(0x1000), 4 * EmulateArch::PAGE_SIZE);
+            let flags = EmulateArch::ENTRY_FLAG_USER
+                | EmulateArch::ENTRY_FLAG_WRITABLE
+                | EmulateArch::ENTRY_FLAG_NO_EXEC;
+            mapper.map_range(pages, flags).unwrap().flush();
+            for (i, page) in pages.enumerate() {
+                EmulateArch::write::<usize>(page.start().add(8), i + 1);
+            }
+            let used = mapper.allocator().usage().used().data();
+
+            let device = MemorySwapDevice::new(8, EmulateArch::PAGE_SIZE);
+            let mut swap = Swap::new(device, pages);
+            // 第一圈清除访问位，第二圈换出
+            let (count, flush) = swap.swap_out(&mut mapper, 2);
+            flush.flush();
+            assert_eq!((count, swap.used()), (2, 2));
+            assert_eq!(mapper.allocator().usage().used().data(), used - 2);
+            let first = pages.start().start();
+            assert!(mapper.translate(first).is_none());
+            assert!(EmulateArch::try_read::<usize>(first).is_err());
+
+            // 被访问过的页不会被换出
+            let third = first.add(2 * EmulateArch::PAGE_SIZE);
+            EmulateArch::read::<usize>(third);
+            let (count, flush) = swap.swap_out(&mut mapper, 1);
+            flush.flush();
+            assert_eq!(count, 1);
+            assert!(mapper.translate(third).is_some());
+            assert!(mapper
+                .translate(third.add(EmulateArch::PAGE_SIZE))
+                .is_none());
+
+            swap.swap_in(&mut mapper, first).unwrap().flush();
+            assert_eq!(EmulateArch::read::<usize>(first.add(8)), 1);
+            let (_, entry_flags) = mapper.translate(first).unwrap();
+            assert_eq!(entry_flags & flags, flags);
+            assert!(swap.swap_in(&mut mapper, first).is_none());
+
+            // 缺页处理中换入
+            let fourth = third.add(EmulateArch::PAGE_SIZE);
+            let fault = EmulateArch::try_read::<usize>(fourth).unwrap_err().info();
+            assert_eq!(
+                swap.handle_fault(&mut mapper, &FaultRegions::default(), fault),
+                FaultAction::SwapIn
+            );
+            assert_eq!(EmulateArch::read::<usize>(fourth.add(8)), 4);
+
+            let second = first.add(EmulateArch::PAGE_SIZE);
+            swap.discard(&mut mapper, second).unwrap();
+            assert_eq!(swap.used(), 0);
+            assert_eq!(swap.device().used(), 0);
+        }
+    }
+
+    #[test]
+    fn skip_unswappable() {
+        unsafe {
+            let mut allocator = emulate_test();
+            let mut mapper = PageMapper::<EmulateArch, _>::current(&mut allocator);
+            let page = EmulateArch::PAGE_SIZE;
+            let flags = EmulateArch::ENTRY_FLAG_WRITABLE | EmulateArch::ENTRY_FLAG_NO_EXEC;
+            let small = VirtualAddress::new(0x20_0000 - 3 * page);
+            let device = small.add(page);
+            let shared = small.add(2 * page);
+            let huge = VirtualAddress::new(0x20_0000);
+
+            mapper.map(small, flags).unwrap().flush();
+            let device_phys = PhysicalAddress::new(0xFEE0_0000);
+            mapper.map_phys(device, device_phys, flags).unwrap().flush();
+            let shared_phys = mapper.allocator_mut().allocate_one().unwrap();
+            mapper
+                .allocator_mut()
+                .share(shared_phys, FrameCount::new(1));
+            mapper.map_phys(shared, shared_phys, flags).unwrap().flush();
+            let pages = EmulateArch::PAGE_ENTRIES;
+            let block = mapper
+                .allocator_mut()
+                .allocate(FrameCount::new(2 * pages))
+                .unwrap();
+            let huge_phys = block.align_up(2 * MEGA_BYTE);
+            let mut pd = mapper.table().next(0).unwrap().next(0).unwrap();
+            pd.set_entry(
+                1,
+                PageEntry::new(
+                    huge_phys.data()
+                        | flags
+                        | EmulateArch::ENTRY_FLAG_PRESENT
+                        | EmulateArch::ENTRY_FLAG_HUGE,
+                ),
+            );
+            EmulateArch::invalid_data_all();
+
+            let range = PageRange::covering(small, 3 * page + 2 * MEGA_BYTE);
+            let mut swap = Swap::new(MemorySwapDevice::new(8, page), range);
+            let (count, flush) = swap.swap_out(&mut mapper, 4);
+            flush.flush();
+            assert_eq!((count, swap.used()), (1, 1));
+            assert!(mapper.translate(small).is_none());
+            assert_eq!(mapper.translate(device).unwrap().0, device_phys);
+            assert_eq!(mapper.translate(shared).unwrap().0, shared_phys);
+            assert_eq!(mapper.allocator().ref_count(shared_phys), Some(2));
+            assert!(mapper.leaf_entry(huge).unwrap().present());
+            assert_eq!(
+                mapper.translate(huge.add(page)).unwrap().0,
+                huge_phys.add(page)
+            );
+        }
+    }
+}
@@ -14,7 +14,7 @@ use core::ops::Deref;
 
 use alloc::alloc::{Alloc, AllocErr, Layout};
 use core::alloc::GlobalAlloc;
-use core::ptr::NonNull;
+use core::ptr::{self, NonNull};
 use slab::Slab;
 
 use spin::Mutex;
@@ -38,6 +38,18 @@ pub enum HeapAllocator {
     LinkedListAllocator,
 }
 
+/// Supplies fresh, mapped memory to a heap that has run out of space.
+/// Implementations must not allocate from the heap they are growing.
+pub trait PageProvider {
+    /// Maps `size` bytes of new memory anywhere and returns its page aligned start address,
+    /// or `None` if memory is exhausted. `size` is a multiple of `MIN_SLAB_SIZE`.
+    fn allocate(&mut self, size: usize) -> Option<usize>;
+
+    /// Maps `size` bytes of new memory starting exactly at `addr`, which is used to extend
+    /// the linked list allocator in place. Returns `false` if that range can't be mapped.
+    fn allocate_at(&mut self, addr: usize, size: usize) -> bool;
+}
+
 /// A fixed size heap backed by multiple slabs with blocks of different sizes.
 /// Allocations over 4096 bytes are served by linked list allocator.
 pub struct Heap {
@@ -104,6 +116,32 @@ impl Heap {
         }
     }
 
+    /// Asks `provider` for enough memory to satisfy `layout` and adds it to the size class that
+    /// serves `layout`. Slabs grow by `MIN_SLAB_SIZE`, the linked list allocator is extended
+    /// in place. Returns `false` if the provider is out of memory.
+    pub unsafe fn grow_from(&mut self, layout: &Layout, provider: &mut dyn PageProvider) -> bool {
+        match Heap::layout_to_allocator(layout) {
+            HeapAllocator::LinkedListAllocator => {
+                let size =
+                    linked_list_allocator::align_up(layout.size() + layout.align(), MIN_SLAB_SIZE);
+                let top = self.linked_list_allocator.top();
+                if provider.allocate_at(top, size) {
+                    self.linked_list_allocator.extend(size);
+                    true
+                } else {
+                    false
+                }
+            }
+            slab => match provider.allocate(MIN_SLAB_SIZE) {
+                Some(addr) => {
+                    self.grow(addr, MIN_SLAB_SIZE, slab);
+                    true
+                }
+                None => false,
+            },
+        }
+    }
+
     /// Allocates a chunk of the given size with the given alignment. Returns a pointer to the
     /// beginning of that chunk if it was successful. Else it returns `Err`.
     /// This function finds the slab of lowest size which can still accomodate the given chunk.
@@ -196,23 +234,57 @@ unsafe impl Alloc for Heap {
     }
 }
 
-pub struct LockedHeap(Mutex<Option<Heap>>);
+/// A heap behind a lock. When a size class runs out, the heap grows itself from the
+/// `PageProvider` set by `set_provider`, so allocations only fail once the provider does.
+pub struct LockedHeap(
+    Mutex<Option<Heap>>,
+    Mutex<Option<&'static mut (dyn PageProvider + Send)>>,
+);
 
 impl LockedHeap {
     pub const fn empty() -> LockedHeap {
-        LockedHeap(Mutex::new(None))
+        LockedHeap(Mutex::new(None), Mutex::new(None))
     }
 
     pub unsafe fn init(&self, heap_start_addr: usize, size: usize) {
         *self.0.lock() = Some(Heap::new(heap_start_addr, size));
     }
 
+    /// Sets the provider used to grow the heap when a size class runs out.
+    pub fn set_provider(&self, provider: &'static mut (dyn PageProvider + Send)) {
+        *self.1.lock() = Some(provider);
+    }
+
+    /// Allocates from `heap`, growing it from the provider until the allocation succeeds
+    /// or the provider runs out of memory.
+    unsafe fn allocate_or_grow(
+        &self,
+        heap: &mut Heap,
+        layout: Layout,
+    ) -> Result<NonNull<u8>, AllocErr> {
+        loop {
+            if let Ok(ptr) = heap.allocate(layout.clone()) {
+                return Ok(ptr);
+            }
+            let grown = match *self.1.lock() {
+                Some(ref mut provider) => heap.grow_from(&layout, &mut **provider),
+                None => false,
+            };
+            if !grown {
+                return Err(AllocErr);
+            }
+        }
+    }
+
     /// Creates a new heap with the given `heap_start_addr` and `heap_size`. The start address must be valid
     /// and the memory in the `[heap_start_addr, heap_bottom + heap_size)` range must not be used for
     /// anything else. This function is unsafe because it can cause undefined behavior if the
     /// given address is invalid.
     pub unsafe fn new(heap_start_addr: usize, heap_size: usize) -> LockedHeap {
-        LockedHeap(Mutex::new(Some(Heap::new(heap_start_addr, heap_size))))
+        LockedHeap(
+            Mutex::new(Some(Heap::new(heap_start_addr, heap_size))),
+            Mutex::new(None),
+        )
     }
 }
 
@@ -227,7 +299,7 @@ impl Deref for LockedHeap {
 unsafe impl<'a> Alloc for &'a LockedHeap {
     unsafe fn alloc(&mut self, layout: Layout) -> Result<NonNull<u8>, AllocErr> {
         if let Some(ref mut heap) = *self.0.lock() {
-            heap.allocate(layout)
+            self.allocate_or_grow(heap, layout)
         } else {
             panic!("allocate: heap not initialized");
         }
@@ -253,10 +325,9 @@ unsafe impl<'a> Alloc for &'a LockedHeap {
 unsafe impl GlobalAlloc for LockedHeap {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         if let Some(ref mut heap) = *self.0.lock() {
-            if let Ok(ref mut nnptr) = heap.allocate(layout) {
-                return nnptr.as_ptr();
-            } else {
-                panic!("allocate: failed");
+            match self.allocate_or_grow(heap, layout) {
+                Ok(nnptr) => nnptr.as_ptr(),
+                Err(AllocErr) => ptr::null_mut(),
             }
         } else {
             panic!("allocate: heap not initialzied");
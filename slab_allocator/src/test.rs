@@ -227,4 +227,54 @@ fn allocate_one_8192_block() {
     unsafe {
         heap.deallocate(x, layout.clone());
     }
-}
\ No newline at end of file
+}
+
+const PROVIDER_SIZE: usize = 4 * 4096;
+
+#[repr(align(4096))]
+struct TestProvider {
+    space: [u8; PROVIDER_SIZE],
+    used: usize,
+}
+
+impl PageProvider for TestProvider {
+    fn allocate(&mut self, size: usize) -> Option<usize> {
+        if self.used + size > PROVIDER_SIZE {
+            return None;
+        }
+        let addr = &self.space[self.used] as *const u8 as usize;
+        self.used += size;
+        Some(addr)
+    }
+
+    fn allocate_at(&mut self, _addr: usize, _size: usize) -> bool {
+        false
+    }
+}
+
+#[test]
+fn locked_heap_grows_from_provider() {
+    let heap = new_locked_heap();
+    let provider = alloc::boxed::Box::new(TestProvider {
+        space: [0u8; PROVIDER_SIZE],
+        used: 0,
+    });
+    let provider: &'static mut TestProvider = alloc::boxed::Box::leak(provider);
+    let provider_start = &provider.space[0] as *const u8 as usize;
+    let layout = Layout::from_size_align(4096, 4096).unwrap();
+
+    // The 4096 byte slab of the initial heap holds exactly one block
+    let first = unsafe { heap.alloc(layout.clone()) };
+    assert!(!first.is_null());
+    assert!(unsafe { heap.alloc(layout.clone()) }.is_null());
+
+    heap.set_provider(provider);
+    for i in 0..4 {
+        let ptr = unsafe { heap.alloc(layout.clone()) };
+        assert_eq!(ptr as usize, provider_start + i * 4096);
+    }
+    // The provider is exhausted and the linked list allocator can't be extended
+    assert!(unsafe { heap.alloc(layout.clone()) }.is_null());
+    let big = Layout::from_size_align(8192, 8).unwrap();
+    assert!(unsafe { heap.alloc(big) }.is_null());
+}
@@ -0,0 +1,181 @@
+use core::marker::PhantomData;
+
+use mm::{Arch, FrameAllocator, PageMapper, PageRange, VirtualAddress};
+use slab_allocator::PageProvider;
+
+/// 内核堆的页提供者，按需分配页框并映射到堆的虚拟地址窗口中。
+/// 链表分配器从初始堆的末尾向上扩展，slab 从窗口顶部向下取页，两者相遇或者页框耗尽时失败
+pub struct HeapPageProvider<A, F> {
+    allocator: F,
+    // 链表分配器已经扩展到的位置
+    top: VirtualAddress,
+    // slab 已经使用到的位置
+    bottom: VirtualAddress,
+    phantom: PhantomData<A>,
+}
+
+impl<A: Arch, F: FrameAllocator> HeapPageProvider<A, F> {
+    /// `heap_end`是传给`LockedHeap::init`的内存的末尾，`end`是堆窗口的末尾，都需要按页对齐
+    pub fn new(allocator: F, heap_end: VirtualAddress, end: VirtualAddress) -> Self {
+        assert!(
+            heap_end <= end,
+            "heap end is above the end of the heap window"
+        );
+        Self {
+            allocator,
+            top: heap_end,
+            bottom: end,
+            phantom: PhantomData,
+        }
+    }
+
+    pub fn allocator(&self) -> &F {
+        &self.allocator
+    }
+
+    unsafe fn map(&mut self, start: VirtualAddress, size: usize) -> bool {
+        let mut mapper = PageMapper::<A, F>::current(&mut self.allocator);
+        let flags = A::ENTRY_FLAG_WRITABLE | A::ENTRY_FLAG_NO_EXEC;
+        match mapper.map_range(PageRange::covering(start, size), flags) {
+            Some(flush) => {
+                flush.flush();
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn remaining(&self) -> usize {
+        self.bottom.data().saturating_sub(self.top.data())
+    }
+}
+
+impl<A: Arch, F: FrameAllocator> PageProvider for HeapPageProvider<A, F> {
+    fn allocate(&mut self, size: usize) -> Option<usize> {
+        if size > self.remaining() {
+            return None;
+        }
+        let start = VirtualAddress::new(self.bottom.data() - size);
+        if unsafe { self.map(start, size) } {
+            self.bottom = start;
+            Some(start.data())
+        } else {
+            None
+        }
+    }
+
+    fn allocate_at(&mut self, addr: usize, size: usize) -> bool {
+        if addr != self.top.data() || size > self.remaining() {
+            return false;
+        }
+        if unsafe { self.map(self.top, size) } {
+            self.top = self.top.add(size);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::alloc::{GlobalAlloc, Layout};
+    use std::collections::BTreeSet;
+
+    use mm::{
+        Arch, BuddyAllocator, BumpAllocator, EmulateArch, EmulateConfig, FrameAllocator,
+        PageMapper, VirtualAddress, MEGA_BYTE,
+    };
+    use slab_allocator::{LockedHeap, MIN_HEAP_SIZE, MIN_SLAB_SIZE};
+
+    use super::HeapPageProvider;
+
+    /// 初始化模拟机器，建立直接映射，返回伙伴分配器
+    unsafe fn emulate(memory_size: usize) -> BuddyAllocator<EmulateArch> {
+        let areas = EmulateArch::init_with(&EmulateConfig::new(memory_size));
+        let mut bump_allocator = BumpAllocator::<EmulateArch>::new(areas, 0);
+        {
+            let mut mapper = PageMapper::<EmulateArch, _>::create(&mut bump_allocator).unwrap();
+            for area in areas.iter() {
+                for i in 0..area.size / EmulateArch::PAGE_SIZE {
+                    let phys = area.base.add(i * EmulateArch::PAGE_SIZE);
+                    let virt = EmulateArch::phys_to_virt(phys);
+                    let flags = EmulateArch::ENTRY_FLAG_WRITABLE | EmulateArch::ENTRY_FLAG_NO_EXEC;
+                    mapper.map_phys(virt, phys, flags).unwrap().ignore();
+                }
+            }
+            mapper.make_current();
+        }
+        BuddyAllocator::new(bump_allocator).unwrap()
+    }
+
+    #[test]
+    fn grow_until_frames_exhausted() {
+        unsafe {
+            let allocator = emulate(4 * MEGA_BYTE);
+            // 堆直接使用宿主的内存，模拟机器中映射同样的地址。
+            // 窗口比模拟的物理内存大，先耗尽的是页框
+            let window = 16 * MEGA_BYTE;
+            let start =
+                std::alloc::alloc_zeroed(Layout::from_size_align(window, MIN_SLAB_SIZE).unwrap())
+                    as usize;
+            let heap_end = start + MIN_HEAP_SIZE;
+            let end = start + window;
+            let heap: &'static mut LockedHeap = Box::leak(Box::new(LockedHeap::empty()));
+            heap.init(start, MIN_HEAP_SIZE);
+            let provider = Box::leak(Box::new(HeapPageProvider::<EmulateArch, _>::new(
+                allocator,
+                VirtualAddress::new(heap_end),
+                VirtualAddress::new(end),
+            )));
+            let provider_ptr: *mut HeapPageProvider<EmulateArch, BuddyAllocator<EmulateArch>> =
+                provider;
+            heap.set_provider(&mut *provider_ptr);
+
+            // 小块从slab分配，大块从链表分配器分配，交替增长直到都失败
+            let small = Layout::from_size_align(64, 8).unwrap();
+            let large = Layout::from_size_align(2 * MIN_SLAB_SIZE, 8).unwrap();
+            let mut smalls = Vec::new();
+            let mut larges = Vec::new();
+            loop {
+                let small_ptr = heap.alloc(small);
+                let large_ptr = heap.alloc(large);
+                if small_ptr.is_null() && large_ptr.is_null() {
+                    break;
+                }
+                if !small_ptr.is_null() {
+                    smalls.push(small_ptr as usize);
+                }
+                if !large_ptr.is_null() {
+                    larges.push(large_ptr as usize);
+                }
+            }
+
+            let provider = &mut *provider_ptr;
+            let (top, bottom) = (provider.top.data(), provider.bottom.data());
+            assert!(heap_end < top && top <= bottom && bottom < end);
+            assert!(provider.allocator().usage().free().data() <= 1);
+            // 链表分配器只用初始堆和窗口底部，slab只用初始堆和窗口顶部
+            assert!(larges
+                .iter()
+                .all(|&ptr| ptr >= start && ptr + large.size() <= top));
+            assert!(larges.iter().any(|&ptr| ptr >= heap_end));
+            assert!(smalls
+                .iter()
+                .all(|&ptr| (ptr >= start && ptr < heap_end) || (ptr >= bottom && ptr < end)));
+            assert!(smalls.iter().any(|&ptr| ptr >= bottom));
+
+            // 两边映射的页框互不重叠，中间没有映射
+            let mapper = PageMapper::<EmulateArch, _>::current(&mut provider.allocator);
+            let mut frames = BTreeSet::new();
+            for page in (heap_end..end).step_by(EmulateArch::PAGE_SIZE) {
+                let phys = mapper.translate(VirtualAddress::new(page));
+                if page < top || page >= bottom {
+                    assert!(frames.insert(phys.unwrap().0));
+                } else {
+                    assert!(phys.is_none());
+                }
+            }
+        }
+    }
+}
@@ -1,5 +1,7 @@
 use log::info;
 
+#[cfg(feature = "slab_allocator")]
+pub mod heap;
 pub mod syscall;
 pub mod time;
 static mut INIT_ENV: &[u8] = &[];